@@ -1,8 +1,9 @@
 extern crate walkdir;
+extern crate miniz_oxide;
 
 use std::env;
-use std::io::Write;
-use std::fs::File;
+use std::io::{Read, Write};
+use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
@@ -59,7 +60,109 @@ fn linker_script() {
     println!("cargo:rerun-if-changed=memory.x");
 }
 
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css",
+        Some("js") => "application/javascript",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("ico") => "image/x-icon",
+        Some("woff2") => "font/woff2",
+        _ => "application/octet-stream"
+    }
+}
+
+// FNV-1a, which is plenty to tell two versions of an asset apart.
+fn etag(data: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in data {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("\"{:016x}\"", hash)
+}
+
+// CRC-32 as used by gzip (IEEE 802.3, reflected).
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// Compresses into the gzip format. The header leaves out the name and
+// timestamp, so that the output is reproducible.
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut gzip = vec![0x1f, 0x8b, /*deflate*/8, /*flags*/0, /*mtime*/0, 0, 0, 0,
+                        /*best compression*/2, /*unix*/3];
+    gzip.extend_from_slice(&miniz_oxide::deflate::compress_to_vec(data, 9));
+    for &value in [crc32(data), data.len() as u32].iter() {
+        gzip.extend_from_slice(&[value as u8, (value >> 8) as u8,
+                                 (value >> 16) as u8, (value >> 24) as u8]);
+    }
+    gzip
+}
+
+fn assets() {
+    // Every file in the assets directory is served, gzip-compressed, at its path
+    // relative to that directory.
+    let assets_dir = Path::new("assets");
+    println!("cargo:rerun-if-changed={}", assets_dir.display());
+
+    let mut paths = Vec::new();
+    for entry in WalkDir::new(assets_dir) {
+        let entry = entry.unwrap();
+        println!("cargo:rerun-if-changed={}", entry.path().display());
+        if entry.file_type().is_file() {
+            paths.push(entry.path().to_owned());
+        }
+    }
+    paths.sort();
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let mut table = String::from("&[\n");
+    for path in paths {
+        let relative = path.strip_prefix(assets_dir).unwrap();
+        let url = relative.components()
+                          .map(|c| c.as_os_str().to_str().unwrap().to_owned())
+                          .collect::<Vec<_>>()
+                          .join("/");
+        assert!(url.is_ascii(), "asset path {} is not ASCII", url);
+
+        let mut data = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+
+        let gz_path = out_dir.join("assets").join(relative).with_extension(
+            match relative.extension().and_then(|ext| ext.to_str()) {
+                Some(ext) => format!("{}.gz", ext),
+                None => "gz".to_owned()
+            });
+        fs::create_dir_all(gz_path.parent().unwrap()).unwrap();
+        File::create(&gz_path).unwrap().write_all(&gzip(&data)).unwrap();
+
+        table.push_str(&format!(
+            "    Asset {{ path: b{:?}, content_type: {:?}, etag: {:?}, \
+                         data: include_bytes!({:?}) }},\n",
+            format!("/{}", url), content_type(&path), etag(&data),
+            gz_path.to_str().unwrap()));
+    }
+    table.push_str("]\n");
+
+    let mut f = File::create(out_dir.join("assets.rs")).unwrap();
+    write!(f, "{}", table).unwrap();
+}
+
 fn main() {
     linker_script();
     git_describe();
+    assets();
 }
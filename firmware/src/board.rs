@@ -1,4 +1,6 @@
+#[cfg(target_arch = "arm")]
 use cortex_m;
+#[cfg(target_arch = "arm")]
 use tm4c129x;
 
 
@@ -42,11 +44,17 @@ pub const IC_ADC_GAIN_MED: f32 = 13201320132.0132;
 pub const IC_ADC_GAIN_HIGH: f32 = 133320001.3332;
 pub const IC_ADC_OFFSET: f32 = 96.0;
 
+// Rate of the ADC sequencer interrupt, which delivers one sample of each input
+pub const ADC_SAMPLE_RATE: f32 = 125.0/24.0*1000.0;
+// The control loops are updated with each ADC sample.
+pub const ADC_SAMPLE_PERIOD: f32 = 1.0/ADC_SAMPLE_RATE;
+
 pub const FBI_R223: f32 = 200.0;
 pub const FBI_R224: f32 = 39.0;
 pub const FBI_R225: f32 = 22000.0;
 
 
+#[cfg(target_arch = "arm")]
 pub fn set_led(state: bool) {
     cortex_m::interrupt::free(|_cs| {
         let gpio_k = unsafe { &*tm4c129x::GPIO_PORTK::ptr() };
@@ -58,6 +66,7 @@ pub fn set_led(state: bool) {
     });
 }
 
+#[cfg(target_arch = "arm")]
 pub fn get_button() -> bool {
     let gpio_dat = cortex_m::interrupt::free(|_cs| {
         let gpio_l = unsafe { &*tm4c129x::GPIO_PORTL::ptr() };
@@ -66,6 +75,7 @@ pub fn get_button() -> bool {
     gpio_dat & BTNN == 0
 }
 
+#[cfg(target_arch = "arm")]
 pub fn set_hv_pwm(duty: u16) {
     cortex_m::interrupt::free(|_cs| {
         let pwm0 = unsafe { &*tm4c129x::PWM0::ptr() };
@@ -73,6 +83,7 @@ pub fn set_hv_pwm(duty: u16) {
     });
 }
 
+#[cfg(target_arch = "arm")]
 pub fn set_fv_pwm(duty: u16) {
     cortex_m::interrupt::free(|_cs| {
         let pwm0 = unsafe { &*tm4c129x::PWM0::ptr() };
@@ -80,6 +91,7 @@ pub fn set_fv_pwm(duty: u16) {
     });
 }
 
+#[cfg(target_arch = "arm")]
 pub fn set_fbv_pwm(duty: u16) {
     cortex_m::interrupt::free(|_cs| {
         let pwm0 = unsafe { &*tm4c129x::PWM0::ptr() };
@@ -87,13 +99,25 @@ pub fn set_fbv_pwm(duty: u16) {
     });
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+/// Emission current ranges, from the most sensitive.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum EmissionRange {
     Low,  // 22K
     Med,  // 22K//(200Ω + compensated diode)
     High  // 22K//(39Ω + uncompensated diode)
 }
 
+impl EmissionRange {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            EmissionRange::Low  => "low",
+            EmissionRange::Med  => "med",
+            EmissionRange::High => "high"
+        }
+    }
+}
+
+#[cfg(target_arch = "arm")]
 pub fn set_emission_range(range: EmissionRange) {
     cortex_m::interrupt::free(|_cs| {
         let gpio_p = unsafe { &*tm4c129x::GPIO_PORTP::ptr() };
@@ -115,6 +139,17 @@ pub enum ElectrometerRange {
     High  // 1G//100K
 }
 
+impl ElectrometerRange {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ElectrometerRange::Low  => "low",
+            ElectrometerRange::Med  => "med",
+            ElectrometerRange::High => "high"
+        }
+    }
+}
+
+#[cfg(target_arch = "arm")]
 pub fn set_electrometer_range(range: ElectrometerRange) {
     cortex_m::interrupt::free(|_cs| {
         let gpio_p = unsafe { &*tm4c129x::GPIO_PORTP::ptr() };
@@ -129,6 +164,7 @@ pub fn set_electrometer_range(range: ElectrometerRange) {
     });
 }
 
+#[cfg(target_arch = "arm")]
 pub fn reset_error() {
     cortex_m::interrupt::free(|_cs| {
         let gpio_q = unsafe { &*tm4c129x::GPIO_PORTQ::ptr() };
@@ -137,6 +173,7 @@ pub fn reset_error() {
     });
 }
 
+#[cfg(target_arch = "arm")]
 pub fn error_latched() -> bool {
     cortex_m::interrupt::free(|_cs| {
         let gpio_l = unsafe { &*tm4c129x::GPIO_PORTL::ptr() };
@@ -144,28 +181,76 @@ pub fn error_latched() -> bool {
     })
 }
 
-pub fn process_errors() {
+#[derive(Clone, Copy)]
+pub struct ErrorStatus {
+    pub fv: bool,
+    pub fbv: bool,
+    pub fbi: bool,
+    pub av: bool,
+    pub ai: bool,
+    pub latched: bool
+}
+
+#[cfg(target_arch = "arm")]
+pub fn get_error_status() -> ErrorStatus {
     let gpio_dat = cortex_m::interrupt::free(|_cs| {
         let gpio_l = unsafe { &*tm4c129x::GPIO_PORTL::ptr() };
         gpio_l.data.read().bits() as u8
     });
-    if gpio_dat & FV_ERRN == 0 {
+    ErrorStatus {
+        fv: gpio_dat & FV_ERRN == 0,
+        fbv: gpio_dat & FBV_ERRN == 0,
+        fbi: gpio_dat & FBI_ERRN == 0,
+        av: gpio_dat & AV_ERRN == 0,
+        ai: gpio_dat & AI_ERRN == 0,
+        latched: gpio_dat & ERR_LATCHN == 0
+    }
+}
+
+#[cfg(target_arch = "arm")]
+pub fn process_errors() {
+    let errors = get_error_status();
+    if errors.fv {
         println!("Filament overvolt");
     }
-    if gpio_dat & FBV_ERRN == 0 {
+    if errors.fbv {
         println!("Filament bias overvolt");
     }
-    if gpio_dat & FBI_ERRN == 0 {
+    if errors.fbi {
         println!("Filament bias overcurrent");
     }
-    if gpio_dat & AV_ERRN == 0 {
+    if errors.av {
         println!("Anode overvolt");
     }
-    if gpio_dat & AI_ERRN == 0 {
+    if errors.ai {
         println!("Anode overcurrent");
     }
 }
 
+#[cfg(target_arch = "arm")]
+pub fn get_uart_char() -> Option<u8> {
+    cortex_m::interrupt::free(|_cs| {
+        let uart_0 = unsafe { &*tm4c129x::UART0::ptr() };
+        if uart_0.fr.read().rxfe().bit() {
+            None
+        } else {
+            Some(uart_0.dr.read().data().bits())
+        }
+    })
+}
+
+#[cfg(target_arch = "arm")]
+pub fn reset() -> ! {
+    cortex_m::interrupt::disable();
+    let cp = unsafe { tm4c129x::CorePeripherals::steal() };
+    cortex_m::asm::dsb();
+    // VECTKEY | SYSRESETREQ
+    unsafe { cp.SCB.aircr.write(0x05fa_0004) };
+    cortex_m::asm::dsb();
+    loop {}
+}
+
+#[cfg(target_arch = "arm")]
 pub fn init() {
     cortex_m::interrupt::free(|_cs| {
         let sysctl = unsafe { &*tm4c129x::SYSCTL::ptr() };
@@ -291,6 +376,7 @@ pub fn init() {
     });
 }
 
+#[cfg(target_arch = "arm")]
 pub fn start_adc() {
     cortex_m::interrupt::free(|_cs| {
         let sysctl = unsafe { &*tm4c129x::SYSCTL::ptr() };
@@ -336,6 +422,7 @@ pub fn start_adc() {
     });
 }
 
+#[cfg(target_arch = "arm")]
 pub fn get_mac_address() -> [u8; 6] {
     let (userreg0, userreg1) = cortex_m::interrupt::free(|_cs| {
         let flashctl = unsafe { &*tm4c129x::FLASH_CTRL::ptr() };
@@ -345,3 +432,14 @@ pub fn get_mac_address() -> [u8; 6] {
     [userreg0 as u8, (userreg0 >> 8) as u8, (userreg0 >> 16) as u8,
      userreg1 as u8, (userreg1 >> 8) as u8, (userreg1 >> 16) as u8]
 }
+
+// Host builds only run the unit tests, which drive the control loops without
+// the hardware.
+#[cfg(not(target_arch = "arm"))]
+pub fn set_hv_pwm(_duty: u16) {}
+#[cfg(not(target_arch = "arm"))]
+pub fn set_fv_pwm(_duty: u16) {}
+#[cfg(not(target_arch = "arm"))]
+pub fn set_fbv_pwm(_duty: u16) {}
+#[cfg(not(target_arch = "arm"))]
+pub fn set_emission_range(_range: EmissionRange) {}
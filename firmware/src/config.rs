@@ -1,9 +1,40 @@
 use eeprom;
+use board;
+use gauge;
+use loop_anode;
+use loop_cathode;
+use filter;
+use electrometer;
+use auth;
+use leak_test;
+use calibration;
+use self_test;
+use degas;
+use pid;
 use crc::crc32;
-use smoltcp::wire::{IpCidr, IpAddress};
+use smoltcp::wire::{IpCidr, IpAddress, Ipv4Address, Ipv4Cidr, EthernetAddress};
 
 const MAGIC: u8 = 0x54;
 
+const PAYLOAD_LEN: usize = eeprom::BLOCK_LEN - 5;
+
+// Each record is stored twice, in two different blocks.
+const NETWORK_BLOCKS: (u16, u16) = (0, 1);
+const GAUGE_BLOCKS: (u16, u16) = (2, 3);
+const AUTH_BLOCKS: (u16, u16) = (4, 5);
+const ALLOWLIST_BLOCKS: (u16, u16) = (6, 7);
+const CALIBRATION_BLOCKS: (u16, u16) = (8, 9);
+const SELF_TEST_BLOCKS: (u16, u16) = (10, 11);
+// Indexed like `Config::pid`.
+const PID_BLOCKS: [(u16, u16); 3] = [(12, 13), (14, 15), (16, 17)];
+// Version of the PID parameter records, whose gains were per sample before
+// version 1.
+const PID_VERSION: u8 = 1;
+const EMISSION_BLOCKS: (u16, u16) = (18, 19);
+const DEGAS_BLOCKS: (u16, u16) = (20, 21);
+// The calibration record has no room left for it.
+const GAS_BLOCKS: (u16, u16) = (22, 23);
+
 struct EepromReader {
     buffer: [u8; eeprom::BLOCK_LEN]
 }
@@ -30,10 +61,10 @@ impl EepromReader {
         true
     }
 
-    fn read_payload<'a>(&'a mut self) -> Result<&'a [u8], ()> {
-        let mut ok = self.read_payload_block(0);
+    fn read_payload<'a>(&'a mut self, blocks: (u16, u16)) -> Result<&'a [u8], ()> {
+        let mut ok = self.read_payload_block(blocks.0);
         if !ok {
-            ok = self.read_payload_block(1);
+            ok = self.read_payload_block(blocks.1);
         }
         if ok {
             Ok(&self.buffer[1..self.buffer.len()-4])
@@ -43,7 +74,9 @@ impl EepromReader {
     }
 }
 
-fn write_eeprom_payload(payload: &[u8]) {
+/// Writes both copies of a record, skipping those that already hold it, since
+/// each write wears the EEPROM.
+fn write_eeprom_payload(payload: &[u8], blocks: (u16, u16)) {
     let mut buffer: [u8; eeprom::BLOCK_LEN] = [0; eeprom::BLOCK_LEN];
     buffer[0] = MAGIC;
     buffer[1..payload.len()+1].copy_from_slice(payload);
@@ -53,41 +86,551 @@ fn write_eeprom_payload(payload: &[u8]) {
     buffer[len-3] = (cksum >> 8) as u8;
     buffer[len-2] = (cksum >> 16) as u8;
     buffer[len-1] = (cksum >> 24) as u8;
-    eeprom::write_block(&buffer, 0);
-    eeprom::write_block(&buffer, 1);
+    let mut stored: [u8; eeprom::BLOCK_LEN] = [0; eeprom::BLOCK_LEN];
+    for &block in [blocks.0, blocks.1].iter() {
+        eeprom::read_block(&mut stored, block);
+        if stored[..] != buffer[..] {
+            eeprom::write_block(&buffer, block);
+        }
+    }
+}
+
+fn get_f32(data: &[u8]) -> f32 {
+    f32::from_bits(data[0] as u32 | (data[1] as u32) << 8 |
+                   (data[2] as u32) << 16 | (data[3] as u32) << 24)
+}
+
+fn get_u16(data: &[u8]) -> u16 {
+    data[0] as u16 | (data[1] as u16) << 8
+}
+
+fn put_u16(data: &mut [u8], value: u16) {
+    data[0] = value as u8;
+    data[1] = (value >> 8) as u8;
+}
+
+fn put_f32(data: &mut [u8], value: f32) {
+    let bits = value.to_bits();
+    data[0] = bits as u8;
+    data[1] = (bits >> 8) as u8;
+    data[2] = (bits >> 16) as u8;
+    data[3] = (bits >> 24) as u8;
 }
 
 pub struct Config {
     pub ip: IpCidr,
+    pub gateway: Option<Ipv4Address>,
+    pub mac: Option<EthernetAddress>,
+    pub tpg_enabled: bool,
+    pub pressure_unit: gauge::PressureUnit,
+
+    pub anode: f32,
+    pub cathode_bias: f32,
+    pub emission: f32,
+    /// Rate of the filament voltage ramp at switch-on, in volts per second.
+    pub filament_ramp: f32,
+    /// Slew rates of the anode voltage and cathode bias, in volts per second.
+    pub anode_slew_rate: f32,
+    pub bias_slew_rate: f32,
+    /// Diode drop assumed in the high emission range, calibrated per unit.
+    pub diode_drop: f32,
+    pub degas_emission: f32,
+    pub degas_anode: f32,
+    pub degas_duration: f32,
+    pub degas_ramp: f32,
+    /// Pressure above which degassing is not allowed, in mbar.
+    pub degas_pressure: f32,
+    pub output_enabled: bool,
+    pub filter: filter::Kind,
+    pub averaging_time: f32,
+    pub range_mode: electrometer::RangeMode,
+    pub autorange: electrometer::Autorange,
+    pub noise_threshold: f32,
+    pub leak_test_fit: leak_test::Fit,
+    pub leak_test_duration: f32,
+    pub leak_test_volume: f32,
+    pub calibration: calibration::Calibration,
+    /// Sensitivity of the gauge to the measured gas, relative to nitrogen.
+    pub gas_sensitivity: f32,
+    pub leakage_threshold: f32,
+    pub self_test: Option<self_test::SelfTestResult>,
+    /// PID parameters of each `gauge::PidLoop`, by index.
+    pub pid: [pid::Parameters; 3],
+
+    pub password: Option<auth::PasswordHash>,
+    pub allowlist: [Option<Ipv4Cidr>; auth::MAX_ALLOWLIST]
 }
 
 impl Config {
     pub fn new() -> Config {
+        // ZJ-10 settings. Others:
+        //   ZJ-27: anode 225V, bias 25V, emission 1mA
+        //   ZJ-12: anode 200V, bias 50V, emission 4mA
+        //   G8130: anode 180V, bias 30V, emission 4mA
         Config {
-            ip: IpCidr::new(IpAddress::v4(192, 168, 69, 1), 24)
+            ip: IpCidr::new(IpAddress::v4(192, 168, 69, 1), 24),
+            gateway: None,
+            mac: None,
+            tpg_enabled: false,
+            pressure_unit: gauge::PressureUnit::Mbar,
+
+            anode: 165.0,
+            cathode_bias: 50.0,
+            emission: 0.5e-3,
+            filament_ramp: loop_cathode::FV_RAMP_DEFAULT,
+            anode_slew_rate: loop_anode::SLEW_RATE_DEFAULT,
+            bias_slew_rate: loop_cathode::BIAS_SLEW_RATE_DEFAULT,
+            diode_drop: loop_cathode::FD_HIGH_DEFAULT,
+            degas_emission: 5.0e-3,
+            degas_anode: 225.0,
+            degas_duration: 180.0,
+            degas_ramp: 30.0,
+            degas_pressure: 1.0e-5,
+            output_enabled: true,
+            filter: filter::Kind::Block,
+            averaging_time: 512.0/board::ADC_SAMPLE_RATE,
+            range_mode: electrometer::RangeMode::Auto,
+            autorange: electrometer::AUTORANGE_DEFAULT,
+            noise_threshold: 0.1,
+            leak_test_fit: leak_test::Fit::Linear,
+            leak_test_duration: 60.0,
+            leak_test_volume: 1.0,
+            calibration: calibration::Calibration::Linear,
+            gas_sensitivity: 1.0,
+            leakage_threshold: 10.0e-12,
+            self_test: None,
+            pid: [gauge::PidLoop::Anode.default_parameters(),
+                  gauge::PidLoop::Emission.default_parameters(),
+                  gauge::PidLoop::FilamentVoltage.default_parameters()],
+
+            password: None,
+            allowlist: [None; auth::MAX_ALLOWLIST]
         }
     }
 
     pub fn load(&mut self) {
         let mut reader = EepromReader::new();
-        let payload = reader.read_payload();
-        if payload.is_ok() {
-            let payload = payload.unwrap();
+        if let Ok(payload) = reader.read_payload(NETWORK_BLOCKS) {
             self.ip = IpCidr::new(
                 IpAddress::v4(payload[0], payload[1], payload[2], payload[3]),
-                payload[4])
+                payload[4]);
+            // Older firmware leaves the rest of the payload zeroed, which
+            // stands for "no gateway" and "use the factory MAC address".
+            let gateway = Ipv4Address::from_bytes(&payload[5..9]);
+            self.gateway = if gateway.is_unspecified() { None } else { Some(gateway) };
+            self.mac = if payload[9] != 0 {
+                Some(EthernetAddress::from_bytes(&payload[10..16]))
+            } else {
+                None
+            };
+            self.tpg_enabled = payload[16] & 1 != 0;
+            self.pressure_unit = match payload[17] {
+                1 => gauge::PressureUnit::Torr,
+                2 => gauge::PressureUnit::Pa,
+                _ => gauge::PressureUnit::Mbar
+            };
+        }
+
+        let mut reader = EepromReader::new();
+        if let Ok(payload) = reader.read_payload(GAUGE_BLOCKS) {
+            self.output_enabled = payload[0] & 1 != 0;
+            // A corrupt block must not drive the outputs out of range; such
+            // setpoints keep their defaults.
+            let anode = get_f32(&payload[1..5]);
+            if gauge::check_anode(anode).is_ok() {
+                self.anode = anode;
+            }
+            let cathode_bias = get_f32(&payload[5..9]);
+            if gauge::check_cathode_bias(cathode_bias).is_ok() {
+                self.cathode_bias = cathode_bias;
+            }
+            let emission = get_f32(&payload[9..13]);
+            if gauge::check_emission(emission).is_ok() {
+                self.emission = emission;
+            }
+            self.filter = match payload[13] {
+                1 => filter::Kind::Moving,
+                2 => filter::Kind::Exponential,
+                _ => filter::Kind::Block
+            };
+            // Older firmware leaves the averaging time zeroed.
+            let averaging_time = get_f32(&payload[14..18]);
+            if filter::check_averaging_time(averaging_time).is_ok() {
+                self.averaging_time = averaging_time;
+            }
+            self.range_mode = match payload[18] {
+                1 => electrometer::RangeMode::Hold(board::ElectrometerRange::Low),
+                2 => electrometer::RangeMode::Hold(board::ElectrometerRange::Med),
+                3 => electrometer::RangeMode::Hold(board::ElectrometerRange::High),
+                _ => electrometer::RangeMode::Auto
+            };
+            // Likewise, zeroed autorange parameters fail the check.
+            let autorange = electrometer::Autorange {
+                up_threshold: get_u16(&payload[19..21]),
+                down_threshold: get_u16(&payload[21..23]),
+                dwell: get_u16(&payload[23..25]),
+                settle: get_u16(&payload[25..27])
+            };
+            if let Ok(autorange) = autorange.check() {
+                self.autorange = autorange;
+            }
+            let noise_threshold = get_f32(&payload[27..31]);
+            if gauge::check_noise_threshold(noise_threshold).is_ok() {
+                self.noise_threshold = noise_threshold;
+            }
+            self.leak_test_fit = match payload[31] {
+                1 => leak_test::Fit::Exponential,
+                _ => leak_test::Fit::Linear
+            };
+            let leak_test_duration = get_f32(&payload[32..36]);
+            if leak_test::check_duration(leak_test_duration).is_ok() {
+                self.leak_test_duration = leak_test_duration;
+            }
+            let leak_test_volume = get_f32(&payload[36..40]);
+            if leak_test::check_volume(leak_test_volume).is_ok() {
+                self.leak_test_volume = leak_test_volume;
+            }
+            let leakage_threshold = get_f32(&payload[40..44]);
+            if self_test::check_threshold(leakage_threshold).is_ok() {
+                self.leakage_threshold = leakage_threshold;
+            }
+            let filament_ramp = get_f32(&payload[44..48]);
+            if gauge::check_filament_ramp(filament_ramp).is_ok() {
+                self.filament_ramp = filament_ramp;
+            }
+            let anode_slew_rate = get_f32(&payload[48..52]);
+            if gauge::check_slew_rate(anode_slew_rate).is_ok() {
+                self.anode_slew_rate = anode_slew_rate;
+            }
+            let bias_slew_rate = get_f32(&payload[52..56]);
+            if gauge::check_slew_rate(bias_slew_rate).is_ok() {
+                self.bias_slew_rate = bias_slew_rate;
+            }
+        }
+
+        let mut reader = EepromReader::new();
+        if let Ok(payload) = reader.read_payload(AUTH_BLOCKS) {
+            self.password = if payload[0] & 1 != 0 {
+                let mut password = auth::PasswordHash { salt: [0; 16], hash: [0; 32] };
+                password.salt.copy_from_slice(&payload[1..17]);
+                password.hash.copy_from_slice(&payload[17..49]);
+                Some(password)
+            } else {
+                None
+            };
+        }
+
+        let mut reader = EepromReader::new();
+        if let Ok(payload) = reader.read_payload(ALLOWLIST_BLOCKS) {
+            let count = payload[0] as usize;
+            for i in 0..auth::MAX_ALLOWLIST {
+                let entry = &payload[1 + i*5..6 + i*5];
+                self.allowlist[i] = if i < count && entry[4] <= 32 {
+                    Some(Ipv4Cidr::new(Ipv4Address::from_bytes(&entry[0..4]), entry[4]))
+                } else {
+                    None
+                };
+            }
+        }
+
+        let mut reader = EepromReader::new();
+        if let Ok(payload) = reader.read_payload(CALIBRATION_BLOCKS) {
+            let calibration = match payload[0] {
+                1 => {
+                    let mut points = [calibration::Point { ratio: 0.0, pressure: 0.0 };
+                                      calibration::POINTS_MAX];
+                    for (i, point) in points.iter_mut().enumerate() {
+                        point.ratio = get_f32(&payload[2 + i*8..6 + i*8]);
+                        point.pressure = get_f32(&payload[6 + i*8..10 + i*8]);
+                    }
+                    calibration::Calibration::Table { points: points, len: payload[1] as usize }
+                }
+                2 => {
+                    let mut coefficients = [0.0; calibration::COEFFICIENTS_MAX];
+                    for (i, coefficient) in coefficients.iter_mut().enumerate() {
+                        *coefficient = get_f32(&payload[2 + i*4..6 + i*4]);
+                    }
+                    calibration::Calibration::Polynomial(coefficients)
+                }
+                _ => calibration::Calibration::Linear
+            };
+            if let Ok(calibration) = calibration.check() {
+                self.calibration = calibration;
+            }
+        }
+
+        let mut reader = EepromReader::new();
+        if let Ok(payload) = reader.read_payload(GAS_BLOCKS) {
+            let gas_sensitivity = get_f32(&payload[0..4]);
+            if calibration::check_gas_sensitivity(gas_sensitivity).is_ok() {
+                self.gas_sensitivity = gas_sensitivity;
+            }
+        }
+
+        let mut reader = EepromReader::new();
+        if let Ok(payload) = reader.read_payload(SELF_TEST_BLOCKS) {
+            self.self_test = if payload[0] & 1 != 0 {
+                Some(self_test::SelfTestResult {
+                    anode: get_f32(&payload[2..6]),
+                    current_on: get_f32(&payload[6..10]),
+                    current_off: get_f32(&payload[10..14]),
+                    leakage: get_f32(&payload[14..18]),
+                    threshold: get_f32(&payload[18..22]),
+                    over_range: payload[1] & 1 != 0
+                })
+            } else {
+                None
+            };
+        }
+
+        for ((parameters, &blocks), &pid_loop) in
+                self.pid.iter_mut().zip(PID_BLOCKS.iter()).zip(gauge::PID_LOOPS.iter()) {
+            let mut reader = EepromReader::new();
+            if let Ok(payload) = reader.read_payload(blocks) {
+                // Unset parameters are zeroed, and older records are in other
+                // units.
+                if payload[45] != PID_VERSION {
+                    continue
+                }
+                let stored = pid::Parameters {
+                    kp: get_f32(&payload[0..4]),
+                    ki: get_f32(&payload[4..8]),
+                    kd: get_f32(&payload[8..12]),
+                    output_min: get_f32(&payload[12..16]),
+                    output_max: get_f32(&payload[16..20]),
+                    integral_min: get_f32(&payload[20..24]),
+                    integral_max: get_f32(&payload[24..28]),
+                    setpoint_weight: get_f32(&payload[28..32]),
+                    derivative_filter: get_f32(&payload[32..36]),
+                    anti_windup: match payload[36] {
+                        1 => pid::AntiWindup::Conditional,
+                        2 => pid::AntiWindup::BackCalculation,
+                        _ => pid::AntiWindup::Clamp
+                    },
+                    tracking: get_f32(&payload[37..41]),
+                    rate_limit: get_f32(&payload[41..45])
+                };
+                if let Ok(stored) = gauge::check_pid(pid_loop, stored) {
+                    *parameters = stored;
+                }
+            }
+        }
+
+        let mut reader = EepromReader::new();
+        if let Ok(payload) = reader.read_payload(EMISSION_BLOCKS) {
+            // Zeroed if never calibrated.
+            let diode_drop = get_f32(&payload[0..4]);
+            if gauge::check_diode_drop(diode_drop).is_ok() {
+                self.diode_drop = diode_drop;
+            }
+        }
+
+        let mut reader = EepromReader::new();
+        if let Ok(payload) = reader.read_payload(DEGAS_BLOCKS) {
+            // Unset settings are zeroed, without the flag.
+            if payload[0] & 1 != 0 {
+                let emission = get_f32(&payload[1..5]);
+                if gauge::check_emission(emission).is_ok() {
+                    self.degas_emission = emission;
+                }
+                let anode = get_f32(&payload[5..9]);
+                if gauge::check_anode(anode).is_ok() {
+                    self.degas_anode = anode;
+                }
+                let duration = get_f32(&payload[9..13]);
+                if degas::check_duration(duration).is_ok() {
+                    self.degas_duration = duration;
+                }
+                let ramp = get_f32(&payload[13..17]);
+                if degas::check_ramp(ramp).is_ok() {
+                    self.degas_ramp = ramp;
+                }
+                let pressure = get_f32(&payload[17..21]);
+                if degas::check_pressure(pressure).is_ok() {
+                    self.degas_pressure = pressure;
+                }
+            }
         }
     }
 
+    /// Saves all the records, e.g. after a configuration reset.
     pub fn save(&self) {
+        self.save_network();
+        self.save_gauge();
+        self.save_auth();
+        self.save_allowlist();
+        self.save_calibration();
+        self.save_gas();
+        self.save_self_test();
+        for &pid_loop in gauge::PID_LOOPS.iter() {
+            self.save_pid(pid_loop);
+        }
+        self.save_emission();
+        self.save_degas();
+    }
+
+    pub fn save_network(&self) {
+        let mut payload: [u8; PAYLOAD_LEN] = [0; PAYLOAD_LEN];
         match self.ip {
             IpCidr::Ipv4(ipv4) => {
-                let mut payload: [u8; 5] = [0; 5];
                 payload[0..4].copy_from_slice(&ipv4.address().0);
                 payload[4] = ipv4.prefix_len();
-                write_eeprom_payload(&payload);
             }
             _ => panic!("unsupported network address")
         };
+        if let Some(gateway) = self.gateway {
+            payload[5..9].copy_from_slice(&gateway.0);
+        }
+        if let Some(mac) = self.mac {
+            payload[9] = 1;
+            payload[10..16].copy_from_slice(&mac.0);
+        }
+        payload[16] = self.tpg_enabled as u8;
+        payload[17] = match self.pressure_unit {
+            gauge::PressureUnit::Mbar => 0,
+            gauge::PressureUnit::Torr => 1,
+            gauge::PressureUnit::Pa => 2
+        };
+        write_eeprom_payload(&payload, NETWORK_BLOCKS);
+    }
+
+    pub fn save_gauge(&self) {
+        let mut payload: [u8; PAYLOAD_LEN] = [0; PAYLOAD_LEN];
+        payload[0] = self.output_enabled as u8;
+        put_f32(&mut payload[1..5], self.anode);
+        put_f32(&mut payload[5..9], self.cathode_bias);
+        put_f32(&mut payload[9..13], self.emission);
+        payload[13] = match self.filter {
+            filter::Kind::Block => 0,
+            filter::Kind::Moving => 1,
+            filter::Kind::Exponential => 2
+        };
+        put_f32(&mut payload[14..18], self.averaging_time);
+        payload[18] = match self.range_mode {
+            electrometer::RangeMode::Auto => 0,
+            electrometer::RangeMode::Hold(board::ElectrometerRange::Low) => 1,
+            electrometer::RangeMode::Hold(board::ElectrometerRange::Med) => 2,
+            electrometer::RangeMode::Hold(board::ElectrometerRange::High) => 3
+        };
+        put_u16(&mut payload[19..21], self.autorange.up_threshold);
+        put_u16(&mut payload[21..23], self.autorange.down_threshold);
+        put_u16(&mut payload[23..25], self.autorange.dwell);
+        put_u16(&mut payload[25..27], self.autorange.settle);
+        put_f32(&mut payload[27..31], self.noise_threshold);
+        payload[31] = match self.leak_test_fit {
+            leak_test::Fit::Linear => 0,
+            leak_test::Fit::Exponential => 1
+        };
+        put_f32(&mut payload[32..36], self.leak_test_duration);
+        put_f32(&mut payload[36..40], self.leak_test_volume);
+        put_f32(&mut payload[40..44], self.leakage_threshold);
+        put_f32(&mut payload[44..48], self.filament_ramp);
+        put_f32(&mut payload[48..52], self.anode_slew_rate);
+        put_f32(&mut payload[52..56], self.bias_slew_rate);
+        write_eeprom_payload(&payload, GAUGE_BLOCKS);
+    }
+
+    pub fn save_auth(&self) {
+        let mut payload: [u8; PAYLOAD_LEN] = [0; PAYLOAD_LEN];
+        if let Some(password) = self.password {
+            payload[0] = 1;
+            payload[1..17].copy_from_slice(&password.salt);
+            payload[17..49].copy_from_slice(&password.hash);
+        }
+        write_eeprom_payload(&payload, AUTH_BLOCKS);
+    }
+
+    pub fn save_allowlist(&self) {
+        let mut payload: [u8; PAYLOAD_LEN] = [0; PAYLOAD_LEN];
+        let mut count = 0;
+        for cidr in self.allowlist.iter().filter_map(|cidr| *cidr) {
+            payload[1 + count*5..5 + count*5].copy_from_slice(&cidr.address().0);
+            payload[5 + count*5] = cidr.prefix_len();
+            count += 1;
+        }
+        payload[0] = count as u8;
+        write_eeprom_payload(&payload, ALLOWLIST_BLOCKS);
+    }
+
+    pub fn save_calibration(&self) {
+        let mut payload: [u8; PAYLOAD_LEN] = [0; PAYLOAD_LEN];
+        match self.calibration {
+            calibration::Calibration::Linear => (),
+            calibration::Calibration::Table { ref points, len } => {
+                payload[0] = 1;
+                payload[1] = len as u8;
+                for (i, point) in points[..len].iter().enumerate() {
+                    put_f32(&mut payload[2 + i*8..6 + i*8], point.ratio);
+                    put_f32(&mut payload[6 + i*8..10 + i*8], point.pressure);
+                }
+            }
+            calibration::Calibration::Polynomial(ref coefficients) => {
+                payload[0] = 2;
+                for (i, &coefficient) in coefficients.iter().enumerate() {
+                    put_f32(&mut payload[2 + i*4..6 + i*4], coefficient);
+                }
+            }
+        }
+        write_eeprom_payload(&payload, CALIBRATION_BLOCKS);
+    }
+
+    pub fn save_gas(&self) {
+        let mut payload: [u8; PAYLOAD_LEN] = [0; PAYLOAD_LEN];
+        put_f32(&mut payload[0..4], self.gas_sensitivity);
+        write_eeprom_payload(&payload, GAS_BLOCKS);
+    }
+
+    pub fn save_self_test(&self) {
+        let mut payload: [u8; PAYLOAD_LEN] = [0; PAYLOAD_LEN];
+        if let Some(result) = self.self_test {
+            payload[0] = 1;
+            payload[1] = result.over_range as u8;
+            put_f32(&mut payload[2..6], result.anode);
+            put_f32(&mut payload[6..10], result.current_on);
+            put_f32(&mut payload[10..14], result.current_off);
+            put_f32(&mut payload[14..18], result.leakage);
+            put_f32(&mut payload[18..22], result.threshold);
+        }
+        write_eeprom_payload(&payload, SELF_TEST_BLOCKS);
+    }
+
+    pub fn save_pid(&self, pid_loop: gauge::PidLoop) {
+        let parameters = &self.pid[pid_loop.index()];
+        let mut payload: [u8; PAYLOAD_LEN] = [0; PAYLOAD_LEN];
+        put_f32(&mut payload[0..4], parameters.kp);
+        put_f32(&mut payload[4..8], parameters.ki);
+        put_f32(&mut payload[8..12], parameters.kd);
+        put_f32(&mut payload[12..16], parameters.output_min);
+        put_f32(&mut payload[16..20], parameters.output_max);
+        put_f32(&mut payload[20..24], parameters.integral_min);
+        put_f32(&mut payload[24..28], parameters.integral_max);
+        put_f32(&mut payload[28..32], parameters.setpoint_weight);
+        put_f32(&mut payload[32..36], parameters.derivative_filter);
+        payload[36] = match parameters.anti_windup {
+            pid::AntiWindup::Clamp => 0,
+            pid::AntiWindup::Conditional => 1,
+            pid::AntiWindup::BackCalculation => 2
+        };
+        put_f32(&mut payload[37..41], parameters.tracking);
+        put_f32(&mut payload[41..45], parameters.rate_limit);
+        payload[45] = PID_VERSION;
+        write_eeprom_payload(&payload, PID_BLOCKS[pid_loop.index()]);
+    }
+
+    pub fn save_emission(&self) {
+        let mut payload: [u8; PAYLOAD_LEN] = [0; PAYLOAD_LEN];
+        put_f32(&mut payload[0..4], self.diode_drop);
+        write_eeprom_payload(&payload, EMISSION_BLOCKS);
+    }
+
+    pub fn save_degas(&self) {
+        let mut payload: [u8; PAYLOAD_LEN] = [0; PAYLOAD_LEN];
+        payload[0] = 1;
+        put_f32(&mut payload[1..5], self.degas_emission);
+        put_f32(&mut payload[5..9], self.degas_anode);
+        put_f32(&mut payload[9..13], self.degas_duration);
+        put_f32(&mut payload[13..17], self.degas_ramp);
+        put_f32(&mut payload[17..21], self.degas_pressure);
+        write_eeprom_payload(&payload, DEGAS_BLOCKS);
     }
 }
@@ -0,0 +1,491 @@
+use core::str;
+use core::cell::RefCell;
+use cortex_m;
+use cortex_m::interrupt::Mutex;
+use smoltcp::wire::{IpCidr, Ipv4Address, Ipv4Cidr, EthernetAddress};
+
+use board;
+use auth;
+use config;
+use gauge;
+use event_log;
+use filter;
+use loop_anode;
+use loop_cathode;
+use electrometer;
+use calibration;
+
+const MAX_LINE: usize = 80;
+
+const HELP: &'static str = "\
+Commands:
+  status                   show measurement and regulator status
+  ip [<address>/<prefix>]  show or set the IP address
+  gateway [<address>|none] show or set the default gateway
+  mac [<address>|default]  show or set the MAC address
+  anode [<volts>]          show or set the anode voltage
+  bias [<volts>]           show or set the cathode bias voltage
+  emission [<amperes>]     show or set the emission current
+  ramp [<volts/s>]         show or set the filament voltage ramp rate at
+                           switch-on
+  slew [<anode>,<bias>]    show or set the anode voltage and cathode bias
+                           slew rates (V/s)
+  diode [<volts>]          show or set the diode drop in the high emission
+                           range
+  output [on|off]          show, enable or disable the gauge outputs
+  filter [block|moving|exponential]
+                           show or set the averaging filter
+  average [<seconds>]      show or set the averaging window or time constant
+  noise [<fraction>]       show or set the relative uncertainty above which
+                           readings are flagged as noisy
+  gas [<sensitivity>]      show or set the sensitivity to the measured gas
+                           relative to nitrogen
+  range [auto|low|med|high]
+                           show the electrometer range, autorange or hold it
+  autorange [<up>,<down>,<dwell>,<settle>]
+                           show or set the autorange thresholds (ADC counts)
+                           and sample counts
+  tpg [on|off]             show, enable or disable the TPG protocol server
+  password [<new>|none]    show status, set or clear the web admin password
+  allow [<address>/<prefix>|none]
+                           show, extend or clear the web control allowlist
+  disallow <address>/<prefix>
+                           remove an entry from the web control allowlist
+  log                      dump the event log
+  reboot                   reboot the device";
+
+pub struct Console {
+    line: [u8; MAX_LINE],
+    line_len: usize,
+    last_cr: bool
+}
+
+macro_rules! opn {
+    ($value:expr) => {
+        match $value {
+            Some(x) => x,
+            None => ::core::f32::NAN
+        }
+    }
+}
+
+impl Console {
+    pub fn new() -> Console {
+        Console {
+            line: [0; MAX_LINE],
+            line_len: 0,
+            last_cr: false
+        }
+    }
+
+    pub fn prompt(&self) {
+        print!("> ");
+    }
+
+    /// Returns true when a complete line has been entered.
+    fn input_char(&mut self, c: u8) -> bool {
+        let last_cr = self.last_cr;
+        self.last_cr = c == b'\r';
+        match c {
+            b'\n' if last_cr => false,
+            b'\r' | b'\n' => {
+                println!("");
+                true
+            }
+            // backspace, delete
+            0x08 | 0x7f => {
+                if self.line_len > 0 {
+                    self.line_len -= 1;
+                    print!("\x08 \x08");
+                }
+                false
+            }
+            // ^C
+            0x03 => {
+                println!("^C");
+                self.line_len = 0;
+                self.prompt();
+                false
+            }
+            // ^U
+            0x15 => {
+                while self.line_len > 0 {
+                    self.line_len -= 1;
+                    print!("\x08 \x08");
+                }
+                false
+            }
+            0x20..=0x7e => {
+                if self.line_len < self.line.len() {
+                    // Passwords are not echoed, so that they do not remain in
+                    // the terminal.
+                    let secret = self.line[..self.line_len].starts_with(b"password ");
+                    self.line[self.line_len] = c;
+                    self.line_len += 1;
+                    print!("{}", if secret { '*' } else { c as char });
+                } else {
+                    print!("\x07");
+                }
+                false
+            }
+            _ => false
+        }
+    }
+
+    pub fn poll(&mut self, config: &mut config::Config,
+                loop_anode_m: &Mutex<RefCell<loop_anode::Controller>>,
+                loop_cathode_m: &Mutex<RefCell<loop_cathode::Controller>>,
+                electrometer_m: &Mutex<RefCell<electrometer::Electrometer>>,
+                event_log_m: &Mutex<RefCell<event_log::EventLog>>,
+                time: u64) {
+        while let Some(c) = board::get_uart_char() {
+            if self.input_char(c) {
+                // Only printable ASCII is ever stored in the line buffer.
+                let line = str::from_utf8(&self.line[..self.line_len]).unwrap();
+                let result = execute(line, config, loop_anode_m, loop_cathode_m,
+                                     electrometer_m, event_log_m, time);
+                if let Err(err) = result {
+                    println!("error: {}", err);
+                }
+                self.line_len = 0;
+                self.prompt();
+            }
+        }
+    }
+}
+
+fn parse_f32(arg: &str) -> Result<f32, &'static str> {
+    arg.parse().map_err(|_| "invalid number")
+}
+
+fn parse_autorange(arg: &str) -> Result<electrometer::Autorange, &'static str> {
+    let mut values = [0u16; 4];
+    let mut fields = arg.split(',');
+    for value in values.iter_mut() {
+        *value = match fields.next().map(|field| field.parse()) {
+            Some(Ok(field)) => field,
+            _ => return Err("expected <up>,<down>,<dwell>,<settle>")
+        };
+    }
+    if fields.next().is_some() {
+        return Err("expected <up>,<down>,<dwell>,<settle>")
+    }
+    electrometer::Autorange {
+        up_threshold: values[0],
+        down_threshold: values[1],
+        dwell: values[2],
+        settle: values[3]
+    }.check()
+}
+
+fn execute(line: &str, config: &mut config::Config,
+           loop_anode_m: &Mutex<RefCell<loop_anode::Controller>>,
+           loop_cathode_m: &Mutex<RefCell<loop_cathode::Controller>>,
+           electrometer_m: &Mutex<RefCell<electrometer::Electrometer>>,
+           event_log_m: &Mutex<RefCell<event_log::EventLog>>,
+           time: u64) -> Result<(), &'static str> {
+    let mut args = line.split_whitespace();
+    let command = match args.next() {
+        None => return Ok(()),
+        Some(command) => command
+    };
+    let arg = args.next();
+    if args.next().is_some() {
+        return Err("too many arguments")
+    }
+
+    match (command, arg) {
+        ("help", None) => println!("{}", HELP),
+        ("status", None) => {
+            let (anode, cathode, electrometer) = cortex_m::interrupt::free(|cs| {
+                (loop_anode_m.borrow(cs).borrow().get_status(),
+                 loop_cathode_m.borrow(cs).borrow().get_status(),
+                 electrometer_m.borrow(cs).borrow().get_status())
+            });
+            let measurement = gauge::measure(&cathode, &electrometer, &config.calibration,
+                                             config.gas_sensitivity);
+            let uncertainty = gauge::pressure_uncertainty(&cathode, &electrometer,
+                                                          &config.calibration,
+                                                          config.gas_sensitivity);
+            println!("Outputs:                 {}", if config.output_enabled { "on" } else { "off" });
+            match measurement {
+                gauge::Measurement::Valid(pressure) =>
+                    println!("Pressure:                {:.3e} mbar", pressure),
+                gauge::Measurement::UnderRange(limit) | gauge::Measurement::OverRange(limit) =>
+                    println!("Pressure:                {} ({:.1e} mbar)", measurement.as_str(), limit),
+                _ => println!("Pressure:                {}", measurement.as_str())
+            }
+            println!("Uncertainty:             {:.2}%{}", opn!(uncertainty.map(|x| x*100.0)),
+                     if gauge::is_noisy(uncertainty, config.noise_threshold) { " (noisy)" } else { "" });
+            println!("Calibration:             {} (gas sensitivity {})", config.calibration.as_str(),
+                     config.gas_sensitivity);
+            println!("Anode regulator ready:   {}", anode.ready);
+            println!("Anode voltage:           {:.1}V (target {:.1}V{})", opn!(anode.av), config.anode,
+                     if anode.ramping { ", ramping" } else { "" });
+            println!("Cathode regulator ready: {}", cathode.ready);
+            println!("Electron current:        {:.1}uA (target {:.1}uA)",
+                     opn!(cathode.fbi.map(|x| x*1.0e6)), config.emission*1.0e6);
+            println!("Emission range:          {} ({} changes{})",
+                     cathode.fbi_range.as_str(), cathode.fbi_range_changes,
+                     if cathode.fbi_settling { ", settling" } else { "" });
+            println!("Filament voltage:        {:.2}V (target {:.2}V{})",
+                     opn!(cathode.fv), opn!(cathode.fv_target),
+                     if cathode.soft_start { ", soft-start" } else { "" });
+            println!("Cathode bias:            {:.1}V (target {:.1}V{})",
+                     opn!(cathode.fbv), config.cathode_bias,
+                     if cathode.bias_ramping { ", ramping" } else { "" });
+            println!("Ion current:             {:.5}nA (noise {:.5}nA)",
+                     opn!(electrometer.ic.map(|x| x*1.0e9)),
+                     opn!(electrometer.ic_stats.map(|x| x.std_dev*1.0e9)));
+            println!("Averaging:               {} over {:.4}s ({:.3}Hz bandwidth)",
+                     electrometer.filter.as_str(), electrometer.averaging_time,
+                     electrometer.bandwidth);
+            println!("Electrometer range:      {} ({}, {} changes{})",
+                     electrometer.range.as_str(), electrometer.mode.as_str(),
+                     electrometer.range_changes,
+                     if electrometer.settling { ", settling" } else { "" });
+            println!("Protection latched:      {}", board::error_latched());
+        }
+        ("ip", None) => println!("{}", config.ip),
+        ("ip", Some(arg)) => {
+            match arg.parse() {
+                Ok(ip @ IpCidr::Ipv4(_)) => config.ip = ip,
+                _ => return Err("invalid IPv4 address, expected <address>/<prefix>")
+            }
+            config.save_network();
+            ::log_event("IP address changed from console");
+            println!("IP address will be active after a reboot");
+        }
+        ("gateway", None) => match config.gateway {
+            None => println!("none"),
+            Some(gateway) => println!("{}", gateway)
+        },
+        ("gateway", Some(arg)) => {
+            config.gateway = if arg == "none" {
+                None
+            } else {
+                match arg.parse::<Ipv4Address>() {
+                    Ok(gateway) if !gateway.is_unspecified() => Some(gateway),
+                    _ => return Err("invalid gateway address")
+                }
+            };
+            config.save_network();
+            ::log_event("gateway changed from console");
+            println!("gateway will be active after a reboot");
+        }
+        ("mac", None) => match config.mac {
+            None => println!("{} (default)", EthernetAddress(board::get_mac_address())),
+            Some(mac) => println!("{}", mac)
+        },
+        ("mac", Some(arg)) => {
+            config.mac = if arg == "default" {
+                None
+            } else {
+                match arg.parse::<EthernetAddress>() {
+                    Ok(mac) if mac.is_unicast() => Some(mac),
+                    _ => return Err("invalid unicast MAC address")
+                }
+            };
+            config.save_network();
+            ::log_event("MAC address changed from console");
+            println!("MAC address will be active after a reboot");
+        }
+        ("anode", None) => println!("{:.1}V", config.anode),
+        ("anode", Some(arg)) => {
+            config.anode = gauge::check_anode(parse_f32(arg)?)?;
+            config.save_gauge();
+            gauge::apply_config(config, loop_anode_m, loop_cathode_m);
+            ::log_event("anode voltage changed from console");
+        }
+        ("bias", None) => println!("{:.1}V", config.cathode_bias),
+        ("bias", Some(arg)) => {
+            config.cathode_bias = gauge::check_cathode_bias(parse_f32(arg)?)?;
+            config.save_gauge();
+            gauge::apply_config(config, loop_anode_m, loop_cathode_m);
+            ::log_event("cathode bias changed from console");
+        }
+        ("emission", None) => println!("{:.3e}A", config.emission),
+        ("emission", Some(arg)) => {
+            config.emission = gauge::check_emission(parse_f32(arg)?)?;
+            config.save_gauge();
+            gauge::apply_config(config, loop_anode_m, loop_cathode_m);
+            ::log_event("emission current changed from console");
+        }
+        ("ramp", None) => println!("{}V/s", config.filament_ramp),
+        ("ramp", Some(arg)) => {
+            config.filament_ramp = gauge::check_filament_ramp(parse_f32(arg)?)?;
+            config.save_gauge();
+            gauge::apply_config(config, loop_anode_m, loop_cathode_m);
+            ::log_event("filament ramp rate changed from console");
+        }
+        ("slew", None) =>
+            println!("{}V/s,{}V/s", config.anode_slew_rate, config.bias_slew_rate),
+        ("slew", Some(arg)) => {
+            let mut rates = arg.split(',');
+            let (anode, bias) = match (rates.next(), rates.next(), rates.next()) {
+                (Some(anode), Some(bias), None) => (anode, bias),
+                _ => return Err("expected <anode>,<bias>")
+            };
+            let anode = gauge::check_slew_rate(parse_f32(anode)?)?;
+            let bias = gauge::check_slew_rate(parse_f32(bias)?)?;
+            config.anode_slew_rate = anode;
+            config.bias_slew_rate = bias;
+            config.save_gauge();
+            gauge::apply_config(config, loop_anode_m, loop_cathode_m);
+            ::log_event("slew rates changed from console");
+        }
+        ("diode", None) => {
+            let cathode = cortex_m::interrupt::free(|cs| {
+                loop_cathode_m.borrow(cs).borrow().get_status()
+            });
+            println!("{:.3}V (last measured in medium range {:.3}V)",
+                     config.diode_drop, opn!(cathode.fd));
+        }
+        ("diode", Some(arg)) => {
+            config.diode_drop = gauge::check_diode_drop(parse_f32(arg)?)?;
+            config.save_emission();
+            gauge::apply_config(config, loop_anode_m, loop_cathode_m);
+            ::log_event("emission diode drop changed from console");
+        }
+        ("output", None) => println!("{}", if config.output_enabled { "on" } else { "off" }),
+        ("output", Some(arg)) => {
+            config.output_enabled = match arg {
+                "on" => true,
+                "off" => false,
+                _ => return Err("expected \"on\" or \"off\"")
+            };
+            config.save_gauge();
+            gauge::apply_config(config, loop_anode_m, loop_cathode_m);
+            ::log_event(if config.output_enabled { "outputs enabled from console" }
+                        else { "outputs disabled from console" });
+        }
+        ("filter", None) => println!("{}", config.filter.as_str()),
+        ("filter", Some(arg)) => {
+            config.filter = arg.parse()?;
+            config.save_gauge();
+            gauge::apply_filter(config, loop_cathode_m, electrometer_m);
+            ::log_event("averaging filter changed from console");
+        }
+        ("average", None) => println!("{}s", config.averaging_time),
+        ("average", Some(arg)) => {
+            config.averaging_time = filter::check_averaging_time(parse_f32(arg)?)?;
+            config.save_gauge();
+            gauge::apply_filter(config, loop_cathode_m, electrometer_m);
+            ::log_event("averaging time changed from console");
+        }
+        ("noise", None) => println!("{}", config.noise_threshold),
+        ("noise", Some(arg)) => {
+            config.noise_threshold = gauge::check_noise_threshold(parse_f32(arg)?)?;
+            config.save_gauge();
+            ::log_event("noise threshold changed from console");
+        }
+        ("gas", None) => println!("{}", config.gas_sensitivity),
+        ("gas", Some(arg)) => {
+            config.gas_sensitivity = calibration::check_gas_sensitivity(parse_f32(arg)?)?;
+            config.save_gas();
+            ::log_event("gas sensitivity changed from console");
+        }
+        ("range", None) => {
+            let electrometer = cortex_m::interrupt::free(|cs| {
+                electrometer_m.borrow(cs).borrow().get_status()
+            });
+            println!("{} ({})", electrometer.range.as_str(), config.range_mode.as_str());
+        }
+        ("range", Some(arg)) => {
+            config.range_mode = arg.parse()?;
+            config.save_gauge();
+            gauge::apply_range(config, electrometer_m);
+            ::log_event("electrometer range mode changed from console");
+        }
+        ("autorange", None) =>
+            println!("{},{},{},{}", config.autorange.up_threshold, config.autorange.down_threshold,
+                     config.autorange.dwell, config.autorange.settle),
+        ("autorange", Some(arg)) => {
+            config.autorange = parse_autorange(arg)?;
+            config.save_gauge();
+            gauge::apply_range(config, electrometer_m);
+            ::log_event("autorange settings changed from console");
+        }
+        ("tpg", None) => println!("{}", if config.tpg_enabled { "on" } else { "off" }),
+        ("tpg", Some(arg)) => {
+            config.tpg_enabled = match arg {
+                "on" => true,
+                "off" => false,
+                _ => return Err("expected \"on\" or \"off\"")
+            };
+            config.save_network();
+            ::log_event(if config.tpg_enabled { "TPG server enabled from console" }
+                        else { "TPG server disabled from console" });
+        }
+        ("password", None) =>
+            println!("{}", if config.password.is_some() { "set" } else { "not set" }),
+        ("password", Some(arg)) => {
+            if arg == "none" {
+                config.password = None;
+                config.save_auth();
+                ::log_event("admin password cleared from console");
+            } else {
+                config.password = Some(auth::PasswordHash::new(arg.as_bytes(), time,
+                                                               &::get_entropy()));
+                config.save_auth();
+                ::log_event("admin password changed from console");
+            }
+        }
+        ("allow", None) => {
+            let mut empty = true;
+            for cidr in config.allowlist.iter().filter_map(|cidr| *cidr) {
+                println!("{}", cidr);
+                empty = false;
+            }
+            if empty {
+                println!("any address");
+            }
+        }
+        ("allow", Some("none")) => {
+            config.allowlist = [None; auth::MAX_ALLOWLIST];
+            config.save_allowlist();
+            ::log_event("allowlist cleared from console");
+        }
+        ("allow", Some(arg)) => {
+            let cidr = match arg.parse() {
+                Ok(IpCidr::Ipv4(cidr)) => cidr,
+                _ => return Err("invalid IPv4 network, expected <address>/<prefix>")
+            };
+            if !config.allowlist.contains(&Some(cidr)) {
+                match config.allowlist.iter_mut().find(|entry| entry.is_none()) {
+                    Some(entry) => *entry = Some(cidr),
+                    None => return Err("allowlist is full")
+                }
+                config.save_allowlist();
+                ::log_event("allowlist changed from console");
+            }
+        }
+        ("disallow", Some(arg)) => {
+            let cidr: Ipv4Cidr = match arg.parse() {
+                Ok(IpCidr::Ipv4(cidr)) => cidr,
+                _ => return Err("invalid IPv4 network, expected <address>/<prefix>")
+            };
+            match config.allowlist.iter_mut().find(|entry| **entry == Some(cidr)) {
+                Some(entry) => *entry = None,
+                None => return Err("no such allowlist entry")
+            }
+            config.save_allowlist();
+            ::log_event("allowlist changed from console");
+        }
+        ("log", None) => {
+            // Copy the log so that the slow UART output happens with interrupts enabled.
+            let event_log = cortex_m::interrupt::free(|cs| *event_log_m.borrow(cs).borrow());
+            for event in event_log.iter() {
+                println!("[{:>10}.{:03}] {}", event.time/1000, event.time%1000, event.message);
+            }
+        }
+        ("reboot", None) => {
+            println!("rebooting");
+            board::reset();
+        }
+        ("help", _) | ("status", _) | ("log", _) | ("reboot", _) =>
+            return Err("too many arguments"),
+        _ => return Err("unknown command, type \"help\" for a list")
+    }
+    Ok(())
+}
@@ -1,87 +1,249 @@
+use core::str::FromStr;
+
 use board;
+use filter;
+
+const ADC_MAX: u16 = 4095;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RangeMode {
+    Auto,
+    Hold(board::ElectrometerRange)
+}
+
+impl RangeMode {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            RangeMode::Auto => "auto",
+            RangeMode::Hold(range) => range.as_str()
+        }
+    }
+}
+
+impl FromStr for RangeMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<RangeMode, &'static str> {
+        match s.trim() {
+            "auto" => Ok(RangeMode::Auto),
+            "low" => Ok(RangeMode::Hold(board::ElectrometerRange::Low)),
+            "med" => Ok(RangeMode::Hold(board::ElectrometerRange::Med)),
+            "high" => Ok(RangeMode::Hold(board::ElectrometerRange::High)),
+            _ => Err("expected \"auto\", \"low\", \"med\" or \"high\"")
+        }
+    }
+}
+
+/// Autoranging parameters, in raw ADC counts and samples.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Autorange {
+    /// Switch to the next less sensitive range above this reading.
+    pub up_threshold: u16,
+    /// Switch to the next more sensitive range below this reading.
+    pub down_threshold: u16,
+    /// Number of consecutive out-of-window samples before switching.
+    pub dwell: u16,
+    /// Number of samples discarded after switching, while the input settles.
+    pub settle: u16
+}
+
+pub const AUTORANGE_DEFAULT: Autorange = Autorange {
+    up_threshold: 3100,
+    down_threshold: 105,
+    dwell: 75,
+    settle: 150
+};
+
+fn gain(range: board::ElectrometerRange) -> f32 {
+    match range {
+        board::ElectrometerRange::Low => board::IC_ADC_GAIN_LOW,
+        board::ElectrometerRange::Med => board::IC_ADC_GAIN_MED,
+        board::ElectrometerRange::High => board::IC_ADC_GAIN_HIGH
+    }
+}
+
+impl Autorange {
+    /// Checks that the parameters are usable, and in particular that a
+    /// reading at one threshold lands inside the window of the range it
+    /// switches to, so that the electrometer cannot flap between ranges.
+    pub fn check(&self) -> Result<Autorange, &'static str> {
+        if self.up_threshold > ADC_MAX {
+            return Err("up threshold above ADC full scale")
+        }
+        if self.down_threshold as f32 <= board::IC_ADC_OFFSET {
+            return Err("down threshold not above ADC offset")
+        }
+        if self.down_threshold >= self.up_threshold {
+            return Err("down threshold not below up threshold")
+        }
+        if self.dwell == 0 {
+            return Err("dwell must be at least one sample")
+        }
+
+        let ratio_low = board::IC_ADC_GAIN_LOW/board::IC_ADC_GAIN_MED;
+        let ratio_high = board::IC_ADC_GAIN_MED/board::IC_ADC_GAIN_HIGH;
+        let ratio = if ratio_low > ratio_high { ratio_low } else { ratio_high };
+        let up = self.up_threshold as f32 - board::IC_ADC_OFFSET;
+        let down = self.down_threshold as f32 - board::IC_ADC_OFFSET;
+        if up/ratio <= down || down*ratio >= up {
+            return Err("thresholds too close, no hysteresis between ranges")
+        }
+        Ok(*self)
+    }
+}
 
 pub struct Electrometer {
     range: board::ElectrometerRange,
-    out_of_range_count: u8,
-    ignore_count: u8,
-    ic_buffer: f32,
-    ic_buffer_count: usize,
-    last_ic: Option<f32>
+    mode: RangeMode,
+    autorange: Autorange,
+    range_changes: u32,
+    out_of_range_count: u16,
+    over_range_count: u16,
+    ignore_count: u16,
+    ic_filter: filter::Filter
 }
 
 #[derive(Clone, Copy)]
 pub struct ElectrometerStatus {
-    pub ic: Option<f32>
+    pub ic: Option<f32>,
+    pub ic_stats: Option<filter::Statistics>,
+    /// Incremented with each new value of `ic`.
+    pub ic_count: u32,
+    /// Number of new values of `ic` per second.
+    pub ic_rate: f32,
+    pub range: board::ElectrometerRange,
+    pub mode: RangeMode,
+    pub autorange: Autorange,
+    pub range_changes: u32,
+    pub settling: bool,
+    /// The input has stayed above the up threshold with no less sensitive
+    /// range to switch to.
+    pub over_range: bool,
+    /// Highest current that can be measured in the current range mode.
+    pub ic_max: f32,
+    pub filter: filter::Kind,
+    pub averaging_time: f32,
+    pub bandwidth: f32
 }
 
 impl Electrometer {
     pub const fn new() -> Electrometer {
         Electrometer {
             range: board::ElectrometerRange::Med,
+            mode: RangeMode::Auto,
+            autorange: AUTORANGE_DEFAULT,
+            range_changes: 0,
             out_of_range_count: 0,
+            over_range_count: 0,
             ignore_count: 0,
-            ic_buffer: 0.0,
-            ic_buffer_count: 0,
-            last_ic: None
+            ic_filter: filter::Filter::new()
         }
     }
 
+    pub fn set_filter(&mut self, kind: filter::Kind, seconds: f32) {
+        self.ic_filter.configure(kind, seconds);
+    }
+
+    pub fn set_range_mode(&mut self, mode: RangeMode) {
+        self.mode = mode;
+        self.out_of_range_count = 0;
+        if let RangeMode::Hold(range) = mode {
+            if range != self.range {
+                self.switch_range(range);
+            }
+        }
+    }
+
+    pub fn set_autorange(&mut self, autorange: Autorange) {
+        self.autorange = autorange;
+        self.out_of_range_count = 0;
+    }
+
+    /// Returns the least sensitive range available in the current mode.
+    fn top_range(&self) -> board::ElectrometerRange {
+        match self.mode {
+            RangeMode::Hold(range) => range,
+            RangeMode::Auto => board::ElectrometerRange::High
+        }
+    }
+
+    fn switch_range(&mut self, range: board::ElectrometerRange) {
+        self.range = range;
+        self.range_changes = self.range_changes.wrapping_add(1);
+        self.out_of_range_count = 0;
+        self.over_range_count = 0;
+        self.ignore_count = self.autorange.settle;
+        self.ic_filter.reset();
+        board::set_electrometer_range(range);
+    }
+
     pub fn adc_input(&mut self, ic_sample: u16) {
         if self.ignore_count > 0 {
             self.ignore_count -= 1;
-        } else {
-            let mut new_range = if ic_sample > 3100 {
+            return;
+        }
+
+        let mut new_range = match self.mode {
+            RangeMode::Hold(_) => None,
+            RangeMode::Auto if ic_sample > self.autorange.up_threshold => {
                 match self.range {
                     board::ElectrometerRange::Low => Some(board::ElectrometerRange::Med),
                     board::ElectrometerRange::Med => Some(board::ElectrometerRange::High),
                     board::ElectrometerRange::High => None
                 }
-            } else if ic_sample < 105 {
+            }
+            RangeMode::Auto if ic_sample < self.autorange.down_threshold => {
                 match self.range {
                     board::ElectrometerRange::Low => None,
                     board::ElectrometerRange::Med => Some(board::ElectrometerRange::Low),
                     board::ElectrometerRange::High => Some(board::ElectrometerRange::Med)
                 }
-            } else {
-                None
-            };
-
-            if new_range.is_some() {
-                self.out_of_range_count += 1;
-                if self.out_of_range_count < 75 {
-                    new_range = None;
-                }
-            } else {
-                self.out_of_range_count = 0;
             }
+            RangeMode::Auto => None
+        };
 
-            if new_range.is_some() {
-                self.ignore_count = 150;
-                self.ic_buffer = 0.0;
-                self.ic_buffer_count = 0;
-                self.last_ic = None;
-                self.range = new_range.unwrap();
-                board::set_electrometer_range(self.range);
-            } else {
-                let gain = match self.range {
-                    board::ElectrometerRange::Low => board::IC_ADC_GAIN_LOW,
-                    board::ElectrometerRange::Med => board::IC_ADC_GAIN_MED,
-                    board::ElectrometerRange::High => board::IC_ADC_GAIN_HIGH
-                };
-                self.ic_buffer += ((ic_sample as f32) - board::IC_ADC_OFFSET)/gain;
-                self.ic_buffer_count += 1;
-                if self.ic_buffer_count == 512 {
-                    self.last_ic = Some(self.ic_buffer/512.0);
-                    self.ic_buffer = 0.0;
-                    self.ic_buffer_count = 0;
-                }
+        if self.range == self.top_range() && ic_sample > self.autorange.up_threshold {
+            if self.over_range_count < self.autorange.dwell {
+                self.over_range_count += 1;
+            }
+        } else {
+            self.over_range_count = 0;
+        }
+
+        if new_range.is_some() {
+            self.out_of_range_count += 1;
+            if self.out_of_range_count < self.autorange.dwell {
+                new_range = None;
+            }
+        } else {
+            self.out_of_range_count = 0;
+        }
+
+        match new_range {
+            Some(range) => self.switch_range(range),
+            None => {
+                let gain = gain(self.range);
+                self.ic_filter.input(((ic_sample as f32) - board::IC_ADC_OFFSET)/gain);
             }
         }
     }
 
     pub fn get_status(&self) -> ElectrometerStatus {
         ElectrometerStatus {
-            ic: self.last_ic
+            ic: self.ic_filter.output(),
+            ic_stats: self.ic_filter.statistics(),
+            ic_count: self.ic_filter.output_count(),
+            ic_rate: self.ic_filter.output_rate(),
+            range: self.range,
+            mode: self.mode,
+            autorange: self.autorange,
+            range_changes: self.range_changes,
+            settling: self.ignore_count > 0,
+            over_range: self.over_range_count >= self.autorange.dwell,
+            ic_max: (self.autorange.up_threshold as f32 - board::IC_ADC_OFFSET)/gain(self.top_range()),
+            filter: self.ic_filter.kind(),
+            averaging_time: self.ic_filter.averaging_time(),
+            bandwidth: self.ic_filter.bandwidth()
         }
     }
 }
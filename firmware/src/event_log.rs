@@ -0,0 +1,38 @@
+const LOG_LEN: usize = 32;
+
+#[derive(Clone, Copy)]
+pub struct Event {
+    pub time: u64,
+    pub message: &'static str
+}
+
+#[derive(Clone, Copy)]
+pub struct EventLog {
+    events: [Event; LOG_LEN],
+    next: usize,
+    count: usize
+}
+
+impl EventLog {
+    pub const fn new() -> EventLog {
+        EventLog {
+            events: [Event { time: 0, message: "" }; LOG_LEN],
+            next: 0,
+            count: 0
+        }
+    }
+
+    pub fn push(&mut self, time: u64, message: &'static str) {
+        self.events[self.next] = Event { time: time, message: message };
+        self.next = (self.next + 1) % LOG_LEN;
+        if self.count < LOG_LEN {
+            self.count += 1;
+        }
+    }
+
+    /// Iterates over the recorded events, oldest first.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item=&'a Event> {
+        let first = (self.next + LOG_LEN - self.count) % LOG_LEN;
+        (0..self.count).map(move |i| &self.events[(first + i) % LOG_LEN])
+    }
+}
@@ -0,0 +1,298 @@
+//! Pressure history, kept at several resolutions.
+//!
+//! Readings are fed at a fixed rate and summarized into one sample per
+//! period for each tier. Each tier is a ring buffer indexed by the period
+//! number, i.e. the uptime divided by the tier resolution, so that the
+//! time of a sample does not need to be stored. The empty history is all
+//! zeroes, so that it does not take any space in flash.
+
+use core::{f32, fmt};
+use libm::F32Ext;
+
+use gauge;
+
+/// Interval between two readings fed into the history.
+pub const INPUT_INTERVAL_MS: u64 = 100;
+
+pub struct Tier {
+    /// Duration of each sample, in seconds.
+    pub resolution: u64,
+    /// Number of samples kept.
+    pub len: usize,
+    offset: usize
+}
+
+pub const TIERS: [Tier; 3] = [
+    Tier { resolution: 1, len: 3600, offset: 0 },       // 1 hour
+    Tier { resolution: 60, len: 1440, offset: 3600 },   // 1 day
+    Tier { resolution: 600, len: 1008, offset: 5040 }   // 1 week
+];
+
+const STORAGE_LEN: usize = 6048;
+
+// Number of samples of each tier that are not served, because they are about
+// to be overwritten. This leaves a minute for the fastest tier to be
+// downloaded, during which the served samples must not change.
+const OVERWRITE_MARGIN: usize = 60;
+
+// Status flags of a sample, set if any reading of the period had the status.
+const FLAG_VALID: u8 = 0x01;
+const FLAG_UNDER_RANGE: u8 = 0x02;
+const FLAG_OVER_RANGE: u8 = 0x04;
+const FLAG_EMISSION_NOT_REGULATED: u8 = 0x08;
+const FLAG_SETTLING: u8 = 0x10;
+const FLAG_OUTPUT_DISABLED: u8 = 0x20;
+const FLAG_NOISY: u8 = 0x40;
+// The emission current was available for at least one reading.
+const FLAG_EMISSION: u8 = 0x80;
+
+const FLAG_NAMES: [(u8, &'static str); 7] = [
+    (FLAG_VALID, "valid"),
+    (FLAG_UNDER_RANGE, "under_range"),
+    (FLAG_OVER_RANGE, "over_range"),
+    (FLAG_EMISSION_NOT_REGULATED, "emission_not_regulated"),
+    (FLAG_SETTLING, "settling"),
+    (FLAG_OUTPUT_DISABLED, "output_disabled"),
+    (FLAG_NOISY, "noisy")
+];
+
+// Pressures and currents are stored as 16-bit steps of their logarithm, from
+// 1e-15 to 1e5, which keeps them within 0.04% and halves the size of the
+// history. Step 0 stands for zero, and for the values below the range.
+const LOG_MIN: f32 = -15.0;
+const LOG_STEPS_PER_DECADE: f32 = 65534.0/20.0;
+
+fn encode(value: f32) -> u16 {
+    if !(value > 0.0) {
+        return 0
+    }
+    let step = (value.log10() - LOG_MIN)*LOG_STEPS_PER_DECADE;
+    if step < 0.0 {
+        0
+    } else if step > 65534.0 {
+        65535
+    } else {
+        step.round() as u16 + 1
+    }
+}
+
+fn decode(step: u16) -> f32 {
+    if step == 0 {
+        return 0.0
+    }
+    let log = LOG_MIN + ((step - 1) as f32)/LOG_STEPS_PER_DECADE;
+    (log*f32::consts::LN_10).exp()
+}
+
+/// Summary of the readings of one period. Pressures are in mbar and only
+/// account for valid readings. A sample without flags has no readings at all.
+#[derive(Clone, Copy)]
+pub struct Sample {
+    min: u16,
+    mean: u16,
+    max: u16,
+    emission: u16,
+    flags: u8
+}
+
+pub const EMPTY_SAMPLE: Sample = Sample {
+    min: 0,
+    mean: 0,
+    max: 0,
+    emission: 0,
+    flags: 0
+};
+
+impl Sample {
+    fn valid(&self, value: u16) -> Option<f32> {
+        if self.flags & FLAG_VALID != 0 { Some(decode(value)) } else { None }
+    }
+
+    pub fn min(&self) -> Option<f32> {
+        self.valid(self.min)
+    }
+
+    pub fn mean(&self) -> Option<f32> {
+        self.valid(self.mean)
+    }
+
+    pub fn max(&self) -> Option<f32> {
+        self.valid(self.max)
+    }
+
+    /// Returns the mean emission current, in amperes.
+    pub fn emission(&self) -> Option<f32> {
+        if self.flags & FLAG_EMISSION != 0 { Some(decode(self.emission)) } else { None }
+    }
+
+    /// Returns the names of the statuses of the readings.
+    pub fn status(&self, separator: &'static str, quote: &'static str) -> StatusFmt {
+        StatusFmt {
+            flags: self.flags,
+            separator: separator,
+            quote: quote
+        }
+    }
+}
+
+/// Writes the status names of a sample, separated by `separator` and each
+/// enclosed in `quote`.
+pub struct StatusFmt {
+    flags: u8,
+    separator: &'static str,
+    quote: &'static str
+}
+
+impl fmt::Display for StatusFmt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut first = true;
+        for &(flag, name) in FLAG_NAMES.iter() {
+            if self.flags & flag != 0 {
+                if !first {
+                    f.write_str(self.separator)?;
+                }
+                write!(f, "{}{}{}", self.quote, name, self.quote)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Accumulator {
+    count: u32,
+    sum: f32,
+    min: f32,
+    max: f32,
+    emission_count: u32,
+    emission_sum: f32,
+    flags: u8
+}
+
+const EMPTY_ACCUMULATOR: Accumulator = Accumulator {
+    count: 0,
+    sum: 0.0,
+    min: 0.0,
+    max: 0.0,
+    emission_count: 0,
+    emission_sum: 0.0,
+    flags: 0
+};
+
+impl Accumulator {
+    fn input(&mut self, measurement: gauge::Measurement, emission: Option<f32>, noisy: bool) {
+        if let Some(pressure) = measurement.pressure() {
+            if self.count == 0 || pressure < self.min {
+                self.min = pressure;
+            }
+            if self.count == 0 || pressure > self.max {
+                self.max = pressure;
+            }
+            self.count += 1;
+            self.sum += pressure;
+        }
+        if let Some(emission) = emission {
+            self.emission_count += 1;
+            self.emission_sum += emission;
+            self.flags |= FLAG_EMISSION;
+        }
+        self.flags |= match measurement {
+            gauge::Measurement::Valid(_) => FLAG_VALID,
+            gauge::Measurement::UnderRange(_) => FLAG_UNDER_RANGE,
+            gauge::Measurement::OverRange(_) => FLAG_OVER_RANGE,
+            gauge::Measurement::EmissionNotRegulated => FLAG_EMISSION_NOT_REGULATED,
+            gauge::Measurement::Settling => FLAG_SETTLING,
+            gauge::Measurement::OutputDisabled => FLAG_OUTPUT_DISABLED
+        };
+        if noisy {
+            self.flags |= FLAG_NOISY;
+        }
+    }
+
+    fn sample(&self) -> Sample {
+        let mut sample = EMPTY_SAMPLE;
+        if self.count > 0 {
+            sample.min = encode(self.min);
+            sample.mean = encode(self.sum/(self.count as f32));
+            sample.max = encode(self.max);
+        }
+        if self.emission_count > 0 {
+            sample.emission = encode(self.emission_sum/(self.emission_count as f32));
+        }
+        sample.flags = self.flags;
+        sample
+    }
+}
+
+#[derive(Clone, Copy)]
+struct TierState {
+    /// Period being accumulated.
+    period: u64,
+    /// Number of samples stored, for the periods preceding `period`.
+    count: usize,
+    accumulator: Accumulator
+}
+
+pub struct History {
+    samples: [Sample; STORAGE_LEN],
+    tiers: [TierState; 3]
+}
+
+impl History {
+    pub const fn new() -> History {
+        History {
+            samples: [EMPTY_SAMPLE; STORAGE_LEN],
+            tiers: [TierState { period: 0, count: 0, accumulator: EMPTY_ACCUMULATOR }; 3]
+        }
+    }
+
+    /// Records a reading, taken `time` milliseconds after boot.
+    pub fn input(&mut self, time: u64, measurement: gauge::Measurement,
+                 emission: Option<f32>, noisy: bool) {
+        for (tier, state) in TIERS.iter().zip(self.tiers.iter_mut()) {
+            let period = time/(tier.resolution*1000);
+            if period != state.period {
+                let storage = &mut self.samples[tier.offset..tier.offset + tier.len];
+                storage[(state.period % tier.len as u64) as usize] = state.accumulator.sample();
+                state.count += 1;
+                // Periods without readings, if the main loop has stalled.
+                let mut missed = state.period + 1;
+                if period - missed > tier.len as u64 {
+                    missed = period - tier.len as u64;
+                }
+                while missed < period {
+                    storage[(missed % tier.len as u64) as usize] = EMPTY_SAMPLE;
+                    state.count += 1;
+                    missed += 1;
+                }
+                if state.count > tier.len {
+                    state.count = tier.len;
+                }
+                state.period = period;
+                state.accumulator = EMPTY_ACCUMULATOR;
+            }
+            state.accumulator.input(measurement, emission, noisy);
+        }
+    }
+
+    /// Returns the range of periods of a tier that can be served, end excluded.
+    pub fn periods(&self, tier: usize) -> (u64, u64) {
+        let state = &self.tiers[tier];
+        let mut count = state.count;
+        if count > TIERS[tier].len - OVERWRITE_MARGIN {
+            count = TIERS[tier].len - OVERWRITE_MARGIN;
+        }
+        (state.period - count as u64, state.period)
+    }
+
+    pub fn get(&self, tier: usize, period: u64) -> Option<Sample> {
+        let state = &self.tiers[tier];
+        if period >= state.period - state.count as u64 && period < state.period {
+            let tier = &TIERS[tier];
+            Some(self.samples[tier.offset + (period % tier.len as u64) as usize])
+        } else {
+            None
+        }
+    }
+}
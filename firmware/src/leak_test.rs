@@ -0,0 +1,399 @@
+//! Rate-of-rise test: with the chamber isolated from its pumps, the pressure
+//! rise is fitted over a fixed duration, and converted into a leak rate
+//! using the chamber volume.
+//!
+//! The exponential fit is linear for a given time constant, so the sums of
+//! the linear least squares are accumulated for a range of time constants,
+//! and the best one is interpolated when the rate is requested. This keeps
+//! the cost of each reading bounded, and nothing has to be fitted while the
+//! readings are locked.
+//!
+//! Every new ion current reading is used for the fit. Only a subset of them
+//! is kept in the trace, with a stride that is chosen when the test starts
+//! so that the trace is only ever appended to.
+
+use core::str::FromStr;
+use libm::F32Ext;
+
+#[cfg(target_arch = "arm")]
+use gauge;
+
+pub const DURATION_MIN: f32 = 1.0;
+pub const DURATION_MAX: f32 = 3600.0;
+pub const VOLUME_MAX: f32 = 1.0e6;
+
+const TRACE_LEN: usize = 2048;
+/// Number of time constants of the exponential fit, spaced logarithmically
+/// from 1/TIME_CONSTANT_RANGE to TIME_CONSTANT_RANGE times the duration.
+const TIME_CONSTANTS: usize = 24;
+const TIME_CONSTANT_RANGE: f32 = 20.0;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Fit {
+    /// Constant rise, as caused by a leak
+    Linear,
+    /// Exponential approach to an equilibrium pressure, as caused by
+    /// outgassing or a virtual leak. The rate of rise is reported at the end
+    /// of the test.
+    Exponential
+}
+
+impl Fit {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Fit::Linear => "linear",
+            Fit::Exponential => "exponential"
+        }
+    }
+}
+
+impl FromStr for Fit {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Fit, &'static str> {
+        match s.trim() {
+            "linear" => Ok(Fit::Linear),
+            "exponential" => Ok(Fit::Exponential),
+            _ => Err("expected \"linear\" or \"exponential\"")
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Idle,
+    Running,
+    Finished,
+    Aborted(&'static str)
+}
+
+impl State {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            State::Idle => "idle",
+            State::Running => "running",
+            State::Finished => "finished",
+            State::Aborted(_) => "aborted"
+        }
+    }
+}
+
+pub fn check_duration(seconds: f32) -> Result<f32, &'static str> {
+    if seconds >= DURATION_MIN && seconds <= DURATION_MAX {
+        Ok(seconds)
+    } else {
+        Err("duration out of range")
+    }
+}
+
+pub fn check_volume(liters: f32) -> Result<f32, &'static str> {
+    if liters > 0.0 && liters <= VOLUME_MAX {
+        Ok(liters)
+    } else {
+        Err("volume out of range")
+    }
+}
+
+/// Pressure reading, at a time in seconds from the start of the test.
+#[derive(Clone, Copy)]
+pub struct Point {
+    pub time: f32,
+    pub pressure: f32
+}
+
+#[derive(Clone, Copy)]
+pub struct LeakTestStatus {
+    pub state: State,
+    pub fit: Fit,
+    pub duration: f32,
+    pub volume: f32,
+    pub elapsed: f32,
+    pub points: u32,
+    pub trace_len: usize,
+    /// Rate of rise, in mbar/s
+    pub rate: Option<f32>,
+    /// Leak rate, in mbar.L/s
+    pub leak_rate: Option<f32>
+}
+
+pub struct LeakTest {
+    state: State,
+    fit: Fit,
+    duration: f32,
+    volume: f32,
+    start: u64,
+    elapsed: f32,
+    last_count: u32,
+    stride: u32,
+    // Least squares sums. Double precision avoids the cancellation in the
+    // slope over long tests.
+    n: u32,
+    sum_t: f64,
+    sum_tt: f64,
+    sum_y: f64,
+    sum_ty: f64,
+    sum_yy: f64,
+    // Sums of x = exp(-t/tau) for each time constant of the exponential fit.
+    time_constants: [f32; TIME_CONSTANTS],
+    sum_x: [f64; TIME_CONSTANTS],
+    sum_xx: [f64; TIME_CONSTANTS],
+    sum_xy: [f64; TIME_CONSTANTS],
+    trace: [Point; TRACE_LEN],
+    trace_len: usize
+}
+
+impl LeakTest {
+    pub const fn new() -> LeakTest {
+        LeakTest {
+            state: State::Idle,
+            fit: Fit::Linear,
+            duration: 0.0,
+            volume: 0.0,
+            start: 0,
+            elapsed: 0.0,
+            last_count: 0,
+            stride: 0,
+            n: 0,
+            sum_t: 0.0,
+            sum_tt: 0.0,
+            sum_y: 0.0,
+            sum_ty: 0.0,
+            sum_yy: 0.0,
+            time_constants: [0.0; TIME_CONSTANTS],
+            sum_x: [0.0; TIME_CONSTANTS],
+            sum_xx: [0.0; TIME_CONSTANTS],
+            sum_xy: [0.0; TIME_CONSTANTS],
+            trace: [Point { time: 0.0, pressure: 0.0 }; TRACE_LEN],
+            trace_len: 0
+        }
+    }
+
+    /// Starts a test at `time` (in ms), given the electrometer reading counter
+    /// and the number of readings per second.
+    pub fn start(&mut self, time: u64, fit: Fit, duration: f32, volume: f32,
+                 count: u32, rate: f32) {
+        self.state = State::Running;
+        self.fit = fit;
+        self.duration = duration;
+        self.volume = volume;
+        self.start = time;
+        self.elapsed = 0.0;
+        self.last_count = count;
+        self.stride = ((duration*rate/(TRACE_LEN as f32)).ceil() as u32).max(1);
+        self.n = 0;
+        self.sum_t = 0.0;
+        self.sum_tt = 0.0;
+        self.sum_y = 0.0;
+        self.sum_ty = 0.0;
+        self.sum_yy = 0.0;
+        for i in 0..TIME_CONSTANTS {
+            let exponent = 2.0*(i as f32)/((TIME_CONSTANTS - 1) as f32) - 1.0;
+            self.time_constants[i] = duration*TIME_CONSTANT_RANGE.powf(exponent);
+            self.sum_x[i] = 0.0;
+            self.sum_xx[i] = 0.0;
+            self.sum_xy[i] = 0.0;
+        }
+        self.trace_len = 0;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state == State::Running
+    }
+
+    pub fn abort(&mut self, reason: &'static str) {
+        if self.state == State::Running {
+            self.state = State::Aborted(reason);
+        }
+    }
+
+    /// Feeds the current measurement, which is only used if the electrometer
+    /// reading counter `count` has changed since the last call.
+    #[cfg(target_arch = "arm")]
+    pub fn input(&mut self, time: u64, count: u32, measurement: gauge::Measurement) {
+        if self.state != State::Running || count == self.last_count {
+            return
+        }
+        self.last_count = count;
+        self.elapsed = ((time - self.start) as f32)/1000.0;
+
+        match measurement {
+            gauge::Measurement::Valid(pressure) => self.add_point(pressure),
+            // Range changes are expected while the pressure rises.
+            gauge::Measurement::Settling => (),
+            gauge::Measurement::OutputDisabled => self.state = State::Aborted("outputs disabled"),
+            gauge::Measurement::EmissionNotRegulated =>
+                self.state = State::Aborted("emission not regulated"),
+            gauge::Measurement::UnderRange(_) => self.state = State::Aborted("under range"),
+            gauge::Measurement::OverRange(_) => self.state = State::Aborted("over range")
+        }
+
+        if self.state == State::Running && self.elapsed >= self.duration {
+            self.state = State::Finished;
+        }
+    }
+
+    /// Adds a pressure reading, taken at the current elapsed time, to the fit.
+    fn add_point(&mut self, pressure: f32) {
+        let t = self.elapsed as f64;
+        let y = pressure as f64;
+        if self.n % self.stride == 0 && self.trace_len < TRACE_LEN {
+            self.trace[self.trace_len] = Point { time: self.elapsed, pressure: pressure };
+            self.trace_len += 1;
+        }
+        self.n += 1;
+        self.sum_t += t;
+        self.sum_tt += t*t;
+        self.sum_y += y;
+        self.sum_ty += t*y;
+        if self.fit == Fit::Exponential {
+            self.sum_yy += y*y;
+            for i in 0..TIME_CONSTANTS {
+                let x = (-self.elapsed/self.time_constants[i]).exp() as f64;
+                self.sum_x[i] += x;
+                self.sum_xx[i] += x*x;
+                self.sum_xy[i] += x*y;
+            }
+        }
+    }
+
+    /// Fits P = a + b*exp(-t/tau) for the time constant at the fractional
+    /// index `position`, whose sums are interpolated quadratically between
+    /// the neighbouring ones. Returns the residual sum of squares and b.
+    fn fit_exponential(&self, position: f32) -> Option<(f64, f64)> {
+        let center = (position.round() as usize).max(1).min(TIME_CONSTANTS - 2);
+        let d = (position - center as f32) as f64;
+        let weights = [d*(d - 1.0)/2.0, 1.0 - d*d, d*(d + 1.0)/2.0];
+        let interpolate = |sums: &[f64; TIME_CONSTANTS]| {
+            weights[0]*sums[center - 1] + weights[1]*sums[center] + weights[2]*sums[center + 1]
+        };
+        let n = self.n as f64;
+        let sum_x = interpolate(&self.sum_x);
+        let s_xx = interpolate(&self.sum_xx) - sum_x*sum_x/n;
+        let s_xy = interpolate(&self.sum_xy) - sum_x*self.sum_y/n;
+        let s_yy = self.sum_yy - self.sum_y*self.sum_y/n;
+        if !(s_xx > 0.0) {
+            return None
+        }
+        Some((s_yy - s_xy*s_xy/s_xx, s_xy/s_xx))
+    }
+
+    /// Returns the rate of rise at the end of the exponential fit. The time
+    /// constant is the one of the smallest residual, refined with a parabola
+    /// through its neighbours.
+    fn exponential_rate(&self) -> Option<f32> {
+        let mut best: Option<(usize, f64)> = None;
+        for i in 0..TIME_CONSTANTS {
+            if let Some((residual, _)) = self.fit_exponential(i as f32) {
+                match best {
+                    Some((_, best_residual)) if best_residual <= residual => (),
+                    _ => best = Some((i, residual))
+                }
+            }
+        }
+        let (index, residual) = best?;
+        let mut position = index as f32;
+        if index > 0 && index < TIME_CONSTANTS - 1 {
+            if let (Some((before, _)), Some((after, _))) =
+                    (self.fit_exponential(position - 1.0), self.fit_exponential(position + 1.0)) {
+                let curvature = before - 2.0*residual + after;
+                if curvature > 0.0 {
+                    position += (0.5*(before - after)/curvature) as f32;
+                }
+            }
+        }
+        let (_, b) = self.fit_exponential(position)?;
+        let step = 2.0*TIME_CONSTANT_RANGE.ln()/((TIME_CONSTANTS - 1) as f32);
+        let time_constant = self.time_constants[0]*(position*step).exp();
+        let decay = (-self.elapsed/time_constant).exp();
+        Some(-(b as f32)/time_constant*decay)
+    }
+
+    /// Returns the fitted rate of rise, in mbar/s.
+    fn rate(&self) -> Option<f32> {
+        let n = self.n as f64;
+        let denominator = n*self.sum_tt - self.sum_t*self.sum_t;
+        if self.n < 2 || denominator <= 0.0 {
+            return None
+        }
+        match self.fit {
+            Fit::Linear => Some(((n*self.sum_ty - self.sum_t*self.sum_y)/denominator) as f32),
+            Fit::Exponential => self.exponential_rate()
+        }
+    }
+
+    pub fn get_status(&self) -> LeakTestStatus {
+        let rate = self.rate();
+        LeakTestStatus {
+            state: self.state,
+            fit: self.fit,
+            duration: self.duration,
+            volume: self.volume,
+            elapsed: self.elapsed,
+            points: self.n,
+            trace_len: self.trace_len,
+            rate: rate,
+            leak_rate: rate.map(|rate| rate*self.volume)
+        }
+    }
+
+    pub fn trace_point(&self, index: usize) -> Option<Point> {
+        if index < self.trace_len {
+            Some(self.trace[index])
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::prelude::v1::*;
+    use super::*;
+
+    const DURATION: f32 = 600.0;
+    const READING_RATE: f32 = 10.0;
+    const VOLUME: f32 = 2.0;
+
+    /// Runs a test on a synthetic trace, and returns the rate of rise.
+    fn run<F: Fn(f32) -> f32>(fit: Fit, pressure: F) -> Option<f32> {
+        let mut leak_test = LeakTest::new();
+        leak_test.start(0, fit, DURATION, VOLUME, 0, READING_RATE);
+        for i in 0..(DURATION*READING_RATE) as u32 + 1 {
+            leak_test.elapsed = i as f32/READING_RATE;
+            leak_test.add_point(pressure(leak_test.elapsed));
+        }
+        let status = leak_test.get_status();
+        assert!(status.points == (DURATION*READING_RATE) as u32 + 1);
+        assert!(status.trace_len <= TRACE_LEN);
+        assert!(status.leak_rate == status.rate.map(|rate| rate*VOLUME));
+        status.rate
+    }
+
+    #[test]
+    fn linear_rise() {
+        let rate = run(Fit::Linear, |t| 1.0e-7 + 2.0e-10*t).unwrap();
+        assert!((rate/2.0e-10 - 1.0).abs() < 1.0e-3);
+    }
+
+    #[test]
+    fn saturating_rise() {
+        // Outgassing towards an equilibrium pressure: the rate of rise at
+        // the end is much smaller than the average one.
+        let (initial, equilibrium) = (1.0e-7f32, 5.0e-7f32);
+        for &time_constant in [75.0f32, 220.0, 600.0, 3000.0].iter() {
+            let pressure = |t: f32| equilibrium - (equilibrium - initial)*(-t/time_constant).exp();
+            let expected = (equilibrium - initial)/time_constant*(-DURATION/time_constant).exp();
+            let rate = run(Fit::Exponential, &pressure).unwrap();
+            assert!((rate/expected - 1.0).abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn too_few_points() {
+        let mut leak_test = LeakTest::new();
+        leak_test.start(0, Fit::Exponential, DURATION, VOLUME, 0, READING_RATE);
+        assert!(leak_test.get_status().rate.is_none());
+        leak_test.add_point(1.0e-7);
+        assert!(leak_test.get_status().rate.is_none());
+    }
+}
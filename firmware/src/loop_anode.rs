@@ -3,34 +3,52 @@ use libm::F32Ext;
 use board;
 use pid;
 
-const PID_PARAMETERS: pid::Parameters = pid::Parameters {
+/// Default slew rate of the anode voltage, in volts per second.
+pub const SLEW_RATE_DEFAULT: f32 = 50.0;
+
+// The gains and integral limits were originally tuned per sample.
+pub const PID_PARAMETERS: pid::Parameters = pid::Parameters {
     kp: 0.2,
-    ki: 0.05,
+    ki: 0.05/board::ADC_SAMPLE_PERIOD,
     kd: 0.0,
     output_min: 0.0,
     output_max: 225.0,
-    integral_min: -700.0,
-    integral_max: 700.0
+    integral_min: -700.0*board::ADC_SAMPLE_PERIOD,
+    integral_max: 700.0*board::ADC_SAMPLE_PERIOD,
+    setpoint_weight: 1.0,
+    derivative_filter: 0.0,
+    anti_windup: pid::AntiWindup::Clamp,
+    tracking: 1.0e-3,
+    rate_limit: 0.0
 };
 
 
 pub struct Controller {
     pid: pid::Controller,
+    enabled: bool,
     target: f32,
+    ramp: pid::Ramp,
     last_av: Option<f32>
 }
 
 #[derive(Clone, Copy)]
 pub struct ControllerStatus {
+    pub enabled: bool,
     pub ready: bool,
-    pub av: Option<f32>
+    pub av: Option<f32>,
+    pub av_target: f32,
+    /// Setpoint of the loop, which follows the target at the slew rate.
+    pub av_setpoint: Option<f32>,
+    pub ramping: bool
 }
 
 impl Controller {
     pub const fn new() -> Controller {
         Controller {
-            pid: pid::Controller::new(PID_PARAMETERS),
+            pid: pid::Controller::new(PID_PARAMETERS, board::ADC_SAMPLE_PERIOD),
+            enabled: false,
             target: 0.0,
+            ramp: pid::Ramp::new(SLEW_RATE_DEFAULT),
             last_av: None
         }
     }
@@ -39,16 +57,42 @@ impl Controller {
         let av = av_sample as f32/board::AV_ADC_GAIN;
         self.last_av = Some(av);
 
-        let hv_pwm_duty = self.pid.update(av);
+        let hv_pwm_duty = if self.enabled {
+            // After a reset, the ramp starts from the remaining voltage.
+            let setpoint = self.ramp.update(av, board::ADC_SAMPLE_PERIOD);
+            self.pid.set_target(setpoint);
+            self.pid.update(av)
+        } else {
+            0.0
+        };
         board::set_hv_pwm(hv_pwm_duty as u16)
     }
 
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled != self.enabled {
+            self.enabled = enabled;
+            self.reset();
+        }
+    }
+
     pub fn set_target(&mut self, volts: f32) {
         self.target = volts;
-        self.pid.set_target(volts);
+        self.ramp.set_target(volts);
+    }
+
+    /// Sets the slew rate of the anode voltage, in volts per second.
+    pub fn set_slew_rate(&mut self, volts_per_second: f32) {
+        self.ramp.set_rate(volts_per_second);
+    }
+
+    pub fn set_pid_parameters(&mut self, parameters: pid::Parameters) {
+        self.pid.set_parameters(parameters);
     }
 
     fn ready(&self) -> bool {
+        if !self.enabled || self.ramp.is_ramping() {
+            return false
+        }
         match self.last_av {
             None => false,
             Some(last_av) => (last_av - self.target).abs() < 2.0
@@ -57,13 +101,18 @@ impl Controller {
 
     pub fn reset(&mut self) {
         self.pid.reset();
+        self.ramp.reset();
         board::set_hv_pwm(0);
     }
 
     pub fn get_status(&self) -> ControllerStatus {
         ControllerStatus {
+            enabled: self.enabled,
             ready: self.ready(),
-            av: self.last_av
+            av: self.last_av,
+            av_target: self.target,
+            av_setpoint: self.ramp.get_setpoint(),
+            ramping: self.enabled && self.ramp.is_ramping()
         }
     }
 }
@@ -2,134 +2,332 @@ use libm::F32Ext;
 
 use board;
 use pid;
+use filter;
 
-const FBI_PID_PARAMETERS: pid::Parameters = pid::Parameters {
+// The gains and integral limits were originally tuned per sample.
+pub const FBI_PID_PARAMETERS: pid::Parameters = pid::Parameters {
     kp: 200.0,
-    ki: 20.0,
-    kd: 10.0,
+    ki: 20.0/board::ADC_SAMPLE_PERIOD,
+    kd: 10.0*board::ADC_SAMPLE_PERIOD,
     output_min: 0.5,
     output_max: 10.0,
-    integral_min: -0.1,
-    integral_max: 0.1
+    integral_min: -0.1*board::ADC_SAMPLE_PERIOD,
+    integral_max: 0.1*board::ADC_SAMPLE_PERIOD,
+    setpoint_weight: 1.0,
+    derivative_filter: 0.0,
+    anti_windup: pid::AntiWindup::Clamp,
+    tracking: 1.0e-3,
+    rate_limit: 0.0
 };
 
-const FV_PID_PARAMETERS: pid::Parameters = pid::Parameters {
+pub const FV_PID_PARAMETERS: pid::Parameters = pid::Parameters {
     kp: 20.0,
-    ki: 1.5,
+    ki: 1.5/board::ADC_SAMPLE_PERIOD,
     kd: 0.0,
     output_min: 0.0,
     output_max: 150.0,
-    integral_min: -50.0,
-    integral_max: 50.0
+    integral_min: -50.0*board::ADC_SAMPLE_PERIOD,
+    integral_max: 50.0*board::ADC_SAMPLE_PERIOD,
+    setpoint_weight: 1.0,
+    derivative_filter: 0.0,
+    anti_windup: pid::AntiWindup::Clamp,
+    tracking: 1.0e-3,
+    rate_limit: 0.0
 };
 
+/// Default rate of the filament voltage ramp at switch-on, in volts per
+/// second.
+pub const FV_RAMP_DEFAULT: f32 = 0.5;
+/// Default slew rate of the cathode bias, in volts per second.
+pub const BIAS_SLEW_RATE_DEFAULT: f32 = 20.0;
+/// Default diode drop in the high emission range, in volts.
+pub const FD_HIGH_DEFAULT: f32 = 0.9;
+
+// Emission range switching thresholds, in amperes. The current must stay
+// past a threshold for the dwell time before switching, and the readings
+// are discarded while the input settles, in samples.
+const LOW_UP: f32 = 120e-6;
+const MED_DOWN: f32 = 100e-6;
+const MED_UP: f32 = 8e-3;
+const HIGH_DOWN: f32 = 6.5e-3;
+const RANGE_DWELL: u16 = 25;
+const RANGE_SETTLE: u16 = 50;
+
+/// Returns the most sensitive range that can measure the emission target.
+fn target_range(amperes: f32) -> board::EmissionRange {
+    if amperes > MED_UP {
+        board::EmissionRange::High
+    } else if amperes > LOW_UP {
+        board::EmissionRange::Med
+    } else {
+        board::EmissionRange::Low
+    }
+}
+
 pub struct Controller {
+    enabled: bool,
+
     fbi_target: f32,
     fbi_range: board::EmissionRange,
-    fbi_buffer: [f32; 16],
-    fbi_buffer_count: usize,
-    last_fbi: Option<f32>,
+    fbi_range_changes: u32,
+    /// Consecutive samples past a switching threshold.
+    fbi_range_count: u16,
+    /// Samples left to discard after a range switch.
+    fbi_settle_count: u16,
+    /// The emission PID must continue from its last output once the
+    /// input has settled.
+    fbi_handover: bool,
+    fbi_filter: filter::Filter,
     fbi_pid: pid::Controller,
     last_fv_target: Option<f32>,
 
     fv_pid: pid::Controller,
     last_fv: Option<f32>,
+    fv_ramp_rate: f32,
+    /// Limit of the filament voltage target during the soft-start.
+    fv_ramp: Option<f32>,
 
     fbv_target: f32,
-    last_fbv: Option<f32>
+    fbv_ramp: pid::Ramp,
+    last_fbv: Option<f32>,
+
+    fd_high: f32,
+    /// Diode drop last measured in the medium emission range.
+    last_fd: Option<f32>
 }
 
 #[derive(Clone, Copy)]
 pub struct ControllerStatus {
+    pub enabled: bool,
     pub ready: bool,
+    pub emission_ready: bool,
+    pub bias_ready: bool,
     pub fbi: Option<f32>,
+    pub fbi_stats: Option<filter::Statistics>,
+    pub fbi_target: f32,
+    pub fbi_range: board::EmissionRange,
+    pub fbi_range_changes: u32,
+    pub fbi_settling: bool,
     pub fv_target: Option<f32>,
     pub fv: Option<f32>,
-    pub fbv: Option<f32>
+    pub soft_start: bool,
+    pub fbv: Option<f32>,
+    pub fbv_target: f32,
+    /// Bias setpoint, which follows the target at the slew rate.
+    pub fbv_setpoint: Option<f32>,
+    pub bias_ramping: bool,
+    pub fd: Option<f32>,
+    pub fd_high: f32
 }
 
 impl Controller {
     pub const fn new() -> Controller {
         Controller {
+            enabled: false,
+
             fbi_target: 0.0,
             fbi_range: board::EmissionRange::Med,
-            fbi_buffer: [0.0; 16],
-            fbi_buffer_count: 0,
-            last_fbi: None,
-            fbi_pid: pid::Controller::new(FBI_PID_PARAMETERS),
+            fbi_range_changes: 0,
+            fbi_range_count: 0,
+            fbi_settle_count: 0,
+            fbi_handover: false,
+            fbi_filter: filter::Filter::new(),
+            fbi_pid: pid::Controller::new(FBI_PID_PARAMETERS, board::ADC_SAMPLE_PERIOD),
             last_fv_target: None,
 
-            fv_pid: pid::Controller::new(FV_PID_PARAMETERS),
+            fv_pid: pid::Controller::new(FV_PID_PARAMETERS, board::ADC_SAMPLE_PERIOD),
             last_fv: None,
+            fv_ramp_rate: FV_RAMP_DEFAULT,
+            fv_ramp: None,
 
             fbv_target: 0.0,
+            fbv_ramp: pid::Ramp::new(BIAS_SLEW_RATE_DEFAULT),
             last_fbv: None,
+
+            fd_high: FD_HIGH_DEFAULT,
+            last_fd: None
         }
     }
 
     pub fn adc_input(&mut self, fbi_sample: u16, fd_sample: u16, fv_sample: u16, fbv_sample: u16) {
-        let fbi_voltage = ((fbi_sample as f32) - board::FBI_ADC_OFFSET)/board::FBI_ADC_GAIN;
-        let fbi_r225 = fbi_voltage/board::FBI_R225;
-        let fbi = match self.fbi_range {
-            board::EmissionRange::Low => fbi_r225,
-            board::EmissionRange::Med => {
-                let fd_voltage = ((fd_sample as f32) - board::FD_ADC_OFFSET)/board::FD_ADC_GAIN;
-                fbi_r225 + (fbi_voltage - fd_voltage)/board::FBI_R223
-            },
-            board::EmissionRange::High => {
-                let fd_voltage = 0.9;
-                fbi_r225 + (fbi_voltage - fd_voltage)/board::FBI_R224
-            }
+        let fbi = if self.fbi_settle_count > 0 {
+            self.fbi_settle_count -= 1;
+            None
+        } else {
+            let fbi_voltage = ((fbi_sample as f32) - board::FBI_ADC_OFFSET)/board::FBI_ADC_GAIN;
+            let fbi_r225 = fbi_voltage/board::FBI_R225;
+            let fbi = match self.fbi_range {
+                board::EmissionRange::Low => fbi_r225,
+                board::EmissionRange::Med => {
+                    let fd_voltage = ((fd_sample as f32) - board::FD_ADC_OFFSET)/board::FD_ADC_GAIN;
+                    self.last_fd = Some(fd_voltage);
+                    fbi_r225 + (fbi_voltage - fd_voltage)/board::FBI_R223
+                },
+                board::EmissionRange::High => {
+                    fbi_r225 + (fbi_voltage - self.fd_high)/board::FBI_R224
+                }
+            };
+            self.fbi_filter.input(fbi);
+            Some(fbi)
         };
-        self.fbi_buffer[self.fbi_buffer_count] = fbi;
-        self.fbi_buffer_count += 1;
-        if self.fbi_buffer_count == self.fbi_buffer.len() {
-            let mut fbi_avg: f32 = 0.0;
-            for fbi in self.fbi_buffer.iter() {
-                fbi_avg += *fbi;
-            }
-            self.last_fbi = Some(fbi_avg/(self.fbi_buffer.len() as f32));
-            self.fbi_buffer_count = 0;
-        }
-
-        let fv_target = self.fbi_pid.update(fbi);
-        self.last_fv_target = Some(fv_target);
-        self.fv_pid.set_target(fv_target);
 
         let fv = fv_sample as f32/board::FV_ADC_GAIN;
-        let fv_pwm_duty = self.fv_pid.update(fv);
-        board::set_fv_pwm(120);
+        let fbv = fbv_sample as f32/board::FBV_ADC_GAIN;
+        if self.enabled {
+            // After a reset, the ramp starts from the remaining voltage.
+            let fbv_setpoint = self.fbv_ramp.update(fbv, board::ADC_SAMPLE_PERIOD);
+            board::set_fbv_pwm((fbv_setpoint/board::FBV_PWM_GAIN) as u16);
+
+            let fv_target = match fbi {
+                Some(fbi) => {
+                    if self.fbi_handover {
+                        self.fbi_handover = false;
+                        if let Some(fv_target) = self.last_fv_target {
+                            self.fbi_pid.initialize(fbi, fv_target);
+                        }
+                    }
+                    let mut fv_target = self.fbi_pid.update(fbi);
+                    // A cold filament has a low resistance, so the voltage is
+                    // ramped up from zero until the emission loop asks for less.
+                    // Meanwhile the emission loop tracks the ramp, so that it
+                    // takes over without winding up.
+                    if let Some(fv_ramp) = self.fv_ramp {
+                        if fv_target < fv_ramp {
+                            self.fv_ramp = None;
+                        } else {
+                            fv_target = fv_ramp;
+                            self.fbi_pid.initialize(fbi, fv_ramp);
+                            self.fv_ramp = Some(fv_ramp + self.fv_ramp_rate*board::ADC_SAMPLE_PERIOD);
+                        }
+                    }
+                    self.autorange(fbi);
+                    fv_target
+                }
+                // The filament voltage target is held while the input
+                // settles after a range switch.
+                None => self.last_fv_target.unwrap_or(0.0)
+            };
+            self.last_fv_target = Some(fv_target);
+            self.fv_pid.set_target(fv_target);
+
+            let fv_pwm_duty = self.fv_pid.update(fv);
+            board::set_fv_pwm(fv_pwm_duty as u16);
+        } else {
+            board::set_fv_pwm(0);
+        }
 
         self.last_fv = Some(fv);
-        self.last_fbv = Some(fbv_sample as f32/board::FBV_ADC_GAIN);
+        self.last_fbv = Some(fbv);
     }
 
+    fn switch_range(&mut self, range: board::EmissionRange) {
+        self.fbi_range = range;
+        self.fbi_range_changes = self.fbi_range_changes.wrapping_add(1);
+        self.fbi_range_count = 0;
+        self.fbi_settle_count = RANGE_SETTLE;
+        self.fbi_handover = true;
+        board::set_emission_range(range);
+    }
+
+    /// Switches to a less sensitive range when the emission current stays
+    /// above the top of the current range, and back when it stays below the
+    /// lower threshold. Ranges more sensitive than that of the target are
+    /// not used, so that the gauge does not switch range while warming up.
+    fn autorange(&mut self, fbi: f32) {
+        let min_range = target_range(self.fbi_target);
+        let next_range = match self.fbi_range {
+            board::EmissionRange::Low if fbi > LOW_UP => Some(board::EmissionRange::Med),
+            board::EmissionRange::Med if fbi > MED_UP => Some(board::EmissionRange::High),
+            board::EmissionRange::Med if fbi < MED_DOWN && min_range == board::EmissionRange::Low =>
+                Some(board::EmissionRange::Low),
+            board::EmissionRange::High if fbi < HIGH_DOWN && min_range != board::EmissionRange::High =>
+                Some(board::EmissionRange::Med),
+            _ => None
+        };
+        match next_range {
+            Some(range) => {
+                self.fbi_range_count += 1;
+                if self.fbi_range_count >= RANGE_DWELL {
+                    self.switch_range(range);
+                }
+            }
+            None => self.fbi_range_count = 0
+        }
+    }
+
+    /// Sets the emission current target. The range switches immediately if
+    /// the target is above the current range, and otherwise follows the
+    /// measured current.
     pub fn set_emission_target(&mut self, amperes: f32) {
         self.fbi_target = amperes;
         self.fbi_pid.set_target(amperes);
-        self.fbi_range = board::EmissionRange::Low;
-        if amperes > 120e-6 {
-            self.fbi_range = board::EmissionRange::Med;
-        }
-        if amperes > 8e-3 {
-            self.fbi_range = board::EmissionRange::High;
+        let range = target_range(amperes);
+        if range > self.fbi_range {
+            self.switch_range(range);
         }
-        board::set_emission_range(self.fbi_range);
+    }
+
+    /// Sets the drop of the diode in the high emission range, in volts,
+    /// which is not measured in that range.
+    pub fn set_fd_high(&mut self, volts: f32) {
+        self.fd_high = volts;
+    }
+
+    /// Sets the averaging of the reported emission current, which should match
+    /// that of the ion current so that the pressure is computed consistently.
+    pub fn set_filter(&mut self, kind: filter::Kind, seconds: f32) {
+        self.fbi_filter.configure(kind, seconds);
+    }
+
+    /// Sets the rate of the filament voltage ramp at switch-on, in volts
+    /// per second.
+    pub fn set_fv_ramp(&mut self, volts_per_second: f32) {
+        self.fv_ramp_rate = volts_per_second;
+    }
+
+    pub fn set_fbi_pid_parameters(&mut self, parameters: pid::Parameters) {
+        self.fbi_pid.set_parameters(parameters);
+    }
+
+    pub fn set_fv_pid_parameters(&mut self, parameters: pid::Parameters) {
+        self.fv_pid.set_parameters(parameters);
     }
 
     pub fn set_bias_target(&mut self, volts: f32) {
         self.fbv_target = volts;
-        board::set_fbv_pwm((volts/board::FBV_PWM_GAIN) as u16);
+        self.fbv_ramp.set_target(volts);
+    }
+
+    /// Sets the slew rate of the cathode bias, in volts per second.
+    pub fn set_bias_slew_rate(&mut self, volts_per_second: f32) {
+        self.fbv_ramp.set_rate(volts_per_second);
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled != self.enabled {
+            self.enabled = enabled;
+            self.reset();
+            if !enabled {
+                board::set_fv_pwm(0);
+                board::set_fbv_pwm(0);
+            }
+        }
     }
 
     fn emission_ready(&self) -> bool {
-        match self.last_fbi {
+        if !self.enabled {
+            return false
+        }
+        match self.fbi_filter.output() {
             None => false,
             Some(last_fbi) => (self.fbi_target - last_fbi).abs()/self.fbi_target < 0.05
         }
     }
 
     fn bias_ready(&self) -> bool {
+        if self.fbv_ramp.is_ramping() {
+            return false
+        }
         match self.last_fbv {
             None => false,
             Some(last_fbv) => (self.fbv_target - last_fbv).abs() < 1.0
@@ -140,19 +338,40 @@ impl Controller {
         self.fbi_pid.reset();
         self.fv_pid.reset();
         self.last_fv_target = None;
-        self.fbi_buffer_count = 0;
-        self.last_fbi = None;
+        self.fv_ramp = Some(0.0);
+        self.fbv_ramp.reset();
+        self.fbi_filter.reset();
+        // Start from the range of the target, with a cold filament.
+        let range = target_range(self.fbi_target);
+        if range != self.fbi_range {
+            self.switch_range(range);
+        }
+        self.fbi_handover = false;
         self.last_fv = None;
         self.last_fbv = None;
     }
 
     pub fn get_status(&self) -> ControllerStatus {
         ControllerStatus {
+            enabled: self.enabled,
             ready: self.emission_ready() & self.bias_ready(),
-            fbi: self.last_fbi,
+            emission_ready: self.emission_ready(),
+            bias_ready: self.bias_ready(),
+            fbi: self.fbi_filter.output(),
+            fbi_stats: self.fbi_filter.statistics(),
+            fbi_target: self.fbi_target,
+            fbi_range: self.fbi_range,
+            fbi_range_changes: self.fbi_range_changes,
+            fbi_settling: self.fbi_settle_count > 0,
             fv_target: self.last_fv_target,
             fv: self.last_fv,
-            fbv: self.last_fbv
+            soft_start: self.enabled && self.fv_ramp.is_some(),
+            fbv: self.last_fbv,
+            fbv_target: self.fbv_target,
+            fbv_setpoint: self.fbv_ramp.get_setpoint(),
+            bias_ramping: self.enabled && self.fbv_ramp.is_ramping(),
+            fd: self.last_fd,
+            fd_high: self.fd_high
         }
     }
 
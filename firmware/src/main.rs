@@ -1,22 +1,41 @@
 #![feature(const_fn)]
 #![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_main)]
+// Only the modules that do not depend on the board support crates are built
+// on the host, for the unit tests, where the float methods of std make
+// those of libm unused.
+#![cfg_attr(not(target_arch = "arm"), allow(dead_code, unused_imports))]
+
+#[cfg(test)]
+extern crate std;
 
 extern crate libm;
+#[cfg(target_arch = "arm")]
 extern crate cortex_m;
+#[cfg(target_arch = "arm")]
 #[macro_use]
 extern crate cortex_m_rt;
+#[cfg(target_arch = "arm")]
 #[macro_use(interrupt)]
 extern crate tm4c129x;
+#[cfg(target_arch = "arm")]
 extern crate smoltcp;
+#[cfg(target_arch = "arm")]
 extern crate crc;
 
+#[cfg(target_arch = "arm")]
 use core::cell::{Cell, RefCell};
+#[cfg(target_arch = "arm")]
 use core::fmt;
+#[cfg(target_arch = "arm")]
 use cortex_m::interrupt::Mutex;
+#[cfg(target_arch = "arm")]
 use smoltcp::time::Instant;
+#[cfg(target_arch = "arm")]
 use smoltcp::wire::EthernetAddress;
-use smoltcp::iface::{NeighborCache, EthernetInterfaceBuilder};
+#[cfg(target_arch = "arm")]
+use smoltcp::iface::{NeighborCache, Routes, EthernetInterfaceBuilder};
+#[cfg(target_arch = "arm")]
 use smoltcp::socket::{SocketSet, TcpSocket, TcpSocketBuffer};
 
 #[macro_export]
@@ -33,27 +52,68 @@ macro_rules! println {
     ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"), $($arg)*));
 }
 
+#[cfg(target_arch = "arm")]
 #[no_mangle] // https://github.com/rust-lang/rust/issues/{38281,51647}
 #[panic_handler]
 pub fn panic_fmt(info: &core::panic::PanicInfo) -> ! {
+    // Stop the regulators and switch off the high voltage before reporting.
+    cortex_m::interrupt::disable();
+    board::set_hv_pwm(0);
+    board::set_fv_pwm(0);
+    board::set_fbv_pwm(0);
     println!("{}", info);
     loop {}
 }
 
 #[macro_use]
 mod board;
+#[cfg(target_arch = "arm")]
 mod eeprom;
+#[cfg(target_arch = "arm")]
 mod config;
+#[cfg(target_arch = "arm")]
 mod ethmac;
 mod pid;
 mod loop_anode;
 mod loop_cathode;
+#[cfg(target_arch = "arm")]
 mod electrometer;
+mod filter;
+#[cfg(target_arch = "arm")]
+mod gauge;
+#[cfg(target_arch = "arm")]
+mod event_log;
+#[cfg(target_arch = "arm")]
+mod history;
+#[cfg(target_arch = "arm")]
+mod chart;
+mod leak_test;
+#[cfg(target_arch = "arm")]
+mod calibration;
+#[cfg(target_arch = "arm")]
+mod self_test;
+#[cfg(target_arch = "arm")]
+mod degas;
+mod args;
+#[cfg(target_arch = "arm")]
 mod http;
+#[cfg(target_arch = "arm")]
+mod assets;
+#[cfg(target_arch = "arm")]
 mod pages;
-
+#[cfg(target_arch = "arm")]
+mod console;
+#[cfg(target_arch = "arm")]
+mod tpg;
+#[cfg(target_arch = "arm")]
+mod sha256;
+#[cfg(target_arch = "arm")]
+mod auth;
+
+#[cfg(target_arch = "arm")]
 static ADC_IRQ_COUNT: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
 
+#[cfg(target_arch = "arm")]
 fn get_time_ms() -> u64 {
     let adc_irq_count = cortex_m::interrupt::free(|cs| {
         ADC_IRQ_COUNT.borrow(cs).get()
@@ -61,18 +121,51 @@ fn get_time_ms() -> u64 {
     adc_irq_count*24/125
 }
 
+#[cfg(target_arch = "arm")]
+static ENTROPY: Mutex<RefCell<auth::Entropy>> = Mutex::new(RefCell::new(auth::Entropy::new()));
+
+#[cfg(target_arch = "arm")]
+fn get_entropy() -> auth::Entropy {
+    cortex_m::interrupt::free(|cs| *ENTROPY.borrow(cs).borrow())
+}
+
+#[cfg(target_arch = "arm")]
 static LOOP_ANODE: Mutex<RefCell<loop_anode::Controller>> = Mutex::new(RefCell::new(
     loop_anode::Controller::new()));
 
+#[cfg(target_arch = "arm")]
 static LOOP_CATHODE: Mutex<RefCell<loop_cathode::Controller>> = Mutex::new(RefCell::new(
     loop_cathode::Controller::new()));
 
+#[cfg(target_arch = "arm")]
 static ELECTROMETER: Mutex<RefCell<electrometer::Electrometer>> = Mutex::new(RefCell::new(
     electrometer::Electrometer::new()));
 
+#[cfg(target_arch = "arm")]
+static EVENT_LOG: Mutex<RefCell<event_log::EventLog>> = Mutex::new(RefCell::new(
+    event_log::EventLog::new()));
+
+#[cfg(target_arch = "arm")]
+static HISTORY: Mutex<RefCell<history::History>> = Mutex::new(RefCell::new(
+    history::History::new()));
+
+#[cfg(target_arch = "arm")]
+static LEAK_TEST: Mutex<RefCell<leak_test::LeakTest>> = Mutex::new(RefCell::new(
+    leak_test::LeakTest::new()));
 
+#[cfg(target_arch = "arm")]
+fn log_event(message: &'static str) {
+    let time = get_time_ms();
+    cortex_m::interrupt::free(|cs| {
+        EVENT_LOG.borrow(cs).borrow_mut().push(time, message);
+    });
+}
+
+
+#[cfg(target_arch = "arm")]
 pub struct UART0;
 
+#[cfg(target_arch = "arm")]
 impl fmt::Write for UART0 {
     fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
         let uart_0 = unsafe { &*tm4c129x::UART0::ptr() };
@@ -84,17 +177,30 @@ impl fmt::Write for UART0 {
     }
 }
 
+#[cfg(target_arch = "arm")]
 const TCP_RX_BUFFER_SIZE: usize = 256;
+#[cfg(target_arch = "arm")]
 const TCP_TX_BUFFER_SIZE: usize = 8192;
 
 
+#[cfg(target_arch = "arm")]
 macro_rules! create_socket_storage {
     ($rx_storage:ident, $tx_storage:ident) => (
-        let mut $rx_storage = [0; TCP_RX_BUFFER_SIZE];
-        let mut $tx_storage = [0; TCP_TX_BUFFER_SIZE];
+        // The buffers are statics rather than on the stack, so that running
+        // out of RAM is a link error. Each expansion has its own, and main
+        // runs only once, so that they are borrowed only here.
+        let $rx_storage = unsafe {
+            static mut STORAGE: [u8; TCP_RX_BUFFER_SIZE] = [0; TCP_RX_BUFFER_SIZE];
+            &mut STORAGE
+        };
+        let $tx_storage = unsafe {
+            static mut STORAGE: [u8; TCP_TX_BUFFER_SIZE] = [0; TCP_TX_BUFFER_SIZE];
+            &mut STORAGE
+        };
     )
 }
 
+#[cfg(target_arch = "arm")]
 macro_rules! create_socket {
     ($set:ident, $rx_storage:ident, $tx_storage:ident, $target:ident) => (
         let tcp_rx_buffer = TcpSocketBuffer::new(&mut $rx_storage[..]);
@@ -104,6 +210,7 @@ macro_rules! create_socket {
     )
 }
 
+#[cfg(target_arch = "arm")]
 #[entry]
 fn main() -> ! {
     board::init();
@@ -117,34 +224,11 @@ fn main() -> ! {
         config.load();
     }
 
-    cortex_m::interrupt::free(|cs| {
-        let mut loop_anode = LOOP_ANODE.borrow(cs).borrow_mut();
-        let mut loop_cathode = LOOP_CATHODE.borrow(cs).borrow_mut();
-
-        // ZJ-10
-        let anode = 165.0;
-        let cathode_bias = 50.0;
-        let emission = 0.5e-3;
-
-        // ZJ-27
-        /*let anode = 225.0;
-        let cathode_bias = 25.0;
-        let emission = 1.0e-3;*/
-
-        // ZJ-12
-        /*let anode = 200.0;
-        let cathode_bias = 50.0;
-        let emission = 4.0e-3;*/
-
-        // G8130
-        /*let anode = 180.0;
-        let cathode_bias = 30.0;
-        let emission = 4.0e-3;*/
-
-        loop_anode.set_target(anode);
-        loop_cathode.set_emission_target(emission);
-        loop_cathode.set_bias_target(cathode_bias);
-    });
+    gauge::apply_config(&config, &LOOP_ANODE, &LOOP_CATHODE);
+    gauge::apply_filter(&config, &LOOP_CATHODE, &ELECTROMETER);
+    gauge::apply_range(&config, &ELECTROMETER);
+    gauge::apply_pid(&config, &LOOP_ANODE, &LOOP_CATHODE);
+    log_event("boot");
 
     println!(r#"
   _                         _
@@ -157,7 +241,7 @@ fn main() -> ! {
                |_|
 "#);
 
-    let mut hardware_addr = EthernetAddress(board::get_mac_address());
+    let mut hardware_addr = config.mac.unwrap_or(EthernetAddress(board::get_mac_address()));
     if hardware_addr.is_multicast() {
         println!("programmed MAC address is invalid, using default");
         hardware_addr = EthernetAddress([0x10, 0xE2, 0xD5, 0x00, 0x03, 0x00]);
@@ -166,12 +250,19 @@ fn main() -> ! {
     println!("MAC {} IP {}", hardware_addr, ip_addrs[0]);
     let mut neighbor_cache_storage = [None; 8];
     let neighbor_cache = NeighborCache::new(&mut neighbor_cache_storage[..]);
+    let mut routes_storage = [None; 1];
+    let mut routes = Routes::new(&mut routes_storage[..]);
+    if let Some(gateway) = config.gateway {
+        println!("gateway {}", gateway);
+        routes.add_default_ipv4_route(gateway).unwrap();
+    }
     let mut device = ethmac::Device::new();
     unsafe { device.init(hardware_addr) };
     let mut iface = EthernetInterfaceBuilder::new(&mut device)
                 .ethernet_addr(hardware_addr)
                 .neighbor_cache(neighbor_cache)
                 .ip_addrs(&mut ip_addrs[..])
+                .routes(routes)
                 .finalize();
 
     create_socket_storage!(tcp_rx_storage0, tcp_tx_storage0);
@@ -182,8 +273,9 @@ fn main() -> ! {
     create_socket_storage!(tcp_rx_storage5, tcp_tx_storage5);
     create_socket_storage!(tcp_rx_storage6, tcp_tx_storage6);
     create_socket_storage!(tcp_rx_storage7, tcp_tx_storage7);
+    create_socket_storage!(tpg_rx_storage, tpg_tx_storage);
 
-    let mut socket_set_entries: [_; 8] = Default::default();
+    let mut socket_set_entries: [_; 9] = Default::default();
     let mut sockets = SocketSet::new(&mut socket_set_entries[..]);
 
     create_socket!(sockets, tcp_rx_storage0, tcp_tx_storage0, tcp_handle0);
@@ -194,51 +286,193 @@ fn main() -> ! {
     create_socket!(sockets, tcp_rx_storage5, tcp_tx_storage5, tcp_handle5);
     create_socket!(sockets, tcp_rx_storage6, tcp_tx_storage6, tcp_handle6);
     create_socket!(sockets, tcp_rx_storage7, tcp_tx_storage7, tcp_handle7);
+    create_socket!(sockets, tpg_rx_storage, tpg_tx_storage, tpg_handle);
 
     let mut sessions = [
-        (http::Request::new(), tcp_handle0),
-        (http::Request::new(), tcp_handle1),
-        (http::Request::new(), tcp_handle2),
-        (http::Request::new(), tcp_handle3),
-        (http::Request::new(), tcp_handle4),
-        (http::Request::new(), tcp_handle5),
-        (http::Request::new(), tcp_handle6),
-        (http::Request::new(), tcp_handle7),
+        (http::Request::new(), http::Connection::new(), None, tcp_handle0),
+        (http::Request::new(), http::Connection::new(), None, tcp_handle1),
+        (http::Request::new(), http::Connection::new(), None, tcp_handle2),
+        (http::Request::new(), http::Connection::new(), None, tcp_handle3),
+        (http::Request::new(), http::Connection::new(), None, tcp_handle4),
+        (http::Request::new(), http::Connection::new(), None, tcp_handle5),
+        (http::Request::new(), http::Connection::new(), None, tcp_handle6),
+        (http::Request::new(), http::Connection::new(), None, tcp_handle7),
     ];
+    let mut tpg_session = tpg::Session::new();
+    let mut http_stats = http::Stats::new();
+    let mut auth_throttle = auth::Throttle::new();
 
     board::start_adc();
 
+    let mut console = console::Console::new();
+    console.prompt();
+
     let mut fast_blink_count = if button_pressed { 40 } else { 0 };
     let mut next_blink = 0;
     let mut led_state = true;
     let mut latch_reset_time = None;
+    let mut next_history = 0;
+    let mut self_test = self_test::SelfTest::new();
+    let mut degas = degas::Degas::new();
     loop {
         let time = get_time_ms();
 
-        for &mut(ref mut request, tcp_handle) in sessions.iter_mut() {
+        if time >= next_history {
+            next_history = time + history::INPUT_INTERVAL_MS;
+            let (cathode, electrometer) = cortex_m::interrupt::free(|cs| {
+                (LOOP_CATHODE.borrow(cs).borrow().get_status(),
+                 ELECTROMETER.borrow(cs).borrow().get_status())
+            });
+            let measurement = gauge::measure(&cathode, &electrometer, &config.calibration,
+                                             config.gas_sensitivity);
+            let uncertainty = gauge::pressure_uncertainty(&cathode, &electrometer,
+                                                          &config.calibration,
+                                                          config.gas_sensitivity);
+            let noisy = gauge::is_noisy(uncertainty, config.noise_threshold);
+            cortex_m::interrupt::free(|cs| {
+                HISTORY.borrow(cs).borrow_mut().input(time, measurement, cathode.fbi, noisy);
+            });
+        }
+
+        if let Some(message) = self_test.poll(time, &mut config, &LOOP_ANODE, &LOOP_CATHODE,
+                                              &ELECTROMETER) {
+            log_event(message);
+        }
+        if let Some(message) = degas.poll(time, &config, &LOOP_ANODE, &LOOP_CATHODE,
+                                          &ELECTROMETER) {
+            log_event(message);
+        }
+
+        let leak_test_running = cortex_m::interrupt::free(|cs| {
+            LEAK_TEST.borrow(cs).borrow().is_running()
+        });
+        if leak_test_running {
+            let (cathode, electrometer) = cortex_m::interrupt::free(|cs| {
+                (LOOP_CATHODE.borrow(cs).borrow().get_status(),
+                 ELECTROMETER.borrow(cs).borrow().get_status())
+            });
+            let measurement = gauge::measure(&cathode, &electrometer, &config.calibration,
+                                             config.gas_sensitivity);
+            cortex_m::interrupt::free(|cs| {
+                LEAK_TEST.borrow(cs).borrow_mut().input(time, electrometer.ic_count, measurement);
+            });
+        }
+
+        for &mut(ref mut request, ref mut connection, ref mut reply, tcp_handle)
+                in sessions.iter_mut() {
             let socket = &mut *sockets.get::<TcpSocket>(tcp_handle);
             if !socket.is_open() {
                 socket.listen(80).unwrap()
             }
+            if !socket.is_active() {
+                request.reset();
+                connection.reset(time);
+                *reply = None;
+            }
 
-            if socket.may_recv() {
-                match socket.recv(|data| (data.len(), request.input(data))).unwrap() {
+            // The next request is only read once the previous reply has been sent.
+            if reply.is_none() {
+                if socket.may_recv() && socket.may_send() {
+                    if socket.can_recv() {
+                        connection.activity(time);
+                    }
+                    let result = match socket.recv(|data| request.input(data)) {
+                        Ok(result) => result,
+                        Err(_) => Err("cannot receive from socket")
+                    };
+                    match result {
+                        Ok(true) => {
+                            let keep_alive = connection.keep_alive(request);
+                            http_stats.requests += 1;
+                            match pages::serve(socket, request, keep_alive,
+                                               &mut connection.verified, &mut auth_throttle,
+                                               &mut config,
+                                               &LOOP_ANODE, &LOOP_CATHODE, &ELECTROMETER,
+                                               &HISTORY, &LEAK_TEST, &mut self_test, &mut degas,
+                                               &http_stats, time) {
+                                Ok(transfer) => *reply = Some(transfer),
+                                Err(err) => {
+                                    println!("failed HTTP request: {}", err);
+                                    http_stats.failed_requests += 1;
+                                    socket.close();
+                                }
+                            }
+                            request.reset();
+                            connection.request_served(time);
+                        }
+                        Ok(false) => {
+                            if connection.timed_out(time) {
+                                request.reset();
+                                socket.close();
+                            }
+                        }
+                        Err(err) => {
+                            println!("failed HTTP request: {}", err);
+                            http_stats.requests += 1;
+                            http_stats.failed_requests += 1;
+                            *reply = Some(pages::serve_error(request, err));
+                            request.reset();
+                        }
+                    }
+                } else if socket.may_send() {
+                    request.reset();
+                    socket.close();
+                }
+            }
+
+            let mut finished = false;
+            if let Some(ref mut transfer) = *reply {
+                if socket.can_send() {
+                    connection.activity(time);
+                }
+                match transfer.poll(socket) {
                     Ok(true) => {
-                        if socket.can_send() {
-                            pages::serve(socket, &request, &mut config, &LOOP_ANODE, &LOOP_CATHODE, &ELECTROMETER);
+                        if !transfer.keep_alive() || !socket.may_recv() {
+                            socket.close();
+                        }
+                        finished = true;
+                    }
+                    Ok(false) => {
+                        if connection.timed_out(time) {
+                            println!("HTTP reply timed out");
+                            http_stats.failed_requests += 1;
+                            socket.abort();
+                            finished = true;
                         }
-                        request.reset();
-                        socket.close();
                     }
-                    Ok(false) => (),
                     Err(err) => {
-                        println!("failed HTTP request: {}", err);
-                        request.reset();
+                        // The reply has been cut short, so the connection cannot be reused.
+                        println!("failed HTTP reply: {}", err);
+                        http_stats.failed_requests += 1;
                         socket.close();
+                        finished = true;
                     }
                 }
-            } else if socket.may_send() {
-                request.reset();
+            }
+            if finished {
+                *reply = None;
+            }
+        }
+        {
+            let socket = &mut *sockets.get::<TcpSocket>(tpg_handle);
+            if config.tpg_enabled && !socket.is_open() {
+                tpg_session.reset();
+                socket.listen(tpg::PORT).unwrap()
+            } else if !config.tpg_enabled && socket.is_open() {
+                socket.close();
+            }
+
+            if socket.can_recv() && socket.can_send() {
+                let mut data = [0; 64];
+                let len = socket.recv_slice(&mut data).unwrap();
+                let result = tpg_session.input(&data[..len], socket, &mut config,
+                                               &LOOP_CATHODE, &ELECTROMETER);
+                if result.is_err() {
+                    // A partial reply would desynchronize the client.
+                    println!("failed to send TPG reply, aborting connection");
+                    socket.abort();
+                }
+            } else if socket.may_send() && !socket.may_recv() {
                 socket.close();
             }
         }
@@ -247,6 +481,8 @@ fn main() -> ! {
             Err(e) => println!("poll error: {}", e)
         }
 
+        console.poll(&mut config, &LOOP_ANODE, &LOOP_CATHODE, &ELECTROMETER, &EVENT_LOG, time);
+
         if time > next_blink {
             led_state = !led_state;
             if fast_blink_count > 0 {
@@ -263,6 +499,11 @@ fn main() -> ! {
             match latch_reset_time {
                 None => {
                     println!("Protection latched");
+                    log_event("protection latched");
+                    if degas.is_running() {
+                        degas.abort("protection latched", &config, &LOOP_ANODE, &LOOP_CATHODE);
+                        log_event("degas aborted");
+                    }
                     latch_reset_time = Some(time + 5000);
                 }
                 Some(t) => if time > t {
@@ -276,13 +517,16 @@ fn main() -> ! {
                         board::reset_error();
                     });
                     println!("Protection reset");
+                    log_event("protection reset");
                 }
             }
         }
     }
 }
 
+#[cfg(target_arch = "arm")]
 interrupt!(ADC0SS0, adc0_ss0);
+#[cfg(target_arch = "arm")]
 fn adc0_ss0() {
     cortex_m::interrupt::free(|cs| {
         let adc0 = unsafe { &*tm4c129x::ADC0::ptr() };
@@ -305,6 +549,11 @@ fn adc0_ss0() {
         loop_cathode.adc_input(fbi_sample, fd_sample, fv_sample, fbv_sample);
         electrometer.adc_input(ic_sample);
 
+        let mut entropy = ENTROPY.borrow(cs).borrow_mut();
+        entropy.add(ic_sample as u32 | (fbi_sample as u32) << 16);
+        entropy.add(fv_sample as u32 | (fd_sample as u32) << 16);
+        entropy.add(av_sample as u32 | (fbv_sample as u32) << 16);
+
         let adc_irq_count = ADC_IRQ_COUNT.borrow(cs);
         adc_irq_count.set(adc_irq_count.get() + 1);
     });
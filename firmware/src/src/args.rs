@@ -0,0 +1,476 @@
+//! Decoding of request arguments, from form-urlencoded query strings and
+//! bodies, and from flat JSON objects.
+//!
+//! This module has no dependency on the network stack, so that it can be
+//! tested on the host.
+
+use core::str;
+use core::char;
+
+pub const MAX_ARG_NAME: usize = 32;
+// Long enough for a calibration table.
+pub const MAX_ARG_VALUE: usize = 192;
+
+const ERR_DUPLICATE: &'static str = "argument is given more than once";
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None
+    }
+}
+
+fn push_byte(output: &mut [u8], len: &mut usize, c: u8) -> Result<(), &'static str> {
+    if *len >= output.len() {
+        return Err("value is too long")
+    }
+    output[*len] = c;
+    *len += 1;
+    Ok(())
+}
+
+fn split_args<'a>(args: &'a [u8]) -> impl Iterator<Item=(&'a [u8], &'a [u8])> {
+    args.split(|b| *b == '&' as u8).filter(|arg| !arg.is_empty()).map(|arg| {
+        match arg.iter().position(|b| *b == '=' as u8) {
+            Some(eq) => (&arg[..eq], &arg[eq + 1..]),
+            None => (arg, &arg[arg.len()..])
+        }
+    })
+}
+
+/// Decodes an application/x-www-form-urlencoded name or value into `output`.
+pub fn url_decode<'a>(input: &[u8], output: &'a mut [u8]) -> Result<&'a str, &'static str> {
+    let mut len = 0;
+    let mut i = 0;
+    while i < input.len() {
+        let c = match input[i] {
+            b'+' => {
+                i += 1;
+                b' '
+            }
+            b'%' => {
+                if i + 2 >= input.len() {
+                    return Err("truncated percent-encoding")
+                }
+                match (hex_digit(input[i + 1]), hex_digit(input[i + 2])) {
+                    (Some(high), Some(low)) => {
+                        i += 3;
+                        high << 4 | low
+                    }
+                    _ => return Err("invalid percent-encoding")
+                }
+            }
+            c => {
+                i += 1;
+                c
+            }
+        };
+        push_byte(output, &mut len, c)?;
+    }
+    str::from_utf8(&output[..len]).map_err(|_| "invalid UTF-8 sequence")
+}
+
+/// Reads the four hexadecimal digits of a `\uXXXX` escape starting at `input[i]`.
+fn json_code_unit(input: &[u8], i: usize) -> Result<u32, &'static str> {
+    if i + 6 > input.len() || input[i] != b'\\' || input[i + 1] != b'u' {
+        return Err("invalid escape sequence")
+    }
+    let mut code = 0u32;
+    for &digit in input[i + 2..i + 6].iter() {
+        match hex_digit(digit) {
+            Some(digit) => code = code << 4 | digit as u32,
+            None => return Err("invalid escape sequence")
+        }
+    }
+    Ok(code)
+}
+
+/// Decodes the contents of a JSON string into `output`.
+pub fn json_unescape<'a>(input: &[u8], output: &'a mut [u8]) -> Result<&'a str, &'static str> {
+    let mut len = 0;
+    let mut i = 0;
+    while i < input.len() {
+        if input[i] != b'\\' {
+            push_byte(output, &mut len, input[i])?;
+            i += 1;
+            continue
+        }
+        if i + 1 >= input.len() {
+            return Err("invalid escape sequence")
+        }
+        let c = match input[i + 1] {
+            b'"' => b'"',
+            b'\\' => b'\\',
+            b'/' => b'/',
+            b'b' => 0x08,
+            b'f' => 0x0c,
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'u' => {
+                let mut code = json_code_unit(input, i)?;
+                i += 6;
+                // Characters outside the Basic Multilingual Plane are
+                // escaped as a UTF-16 surrogate pair.
+                if code >= 0xd800 && code < 0xdc00 {
+                    let low = json_code_unit(input, i).map_err(|_| "unpaired surrogate")?;
+                    if low < 0xdc00 || low >= 0xe000 {
+                        return Err("unpaired surrogate")
+                    }
+                    code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                    i += 6;
+                }
+                let c = match char::from_u32(code) {
+                    Some(c) => c,
+                    None => return Err("unpaired surrogate")
+                };
+                let mut encoded = [0; 4];
+                for &byte in c.encode_utf8(&mut encoded).as_bytes() {
+                    push_byte(output, &mut len, byte)?;
+                }
+                continue
+            }
+            _ => return Err("invalid escape sequence")
+        };
+        push_byte(output, &mut len, c)?;
+        i += 2;
+    }
+    str::from_utf8(&output[..len]).map_err(|_| "invalid UTF-8 sequence")
+}
+
+pub fn parse_bool(value: &str) -> Result<bool, &'static str> {
+    match value {
+        "1" | "true" | "on" | "yes" => Ok(true),
+        "0" | "false" | "off" | "no" => Ok(false),
+        _ => Err("invalid boolean, expected true or false")
+    }
+}
+
+pub fn parse_f32(value: &str) -> Result<f32, &'static str> {
+    match value.trim().parse::<f32>() {
+        Ok(value) if value.is_finite() => Ok(value),
+        _ => Err("invalid number")
+    }
+}
+
+pub fn parse_u32(value: &str) -> Result<u32, &'static str> {
+    value.trim().parse().map_err(|_| "invalid integer")
+}
+
+/// Iterates over the members of a flat JSON object, yielding the raw
+/// (unescaped) contents of string values and the text of other values.
+/// Nested objects and arrays are not supported.
+struct JsonFields<'a> {
+    data: &'a [u8],
+    pos: usize,
+    started: bool,
+    done: bool,
+    error: bool
+}
+
+impl<'a> JsonFields<'a> {
+    fn new(data: &'a [u8]) -> JsonFields<'a> {
+        JsonFields { data: data, pos: 0, started: false, done: false, error: false }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.data.len() {
+            match self.data[self.pos] {
+                b' ' | b'\t' | b'\r' | b'\n' => self.pos += 1,
+                _ => break
+            }
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Option<()> {
+        self.skip_whitespace();
+        if self.pos < self.data.len() && self.data[self.pos] == c {
+            self.pos += 1;
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn string(&mut self) -> Option<&'a [u8]> {
+        self.expect(b'"')?;
+        let start = self.pos;
+        while self.pos < self.data.len() {
+            match self.data[self.pos] {
+                b'\\' => self.pos += 2,
+                b'"' => {
+                    self.pos += 1;
+                    return Some(&self.data[start..self.pos - 1])
+                }
+                _ => self.pos += 1
+            }
+        }
+        None
+    }
+
+    fn scalar(&mut self) -> Option<&'a [u8]> {
+        self.skip_whitespace();
+        let start = self.pos;
+        while self.pos < self.data.len() {
+            match self.data[self.pos] {
+                b',' | b'}' | b' ' | b'\t' | b'\r' | b'\n' => break,
+                b'{' | b'[' | b'"' => return None,
+                _ => self.pos += 1
+            }
+        }
+        if self.pos == start { None } else { Some(&self.data[start..self.pos]) }
+    }
+
+    fn member(&mut self) -> Option<Option<(&'a [u8], &'a [u8])>> {
+        if !self.started {
+            self.started = true;
+            self.expect(b'{')?;
+            if self.expect(b'}').is_some() {
+                return Some(None)
+            }
+        } else {
+            if self.expect(b'}').is_some() {
+                return Some(None)
+            }
+            self.expect(b',')?;
+        }
+        let name = self.string()?;
+        self.expect(b':')?;
+        self.skip_whitespace();
+        let value = if self.pos < self.data.len() && self.data[self.pos] == b'"' {
+            self.string()?
+        } else {
+            self.scalar()?
+        };
+        Some(Some((name, value)))
+    }
+}
+
+impl<'a> Iterator for JsonFields<'a> {
+    type Item = (&'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None
+        }
+        match self.member() {
+            Some(Some(member)) => Some(member),
+            Some(None) => {
+                self.done = true;
+                self.skip_whitespace();
+                self.error = self.pos != self.data.len();
+                None
+            }
+            None => {
+                self.done = true;
+                self.error = true;
+                None
+            }
+        }
+    }
+}
+
+/// Checks that `body` is a flat JSON object.
+pub fn check_json(body: &[u8]) -> Result<(), &'static str> {
+    let mut fields = JsonFields::new(body);
+    while fields.next().is_some() {}
+    if fields.error {
+        Err("malformed JSON body")
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Encoding {
+    Url,
+    Json
+}
+
+/// The arguments of a request: its query string, and its body if that is
+/// form-urlencoded or JSON. The body that does not apply is left empty.
+#[derive(Clone, Copy)]
+pub struct Args<'a> {
+    pub query: &'a [u8],
+    pub form: &'a [u8],
+    pub json: &'a [u8]
+}
+
+impl<'a> Args<'a> {
+    /// Looks up an argument. An argument that appears more than once is
+    /// rejected, since there is no way to tell which value was intended.
+    fn find(&self, name: &str) -> Result<Option<(&'a [u8], Encoding)>, &'static str> {
+        let mut found = None;
+        let mut name_buffer = [0; MAX_ARG_NAME];
+
+        for (current_name, current_value) in split_args(self.query).chain(split_args(self.form)) {
+            if url_decode(current_name, &mut name_buffer) == Ok(name) {
+                if found.is_some() {
+                    return Err(ERR_DUPLICATE)
+                }
+                found = Some((current_value, Encoding::Url));
+            }
+        }
+        if !self.json.is_empty() {
+            for (current_name, current_value) in JsonFields::new(self.json) {
+                if json_unescape(current_name, &mut name_buffer) == Ok(name) {
+                    if found.is_some() {
+                        return Err(ERR_DUPLICATE)
+                    }
+                    found = Some((current_value, Encoding::Json));
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Returns the decoded value of an argument, or `None` if it is absent.
+    pub fn get<'b>(&self, name: &str, buffer: &'b mut [u8]) -> Result<Option<&'b str>, &'static str> {
+        match self.find(name)? {
+            None => Ok(None),
+            Some((value, Encoding::Url)) => url_decode(value, buffer).map(Some),
+            Some((value, Encoding::Json)) => json_unescape(value, buffer).map(Some)
+        }
+    }
+
+    /// Returns the value of an argument, decoded into a buffer of
+    /// `MAX_ARG_VALUE` bytes and parsed with `parse`.
+    pub fn get_parsed<T, F>(&self, name: &str, parse: F) -> Result<Option<T>, &'static str>
+            where F: FnOnce(&str) -> Result<T, &'static str> {
+        let mut buffer = [0; MAX_ARG_VALUE];
+        match self.get(name, &mut buffer)? {
+            None => Ok(None),
+            Some(value) => parse(value).map(Some)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::prelude::v1::*;
+    use super::*;
+
+    fn decode(input: &str) -> Result<String, &'static str> {
+        let mut buffer = [0; MAX_ARG_VALUE];
+        url_decode(input.as_bytes(), &mut buffer).map(|value| value.to_string())
+    }
+
+    fn unescape(input: &str) -> Result<String, &'static str> {
+        let mut buffer = [0; MAX_ARG_VALUE];
+        json_unescape(input.as_bytes(), &mut buffer).map(|value| value.to_string())
+    }
+
+    fn form<'a>(query: &'a str, body: &'a str) -> Args<'a> {
+        Args { query: query.as_bytes(), form: body.as_bytes(), json: b"" }
+    }
+
+    fn json<'a>(query: &'a str, body: &'a str) -> Args<'a> {
+        Args { query: query.as_bytes(), form: b"", json: body.as_bytes() }
+    }
+
+    fn get(args: Args, name: &str) -> Result<Option<String>, &'static str> {
+        args.get_parsed(name, |value| Ok(value.to_string()))
+    }
+
+    #[test]
+    fn url_escapes() {
+        assert_eq!(decode("a%20b%2Bc%2fd"), Ok("a b+c/d".to_string()));
+        assert_eq!(decode("a+b+"), Ok("a b ".to_string()));
+        assert_eq!(decode("%C2%B5A"), Ok("\u{b5}A".to_string()));
+        assert_eq!(decode(""), Ok("".to_string()));
+    }
+
+    #[test]
+    fn url_malformed_escapes() {
+        assert_eq!(decode("%zz"), Err("invalid percent-encoding"));
+        assert_eq!(decode("%2"), Err("truncated percent-encoding"));
+        assert_eq!(decode("abc%"), Err("truncated percent-encoding"));
+        assert_eq!(decode("%C2"), Err("invalid UTF-8 sequence"));
+    }
+
+    #[test]
+    fn value_overflow() {
+        let fits = "x".repeat(MAX_ARG_VALUE);
+        let short = &fits[1..];
+        let query = ["a=", &fits, "&b=", &fits, "x&c=", short, "%41"].concat();
+        let args = form(&query, "");
+        assert_eq!(get(args, "a"), Ok(Some(fits.clone())));
+        assert_eq!(get(args, "b"), Err("value is too long"));
+        assert_eq!(get(args, "c"), Ok(Some([short, "A"].concat())));
+
+        let body = [r#"{"a": ""#, short, r#"\n"}"#].concat();
+        assert_eq!(get(json("", &body), "a"), Ok(Some([short, "\n"].concat())));
+        let body = [r#"{"a": ""#, short, r#"\u00b5"}"#].concat();
+        assert_eq!(get(json("", &body), "a"), Err("value is too long"));
+    }
+
+    #[test]
+    fn json_escapes() {
+        assert_eq!(unescape(r#"a\"b\\c\/d"#), Ok("a\"b\\c/d".to_string()));
+        assert_eq!(unescape(r"\b\f\n\r\t"), Ok("\u{8}\u{c}\n\r\t".to_string()));
+        assert_eq!(unescape(r"A\u00b5\u20ac"), Ok("A\u{b5}\u{20ac}".to_string()));
+        assert_eq!(unescape(r"\u004"), Err("invalid escape sequence"));
+        assert_eq!(unescape(r"\u00zz"), Err("invalid escape sequence"));
+        assert_eq!(unescape(r"\x"), Err("invalid escape sequence"));
+        assert_eq!(unescape(r"abc\"), Err("invalid escape sequence"));
+    }
+
+    #[test]
+    fn json_surrogates() {
+        assert_eq!(unescape(r"\ud83d\ude00"), Ok("\u{1f600}".to_string()));
+        assert_eq!(unescape(r"\uD834\uDD1Ex"), Ok("\u{1d11e}x".to_string()));
+        assert_eq!(unescape(r"\ud83d"), Err("unpaired surrogate"));
+        assert_eq!(unescape(r"\ud83dx"), Err("unpaired surrogate"));
+        assert_eq!(unescape(r"\ud83d\u0041"), Err("unpaired surrogate"));
+        assert_eq!(unescape(r"\ude00"), Err("unpaired surrogate"));
+    }
+
+    #[test]
+    fn duplicate_keys() {
+        assert_eq!(get(form("a=1&a=2", ""), "a"), Err(ERR_DUPLICATE));
+        assert_eq!(get(form("a=1", "a=2"), "a"), Err(ERR_DUPLICATE));
+        assert_eq!(get(form("%61=1", "a=2"), "a"), Err(ERR_DUPLICATE));
+        assert_eq!(get(json("", r#"{"a": 1, "a": 2}"#), "a"), Err(ERR_DUPLICATE));
+        assert_eq!(get(json("a=1", r#"{"a": 2}"#), "a"), Err(ERR_DUPLICATE));
+        // Other arguments are unaffected.
+        assert_eq!(get(form("a=1&a=2&b=3", ""), "b"), Ok(Some("3".to_string())));
+    }
+
+    #[test]
+    fn form_and_json_bodies() {
+        let body = "emission=0.5e-3&enabled=on&name=a+b%21&empty=&flag";
+        let args = form("", body);
+        assert_eq!(args.get_parsed("emission", parse_f32), Ok(Some(0.5e-3)));
+        assert_eq!(args.get_parsed("enabled", parse_bool), Ok(Some(true)));
+        assert_eq!(get(args, "name"), Ok(Some("a b!".to_string())));
+        assert_eq!(get(args, "empty"), Ok(Some("".to_string())));
+        assert_eq!(get(args, "flag"), Ok(Some("".to_string())));
+        assert_eq!(get(args, "missing"), Ok(None));
+
+        let body = r#" { "emission": 0.5e-3, "enabled": true, "name": "a b!", "count": "12" } "#;
+        assert_eq!(check_json(body.as_bytes()), Ok(()));
+        let args = json("", body);
+        assert_eq!(args.get_parsed("emission", parse_f32), Ok(Some(0.5e-3)));
+        assert_eq!(args.get_parsed("enabled", parse_bool), Ok(Some(true)));
+        assert_eq!(args.get_parsed("count", parse_u32), Ok(Some(12)));
+        assert_eq!(get(args, "name"), Ok(Some("a b!".to_string())));
+        assert_eq!(get(args, "missing"), Ok(None));
+
+        // A form body is not looked at as JSON, nor the other way round.
+        assert_eq!(get(form("", r#"{"a": 1}"#), "a"), Ok(None));
+        assert_eq!(get(json("", "a=1"), "a"), Ok(None));
+        assert_eq!(get(Args { query: b"", form: b"", json: b"" }, "a"), Ok(None));
+    }
+
+    #[test]
+    fn malformed_json() {
+        assert_eq!(check_json(b"{}"), Ok(()));
+        assert_eq!(check_json(br#"{"a": 1"#), Err("malformed JSON body"));
+        assert_eq!(check_json(br#"{"a": {"b": 1}}"#), Err("malformed JSON body"));
+        assert_eq!(check_json(br#"{"a": [1]}"#), Err("malformed JSON body"));
+        assert_eq!(check_json(br#"{"a": 1} x"#), Err("malformed JSON body"));
+        assert_eq!(check_json(br#"{"a" 1}"#), Err("malformed JSON body"));
+    }
+}
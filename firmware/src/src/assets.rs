@@ -0,0 +1,16 @@
+//! Static web assets, compressed and indexed by `build.rs` from the files
+//! in the `assets` directory.
+
+pub struct Asset {
+    pub path: &'static [u8],
+    pub content_type: &'static str,
+    pub etag: &'static str,
+    /// gzip-compressed contents
+    pub data: &'static [u8]
+}
+
+static ASSETS: &'static [Asset] = include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+pub fn find(path: &[u8]) -> Option<&'static Asset> {
+    ASSETS.iter().find(|asset| asset.path == path)
+}
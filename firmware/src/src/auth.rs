@@ -0,0 +1,270 @@
+use smoltcp::wire::{IpAddress, Ipv4Cidr};
+
+use board;
+use config;
+use http;
+use sha256::Sha256;
+
+pub const USERNAME: &'static str = "admin";
+pub const MAX_ALLOWLIST: usize = 8;
+
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+const HASH_ITERATIONS: usize = 1000;
+const ENTROPY_WORDS: usize = 8;
+const FAILURES_ALLOWED: u32 = 3;
+const BACKOFF_MS: u64 = 1000;
+const BACKOFF_MAX_MS: u64 = 60_000;
+
+#[derive(Clone, Copy)]
+pub struct PasswordHash {
+    pub salt: [u8; SALT_LEN],
+    pub hash: [u8; HASH_LEN]
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Denial {
+    /// Missing or wrong credentials (401)
+    Unauthorized,
+    /// Client address is not in the allowlist (403)
+    Forbidden,
+    /// Too many recent failed attempts, credentials not checked (503)
+    Throttled
+}
+
+fn hash_password(salt: &[u8; SALT_LEN], password: &[u8]) -> [u8; HASH_LEN] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(password);
+    let mut digest = hasher.finalize();
+    for _ in 1..HASH_ITERATIONS {
+        let mut hasher = Sha256::new();
+        hasher.update(salt);
+        hasher.update(&digest);
+        digest = hasher.finalize();
+    }
+    digest
+}
+
+/// Pool of the noise of the ADC samples. The board has no random number
+/// generator, and this noise is its only source of entropy. The ADC averages
+/// 64 conversions per sample, which leaves little noise in the quiet inputs,
+/// so the pool is only good for salts, not for keys.
+#[derive(Clone, Copy)]
+pub struct Entropy {
+    pool: [u32; ENTROPY_WORDS],
+    index: usize
+}
+
+impl Entropy {
+    pub const fn new() -> Entropy {
+        Entropy {
+            pool: [0; ENTROPY_WORDS],
+            index: 0
+        }
+    }
+
+    /// Mixes in a sample. The rotation spreads the noisy low bits of the
+    /// successive samples over each word of the pool.
+    pub fn add(&mut self, sample: u32) {
+        let word = &mut self.pool[self.index];
+        *word = word.rotate_left(7) ^ sample;
+        self.index = (self.index + 1) % ENTROPY_WORDS;
+    }
+}
+
+impl PasswordHash {
+    /// Hashes a new password, with a salt derived from the ADC noise gathered
+    /// since boot, the MAC address and the time since boot.
+    pub fn new(password: &[u8], time: u64, entropy: &Entropy) -> PasswordHash {
+        let mut hasher = Sha256::new();
+        for word in entropy.pool.iter() {
+            hasher.update(&[*word as u8, (*word >> 8) as u8, (*word >> 16) as u8,
+                            (*word >> 24) as u8]);
+        }
+        hasher.update(&board::get_mac_address());
+        for i in 0..8 {
+            hasher.update(&[(time >> (8*i)) as u8]);
+        }
+        let digest = hasher.finalize();
+        let mut salt = [0; SALT_LEN];
+        salt.copy_from_slice(&digest[..SALT_LEN]);
+        PasswordHash {
+            salt: salt,
+            hash: hash_password(&salt, password)
+        }
+    }
+
+    pub fn verify(&self, password: &[u8]) -> bool {
+        equal(&hash_password(&self.salt, password), &self.hash)
+    }
+}
+
+/// Constant-time comparison of two hashes.
+fn equal(a: &[u8; HASH_LEN], b: &[u8; HASH_LEN]) -> bool {
+    let mut difference = 0;
+    for i in 0..HASH_LEN {
+        difference |= a[i] ^ b[i];
+    }
+    difference == 0
+}
+
+/// Credentials already verified on a connection. Hashing the password takes
+/// a noticeable time, so a later request on the same connection that presents
+/// the same Authorization header is checked with a single round of SHA-256.
+pub struct Verified {
+    digest: Option<[u8; HASH_LEN]>
+}
+
+impl Verified {
+    pub fn new() -> Verified {
+        Verified { digest: None }
+    }
+
+    pub fn reset(&mut self) {
+        self.digest = None
+    }
+}
+
+/// Failed password attempts across all connections. Each attempt costs the
+/// main loop a thousand rounds of SHA-256, so after a few failures further
+/// attempts are refused without hashing until a back-off, which doubles with
+/// every failure, has expired. Credentials already verified on a connection
+/// are still accepted during the back-off.
+pub struct Throttle {
+    failures: u32,
+    until: u64
+}
+
+impl Throttle {
+    pub fn new() -> Throttle {
+        Throttle { failures: 0, until: 0 }
+    }
+
+    fn blocked(&self, time: u64) -> bool {
+        time < self.until
+    }
+
+    fn fail(&mut self, time: u64) {
+        self.failures = self.failures.saturating_add(1);
+        if self.failures >= FAILURES_ALLOWED {
+            let shift = (self.failures - FAILURES_ALLOWED).min(16);
+            self.until = time + (BACKOFF_MS << shift).min(BACKOFF_MAX_MS);
+        }
+    }
+
+    fn succeed(&mut self) {
+        self.failures = 0;
+        self.until = 0;
+    }
+}
+
+/// Binds an Authorization header to the stored password, so that the cached
+/// credentials no longer match once the password is changed.
+fn authorization_digest(password_hash: &PasswordHash, authorization: &[u8]) -> [u8; HASH_LEN] {
+    let mut hasher = Sha256::new();
+    hasher.update(&password_hash.salt);
+    hasher.update(&password_hash.hash);
+    hasher.update(authorization);
+    hasher.finalize()
+}
+
+fn base64_value(c: u8) -> Option<u8> {
+    match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None
+    }
+}
+
+fn base64_decode<'a>(input: &[u8], output: &'a mut [u8]) -> Result<&'a [u8], ()> {
+    let mut len = 0;
+    let mut accumulator = 0u32;
+    let mut bits = 0;
+    for &c in input.iter() {
+        if c == b'=' {
+            break
+        }
+        accumulator = accumulator << 6 | base64_value(c).ok_or(())? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            if len >= output.len() {
+                return Err(())
+            }
+            output[len] = (accumulator >> bits) as u8;
+            len += 1;
+        }
+    }
+    Ok(&output[..len])
+}
+
+fn check_credentials(password_hash: &PasswordHash, request: &http::Request,
+                     verified: &mut Verified, throttle: &mut Throttle,
+                     time: u64) -> Result<(), Denial> {
+    let authorization = match request.get_authorization() {
+        Some(authorization) => authorization,
+        None => return Err(Denial::Unauthorized)
+    };
+    let digest = authorization_digest(password_hash, authorization);
+    if let Some(ref verified_digest) = verified.digest {
+        if equal(&digest, verified_digest) {
+            return Ok(())
+        }
+    }
+    if authorization.len() < 6 || !authorization[..6].eq_ignore_ascii_case(b"Basic ") {
+        return Err(Denial::Unauthorized)
+    }
+    let mut buffer = [0; 96];
+    let credentials = match base64_decode(&authorization[6..], &mut buffer) {
+        Ok(credentials) => credentials,
+        Err(()) => return Err(Denial::Unauthorized)
+    };
+    let colon = match credentials.iter().position(|b| *b == b':') {
+        Some(colon) => colon,
+        None => return Err(Denial::Unauthorized)
+    };
+    let (username, password) = (&credentials[..colon], &credentials[colon + 1..]);
+    if throttle.blocked(time) {
+        return Err(Denial::Throttled)
+    }
+    // Always hash, so that the response time does not reveal the username.
+    let password_ok = password_hash.verify(password);
+    if username == USERNAME.as_bytes() && password_ok {
+        verified.digest = Some(digest);
+        throttle.succeed();
+        Ok(())
+    } else {
+        throttle.fail(time);
+        Err(Denial::Unauthorized)
+    }
+}
+
+pub fn allowed(config: &config::Config, remote: Option<IpAddress>) -> bool {
+    let mut allowlist = config.allowlist.iter().filter_map(|cidr| *cidr).peekable();
+    if allowlist.peek().is_none() {
+        return true
+    }
+    match remote {
+        Some(IpAddress::Ipv4(address)) =>
+            allowlist.any(|cidr: Ipv4Cidr| cidr.contains_addr(&address)),
+        _ => false
+    }
+}
+
+/// Checks whether a request may access settings and controls.
+pub fn check(config: &config::Config, request: &http::Request,
+             remote: Option<IpAddress>, verified: &mut Verified,
+             throttle: &mut Throttle, time: u64) -> Result<(), Denial> {
+    if !allowed(config, remote) {
+        return Err(Denial::Forbidden)
+    }
+    match config.password {
+        None => Ok(()),
+        Some(ref password_hash) =>
+            check_credentials(password_hash, request, verified, throttle, time)
+    }
+}
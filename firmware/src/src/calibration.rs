@@ -0,0 +1,246 @@
+//! Pressure calibration curve, which converts the ratio of the ion current
+//! to the emission current into a pressure.
+//!
+//! The response of the gauge is linear at low pressures but falls off above
+//! about 1e-4 mbar. The curve can be measured against a reference gauge and
+//! entered either as a table of points, interpolated in log-log space, or as
+//! a polynomial in log-log space.
+//!
+//! The curve is for nitrogen. Other gases ionize more or less easily, which
+//! is corrected by dividing the ratio by the sensitivity to the gas relative
+//! to nitrogen before applying the curve.
+
+use core::fmt;
+use core::f32;
+use libm::F32Ext;
+
+/// Nominal sensitivity of the linear response, in 1/mbar.
+pub const SENSITIVITY: f32 = 18.75154;
+
+pub const POINTS_MAX: usize = 7;
+pub const COEFFICIENTS_MAX: usize = 4;
+
+// Range of the sensitivity relative to nitrogen: from about 0.18 for helium
+// to about 10 for heavy organic vapours.
+const GAS_SENSITIVITY_MIN: f32 = 0.05;
+const GAS_SENSITIVITY_MAX: f32 = 20.0;
+
+// Range of log10(Ic/Ie) over which a polynomial must be increasing: from the
+// smallest ion current at the largest emission current, to the largest ion
+// current at the smallest emission current.
+const LOG_RATIO_MIN: f32 = -12.0;
+const LOG_RATIO_MAX: f32 = 3.0;
+
+/// Calibration point: ratio of the ion current to the emission current, and
+/// pressure in mbar.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Point {
+    pub ratio: f32,
+    pub pressure: f32
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Calibration {
+    /// Linear response with the nominal sensitivity.
+    Linear,
+    /// Points sorted by ratio. Below the first point, the response is linear
+    /// through it; above the last one, the last segment is extended.
+    Table {
+        points: [Point; POINTS_MAX],
+        len: usize
+    },
+    /// Coefficients of log10(P), from the constant term up, as a polynomial
+    /// of log10(Ic/Ie).
+    Polynomial([f32; COEFFICIENTS_MAX])
+}
+
+impl Calibration {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Calibration::Linear => "linear",
+            Calibration::Table { .. } => "table",
+            Calibration::Polynomial(_) => "polynomial"
+        }
+    }
+
+    /// Checks that the curve is usable, and in particular that the pressure
+    /// increases with the ratio, so that the measurement limits are ordered.
+    pub fn check(&self) -> Result<Calibration, &'static str> {
+        match *self {
+            Calibration::Linear => (),
+            Calibration::Table { ref points, len } => {
+                if len == 0 || len > POINTS_MAX {
+                    return Err("expected 1 to 7 calibration points")
+                }
+                for point in points[..len].iter() {
+                    if !(point.ratio > 0.0 && point.ratio.is_finite() &&
+                         point.pressure > 0.0 && point.pressure.is_finite()) {
+                        return Err("calibration values must be positive")
+                    }
+                }
+                for pair in points[..len].windows(2) {
+                    if pair[1].ratio <= pair[0].ratio || pair[1].pressure <= pair[0].pressure {
+                        return Err("calibration points must increase")
+                    }
+                }
+            }
+            Calibration::Polynomial(ref coefficients) => {
+                if !coefficients.iter().all(|&c| c.is_finite()) {
+                    return Err("invalid calibration coefficient")
+                }
+                let mut x = LOG_RATIO_MIN;
+                while x <= LOG_RATIO_MAX {
+                    if self.polynomial_slope(x) <= 0.0 {
+                        return Err("calibration polynomial must increase")
+                    }
+                    x += 0.25;
+                }
+            }
+        }
+        Ok(*self)
+    }
+
+    fn polynomial_slope(&self, x: f32) -> f32 {
+        match *self {
+            Calibration::Polynomial(ref coefficients) => {
+                let mut slope = 0.0;
+                for (k, &c) in coefficients.iter().enumerate().skip(1).rev() {
+                    slope = slope*x + (k as f32)*c;
+                }
+                slope
+            }
+            _ => 1.0
+        }
+    }
+
+    /// Returns the table segment to use for `ratio`, or None below the first
+    /// point.
+    fn segment(points: &[Point], ratio: f32) -> Option<(Point, Point)> {
+        if points.len() < 2 || ratio <= points[0].ratio {
+            return None
+        }
+        let mut i = 0;
+        while i + 2 < points.len() && ratio > points[i + 1].ratio {
+            i += 1;
+        }
+        Some((points[i], points[i + 1]))
+    }
+
+    fn segment_slope(p0: Point, p1: Point) -> f32 {
+        (p1.pressure/p0.pressure).ln()/(p1.ratio/p0.ratio).ln()
+    }
+
+    /// Converts a positive ratio of the ion current to the emission current
+    /// into a pressure, in mbar.
+    pub fn pressure(&self, ratio: f32) -> f32 {
+        match *self {
+            Calibration::Linear => ratio/SENSITIVITY,
+            Calibration::Table { ref points, len } => {
+                match Calibration::segment(&points[..len], ratio) {
+                    None => points[0].pressure*ratio/points[0].ratio,
+                    Some((p0, p1)) => {
+                        let slope = Calibration::segment_slope(p0, p1);
+                        p0.pressure*(slope*(ratio/p0.ratio).ln()).exp()
+                    }
+                }
+            }
+            Calibration::Polynomial(ref coefficients) => {
+                let x = ratio.log10();
+                let mut y = 0.0;
+                for &c in coefficients.iter().rev() {
+                    y = y*x + c;
+                }
+                (y*f32::consts::LN_10).exp()
+            }
+        }
+    }
+
+    /// Returns the logarithmic slope of the curve, d(ln P)/d(ln ratio), which
+    /// converts a relative uncertainty on the ratio into one on the pressure.
+    pub fn log_slope(&self, ratio: f32) -> f32 {
+        match *self {
+            Calibration::Linear => 1.0,
+            Calibration::Table { ref points, len } => {
+                match Calibration::segment(&points[..len], ratio) {
+                    None => 1.0,
+                    Some((p0, p1)) => Calibration::segment_slope(p0, p1)
+                }
+            }
+            Calibration::Polynomial(_) => self.polynomial_slope(ratio.log10())
+        }
+    }
+
+    /// Writes the points or coefficients, in the format accepted by
+    /// `parse_points` or `parse_coefficients`.
+    pub fn values(&self) -> ValuesFmt {
+        ValuesFmt(*self)
+    }
+}
+
+/// Checks the sensitivity of the gauge to the measured gas, relative to
+/// nitrogen.
+pub fn check_gas_sensitivity(relative: f32) -> Result<f32, &'static str> {
+    if relative >= GAS_SENSITIVITY_MIN && relative <= GAS_SENSITIVITY_MAX {
+        Ok(relative)
+    } else {
+        Err("gas sensitivity out of range")
+    }
+}
+
+/// Parses a table as comma-separated `<ratio>:<pressure>` points.
+pub fn parse_points(s: &str) -> Result<Calibration, &'static str> {
+    let mut points = [Point { ratio: 0.0, pressure: 0.0 }; POINTS_MAX];
+    let mut len = 0;
+    for point in s.split(',') {
+        if len == POINTS_MAX {
+            return Err("too many calibration points")
+        }
+        let mut values = point.split(':');
+        let (ratio, pressure) = match (values.next(), values.next(), values.next()) {
+            (Some(ratio), Some(pressure), None) => (ratio.trim(), pressure.trim()),
+            _ => return Err("expected <ratio>:<pressure>")
+        };
+        points[len] = Point {
+            ratio: ratio.parse().map_err(|_| "invalid number")?,
+            pressure: pressure.parse().map_err(|_| "invalid number")?
+        };
+        len += 1;
+    }
+    Calibration::Table { points: points, len: len }.check()
+}
+
+/// Parses up to four comma-separated polynomial coefficients, from the
+/// constant term up.
+pub fn parse_coefficients(s: &str) -> Result<Calibration, &'static str> {
+    let mut coefficients = [0.0; COEFFICIENTS_MAX];
+    for (i, coefficient) in s.split(',').enumerate() {
+        if i == COEFFICIENTS_MAX {
+            return Err("too many calibration coefficients")
+        }
+        coefficients[i] = coefficient.trim().parse().map_err(|_| "invalid number")?;
+    }
+    Calibration::Polynomial(coefficients).check()
+}
+
+pub struct ValuesFmt(Calibration);
+
+impl fmt::Display for ValuesFmt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Calibration::Linear => Ok(()),
+            Calibration::Table { ref points, len } => {
+                for (i, point) in points[..len].iter().enumerate() {
+                    write!(f, "{}{:e}:{:e}", if i == 0 { "" } else { "," },
+                           point.ratio, point.pressure)?;
+                }
+                Ok(())
+            }
+            Calibration::Polynomial(ref coefficients) => {
+                for (i, coefficient) in coefficients.iter().enumerate() {
+                    write!(f, "{}{:e}", if i == 0 { "" } else { "," }, coefficient)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! Pressure trend chart, rendered as inline SVG from the history.
+//!
+//! Consecutive samples are merged so that the chart has at most
+//! `MAX_COLUMNS` columns, which keeps it small whatever the span.
+
+use core::fmt;
+use core::cell::RefCell;
+use cortex_m;
+use cortex_m::interrupt::Mutex;
+use libm::F32Ext;
+
+use history;
+
+/// Selectable spans, in seconds.
+pub const SPANS: [u64; 3] = [600, 3600, 86400];
+
+const MAX_COLUMNS: u64 = 360;
+
+const WIDTH: f32 = 720.0;
+const HEIGHT: f32 = 300.0;
+const LEFT: f32 = 50.0;
+const RIGHT: f32 = 10.0;
+const TOP: f32 = 10.0;
+const BOTTOM: f32 = 25.0;
+
+#[derive(Clone, Copy)]
+struct Column {
+    min: f32,
+    mean: f32,
+    max: f32
+}
+
+pub struct Chart {
+    history_m: &'static Mutex<RefCell<history::History>>,
+    span: u64,
+    tier: usize,
+    step: u64,
+    columns: u64,
+    end: u64
+}
+
+impl Chart {
+    /// Shows the last `span` seconds of the history, with the finest tier
+    /// that covers them.
+    pub fn new(history_m: &'static Mutex<RefCell<history::History>>, span: u64) -> Chart {
+        let tier = (0..history::TIERS.len())
+            .find(|&tier| {
+                let tier = &history::TIERS[tier];
+                tier.len as u64*tier.resolution >= span
+            })
+            .unwrap_or(history::TIERS.len() - 1);
+        let periods = span/history::TIERS[tier].resolution;
+        let step = (periods + MAX_COLUMNS - 1)/MAX_COLUMNS;
+        let end = cortex_m::interrupt::free(|cs| {
+            history_m.borrow(cs).borrow().periods(tier).1
+        });
+        Chart {
+            history_m: history_m,
+            span: span,
+            tier: tier,
+            step: step,
+            columns: periods/step,
+            end: end
+        }
+    }
+
+    fn column(&self, column: u64) -> Option<Column> {
+        let mut result: Option<Column> = None;
+        let mut sum = 0.0;
+        let mut count = 0;
+        let first = (self.end + column*self.step) as i64 - (self.columns*self.step) as i64;
+        for period in first..first + self.step as i64 {
+            if period < 0 {
+                continue
+            }
+            let sample = cortex_m::interrupt::free(|cs| {
+                self.history_m.borrow(cs).borrow().get(self.tier, period as u64)
+            });
+            let (min, mean, max) = match sample {
+                Some(sample) => match (sample.min(), sample.mean(), sample.max()) {
+                    (Some(min), Some(mean), Some(max)) if min > 0.0 => (min, mean, max),
+                    _ => continue
+                },
+                None => continue
+            };
+            sum += mean;
+            count += 1;
+            result = Some(match result {
+                None => Column { min: min, mean: mean, max: max },
+                Some(column) => Column {
+                    min: if min < column.min { min } else { column.min },
+                    mean: 0.0,
+                    max: if max > column.max { max } else { column.max }
+                }
+            });
+        }
+        result.map(|column| Column { mean: sum/(count as f32), ..column })
+    }
+
+    fn x(&self, column: u64) -> f32 {
+        LEFT + (column as f32 + 0.5)*(WIDTH - LEFT - RIGHT)/(self.columns as f32)
+    }
+}
+
+impl fmt::Display for Chart {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Scale the pressure axis to whole decades around the data.
+        let mut range: Option<(f32, f32)> = None;
+        for column in 0..self.columns {
+            if let Some(column) = self.column(column) {
+                range = Some(match range {
+                    None => (column.min, column.max),
+                    Some((min, max)) => (if column.min < min { column.min } else { min },
+                                         if column.max > max { column.max } else { max })
+                });
+            }
+        }
+        let (low, high) = match range {
+            Some((min, max)) => {
+                let low = min.log10().floor();
+                let high = max.log10().ceil();
+                (low, if high > low { high } else { low + 1.0 })
+            }
+            None => (-10.0, -2.0)
+        };
+        let y = |pressure: f32| {
+            TOP + (high - pressure.log10())*(HEIGHT - TOP - BOTTOM)/(high - low)
+        };
+
+        write!(f, "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {} {}\" \
+                   style=\"width:100%;max-width:{}px\" font-family=\"sans-serif\" font-size=\"11\">",
+               WIDTH, HEIGHT, WIDTH)?;
+
+        // Decade grid
+        let mut decade = low;
+        while decade <= high {
+            let grid_y = TOP + (high - decade)*(HEIGHT - TOP - BOTTOM)/(high - low);
+            write!(f, "<line x1=\"{}\" y1=\"{:.1}\" x2=\"{}\" y2=\"{:.1}\" stroke=\"#ddd\"/>\
+                       <text x=\"{}\" y=\"{:.1}\" text-anchor=\"end\">1e{}</text>",
+                   LEFT, grid_y, WIDTH - RIGHT, grid_y, LEFT - 4.0, grid_y + 4.0, decade as i32)?;
+            decade += 1.0;
+        }
+
+        // Time axis, in minutes or hours before now
+        let (unit, unit_name) = if self.span >= 7200 { (3600.0, " h") } else { (60.0, " min") };
+        for tick in 0..5 {
+            let x = LEFT + (tick as f32)*(WIDTH - LEFT - RIGHT)/4.0;
+            let ago = (self.span as f32)*((4 - tick) as f32)/4.0/unit;
+            write!(f, "<line x1=\"{:.1}\" y1=\"{}\" x2=\"{:.1}\" y2=\"{}\" stroke=\"#ddd\"/>",
+                   x, TOP, x, HEIGHT - BOTTOM)?;
+            if tick == 4 {
+                write!(f, "<text x=\"{:.1}\" y=\"{}\" text-anchor=\"end\">now</text>",
+                       x, HEIGHT - 8.0)?;
+            } else {
+                write!(f, "<text x=\"{:.1}\" y=\"{}\" text-anchor=\"middle\">-{}{}</text>",
+                       x, HEIGHT - 8.0, ago, unit_name)?;
+            }
+        }
+        write!(f, "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"none\" stroke=\"#888\"/>",
+               LEFT, TOP, WIDTH - LEFT - RIGHT, HEIGHT - TOP - BOTTOM)?;
+
+        if range.is_none() {
+            return write!(f, "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\">No data</text></svg>",
+                          (WIDTH + LEFT - RIGHT)/2.0, (HEIGHT + TOP - BOTTOM)/2.0)
+        }
+
+        // Spread of the readings within each column
+        write!(f, "<path stroke=\"#9cd\" fill=\"none\" d=\"")?;
+        for column in 0..self.columns {
+            if let Some(values) = self.column(column) {
+                write!(f, "M{:.1} {:.1}V{:.1}", self.x(column), y(values.max), y(values.min))?;
+            }
+        }
+        write!(f, "\"/>")?;
+
+        // Mean pressure, interrupted where there are no valid readings
+        write!(f, "<path stroke=\"#009688\" stroke-width=\"1.5\" fill=\"none\" d=\"")?;
+        let mut drawing = false;
+        for column in 0..self.columns {
+            match self.column(column) {
+                Some(values) => {
+                    write!(f, "{}{:.1} {:.1}", if drawing { "L" } else { "M" },
+                           self.x(column), y(values.mean))?;
+                    drawing = true;
+                }
+                None => drawing = false
+            }
+        }
+        write!(f, "\"/></svg>")
+    }
+}
@@ -0,0 +1,252 @@
+//! Degassing of Bayard-Alpert gauges by electron bombardment of the grid.
+//!
+//! The emission current and anode voltage are raised above their measurement
+//! settings for a few minutes, which heats the grid and releases the gas it
+//! has adsorbed. Both are ramped up together from the measurement settings,
+//! held for the configured duration, and the measurement settings are then
+//! restored.
+
+use core::cell::RefCell;
+use cortex_m;
+use cortex_m::interrupt::Mutex;
+
+use config;
+use gauge;
+use loop_anode;
+use loop_cathode;
+use electrometer;
+
+pub const DURATION_MIN: f32 = 10.0;
+pub const DURATION_MAX: f32 = 1800.0;
+pub const RAMP_MAX: f32 = 600.0;
+pub const PRESSURE_MAX: f32 = 1.0e-4;
+// Time without a pressure reading after which degassing is aborted.
+const READING_TIMEOUT_MS: u64 = 5000;
+
+pub fn check_duration(seconds: f32) -> Result<f32, &'static str> {
+    if seconds >= DURATION_MIN && seconds <= DURATION_MAX {
+        Ok(seconds)
+    } else {
+        Err("degas duration out of range")
+    }
+}
+
+pub fn check_ramp(seconds: f32) -> Result<f32, &'static str> {
+    if seconds >= 0.0 && seconds <= RAMP_MAX {
+        Ok(seconds)
+    } else {
+        Err("degas ramp time out of range")
+    }
+}
+
+/// Checks the pressure threshold, in mbar, above which degassing is not
+/// allowed.
+pub fn check_pressure(mbar: f32) -> Result<f32, &'static str> {
+    if mbar > 0.0 && mbar <= PRESSURE_MAX {
+        Ok(mbar)
+    } else {
+        Err("degas pressure threshold out of range")
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Idle,
+    Ramp,
+    Hold,
+    Finished,
+    Aborted(&'static str)
+}
+
+impl State {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            State::Idle => "idle",
+            State::Ramp => "ramp",
+            State::Hold => "hold",
+            State::Finished => "finished",
+            State::Aborted(_) => "aborted"
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct DegasStatus {
+    pub state: State,
+    /// Emission current and anode voltage targets of the running degas.
+    pub emission: f32,
+    pub anode: f32,
+    /// Times in seconds, covering the ramp and the hold.
+    pub elapsed: f32,
+    pub remaining: f32
+}
+
+pub struct Degas {
+    state: State,
+    start: u64,
+    ramp_ms: u64,
+    end_ms: u64,
+    from_emission: f32,
+    from_anode: f32,
+    emission: f32,
+    anode: f32,
+    pressure_max: f32,
+    elapsed_ms: u64,
+    last_reading_ms: u64
+}
+
+/// Returns whether the pressure is low enough to degas, or None without a
+/// reading. Unlike a measurement, the pressure is computed whenever both
+/// currents are available, even while the emission is not regulated, since
+/// it changes throughout the ramp. Readings below the measurement range are
+/// low enough, and readings above it are not.
+fn pressure_allowed(cathode: &loop_cathode::ControllerStatus,
+                    electrometer: &electrometer::ElectrometerStatus,
+                    config: &config::Config, pressure_max: f32) -> Option<bool> {
+    let (fbi, ic) = match (cathode.fbi, electrometer.ic) {
+        (Some(fbi), Some(ic)) if fbi > 0.0 => (fbi, ic),
+        _ => return None
+    };
+    if electrometer.over_range {
+        Some(false)
+    } else if ic < gauge::IC_MIN {
+        Some(true)
+    } else {
+        Some(config.calibration.pressure(ic/fbi/config.gas_sensitivity) <= pressure_max)
+    }
+}
+
+impl Degas {
+    pub fn new() -> Degas {
+        Degas {
+            state: State::Idle,
+            start: 0,
+            ramp_ms: 0,
+            end_ms: 0,
+            from_emission: 0.0,
+            from_anode: 0.0,
+            emission: 0.0,
+            anode: 0.0,
+            pressure_max: 0.0,
+            elapsed_ms: 0,
+            last_reading_ms: 0
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        match self.state {
+            State::Ramp | State::Hold => true,
+            _ => false
+        }
+    }
+
+    pub fn get_status(&self) -> DegasStatus {
+        DegasStatus {
+            state: self.state,
+            emission: self.emission,
+            anode: self.anode,
+            elapsed: self.elapsed_ms as f32/1000.0,
+            remaining: (self.end_ms - self.elapsed_ms) as f32/1000.0
+        }
+    }
+
+    /// Starts degassing with the configured settings, if the outputs are
+    /// enabled and the pressure is below the configured threshold.
+    pub fn start(&mut self, time: u64, config: &config::Config,
+                 loop_cathode_m: &Mutex<RefCell<loop_cathode::Controller>>,
+                 electrometer_m: &Mutex<RefCell<electrometer::Electrometer>>) -> Result<(), &'static str> {
+        if self.is_running() {
+            return Err("degas already running")
+        }
+        if !config.output_enabled {
+            return Err("outputs disabled")
+        }
+        let (cathode, electrometer) = cortex_m::interrupt::free(|cs| {
+            (loop_cathode_m.borrow(cs).borrow().get_status(),
+             electrometer_m.borrow(cs).borrow().get_status())
+        });
+        match pressure_allowed(&cathode, &electrometer, config, config.degas_pressure) {
+            Some(true) => (),
+            Some(false) => return Err("pressure above degas threshold"),
+            None => return Err("no pressure reading")
+        }
+
+        self.state = State::Ramp;
+        self.start = time;
+        self.ramp_ms = (config.degas_ramp*1000.0) as u64;
+        self.end_ms = self.ramp_ms + (config.degas_duration*1000.0) as u64;
+        self.from_emission = config.emission;
+        self.from_anode = config.anode;
+        self.emission = config.degas_emission;
+        self.anode = config.degas_anode;
+        self.pressure_max = config.degas_pressure;
+        self.elapsed_ms = 0;
+        self.last_reading_ms = time;
+        Ok(())
+    }
+
+    /// Aborts a running degas, and restores the measurement settings.
+    pub fn abort(&mut self, reason: &'static str, config: &config::Config,
+                 loop_anode_m: &Mutex<RefCell<loop_anode::Controller>>,
+                 loop_cathode_m: &Mutex<RefCell<loop_cathode::Controller>>) {
+        if self.is_running() {
+            self.state = State::Aborted(reason);
+            gauge::apply_config(config, loop_anode_m, loop_cathode_m);
+        }
+    }
+
+    /// Advances a running degas. Returns a message to log when it ends.
+    pub fn poll(&mut self, time: u64, config: &config::Config,
+                loop_anode_m: &Mutex<RefCell<loop_anode::Controller>>,
+                loop_cathode_m: &Mutex<RefCell<loop_cathode::Controller>>,
+                electrometer_m: &Mutex<RefCell<electrometer::Electrometer>>) -> Option<&'static str> {
+        if !self.is_running() {
+            return None
+        }
+        let (anode, cathode, electrometer) = cortex_m::interrupt::free(|cs| {
+            (loop_anode_m.borrow(cs).borrow().get_status(),
+             loop_cathode_m.borrow(cs).borrow().get_status(),
+             electrometer_m.borrow(cs).borrow().get_status())
+        });
+
+        let allowed = pressure_allowed(&cathode, &electrometer, config, self.pressure_max);
+        if allowed.is_some() {
+            self.last_reading_ms = time;
+        }
+        let reason = if !anode.enabled || !cathode.enabled {
+            Some("outputs switched off")
+        } else if allowed == Some(false) {
+            Some("pressure above degas threshold")
+        } else if time - self.last_reading_ms >= READING_TIMEOUT_MS {
+            Some("no pressure reading")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            self.abort(reason, config, loop_anode_m, loop_cathode_m);
+            return Some("degas aborted")
+        }
+
+        self.elapsed_ms = time - self.start;
+        if self.elapsed_ms >= self.end_ms {
+            self.elapsed_ms = self.end_ms;
+            self.state = State::Finished;
+            gauge::apply_config(config, loop_anode_m, loop_cathode_m);
+            return Some("degas finished")
+        }
+
+        let fraction = if self.elapsed_ms < self.ramp_ms {
+            self.elapsed_ms as f32/self.ramp_ms as f32
+        } else {
+            self.state = State::Hold;
+            1.0
+        };
+        cortex_m::interrupt::free(|cs| {
+            loop_anode_m.borrow(cs).borrow_mut().set_target(
+                self.from_anode + (self.anode - self.from_anode)*fraction);
+            loop_cathode_m.borrow(cs).borrow_mut().set_emission_target(
+                self.from_emission + (self.emission - self.from_emission)*fraction);
+        });
+        None
+    }
+}
@@ -0,0 +1,268 @@
+//! Averaging of ADC samples for the reported measurements.
+//!
+//! Samples are first averaged into 1/32 of the window, so that long windows
+//! can be implemented with a small buffer and without losing precision.
+//! The spread of the samples is tracked alongside, to estimate the noise
+//! of each reading.
+
+use core::f32::consts::PI;
+use core::str::FromStr;
+use libm::F32Ext;
+
+use board;
+
+const SEGMENTS: usize = 32;
+
+pub const AVERAGING_TIME_MIN: f32 = 1.0e-3;
+pub const AVERAGING_TIME_MAX: f32 = 30.0;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Mean of consecutive, non-overlapping windows
+    Block,
+    /// Mean of the last window, updated every 1/32 of the window
+    Moving,
+    /// First-order low-pass filter, whose time constant is the window,
+    /// updated every 1/32 of the window
+    Exponential
+}
+
+impl Kind {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Kind::Block => "block",
+            Kind::Moving => "moving",
+            Kind::Exponential => "exponential"
+        }
+    }
+}
+
+impl FromStr for Kind {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Kind, &'static str> {
+        match s {
+            "block" => Ok(Kind::Block),
+            "moving" => Ok(Kind::Moving),
+            "exponential" => Ok(Kind::Exponential),
+            _ => Err("expected \"block\", \"moving\" or \"exponential\"")
+        }
+    }
+}
+
+pub fn check_averaging_time(seconds: f32) -> Result<f32, &'static str> {
+    if seconds >= AVERAGING_TIME_MIN && seconds <= AVERAGING_TIME_MAX {
+        Ok(seconds)
+    } else {
+        Err("averaging time out of range")
+    }
+}
+
+/// Noise of a reading, over the last averaging window.
+#[derive(Clone, Copy)]
+pub struct Statistics {
+    /// Standard deviation of the individual samples.
+    pub std_dev: f32,
+    /// Standard error of the reading, i.e. the expected standard deviation
+    /// of the filter output.
+    pub std_error: f32
+}
+
+pub struct Filter {
+    kind: Kind,
+    segment_len: u32,
+    segment_mean: f32,
+    segment_m2: f32,
+    segment_count: u32,
+    segments: [f32; SEGMENTS],
+    segments_m2: [f32; SEGMENTS],
+    segments_len: usize,
+    segment_index: usize,
+    segments_filled: usize,
+    exponential: f32,
+    output: Option<f32>,
+    output_count: u32,
+    statistics: Option<Statistics>
+}
+
+impl Filter {
+    /// Creates a filter that averages blocks of 512 samples.
+    pub const fn new() -> Filter {
+        Filter {
+            kind: Kind::Block,
+            segment_len: 16,
+            segment_mean: 0.0,
+            segment_m2: 0.0,
+            segment_count: 0,
+            segments: [0.0; SEGMENTS],
+            segments_m2: [0.0; SEGMENTS],
+            segments_len: SEGMENTS,
+            segment_index: 0,
+            segments_filled: 0,
+            exponential: 0.0,
+            output: None,
+            output_count: 0,
+            statistics: None
+        }
+    }
+
+    /// Changes the filter type and the averaging time, and restarts the filter.
+    pub fn configure(&mut self, kind: Kind, seconds: f32) {
+        let length = (seconds*board::ADC_SAMPLE_RATE).round().max(1.0) as u32;
+        self.kind = kind;
+        self.segments_len = if (length as usize) < SEGMENTS { length as usize } else { SEGMENTS };
+        self.segment_len = length/(self.segments_len as u32);
+        self.reset();
+    }
+
+    pub fn reset(&mut self) {
+        self.segment_mean = 0.0;
+        self.segment_m2 = 0.0;
+        self.segment_count = 0;
+        self.segment_index = 0;
+        self.segments_filled = 0;
+        self.output = None;
+        self.statistics = None;
+    }
+
+    /// Number of samples in the averaging window.
+    fn length(&self) -> u32 {
+        self.segment_len*(self.segments_len as u32)
+    }
+
+    /// Returns the effective averaging time, in seconds.
+    pub fn averaging_time(&self) -> f32 {
+        self.length() as f32/board::ADC_SAMPLE_RATE
+    }
+
+    /// Returns the -3dB bandwidth, in Hz.
+    pub fn bandwidth(&self) -> f32 {
+        match self.kind {
+            Kind::Exponential if self.segments_len > 1 => {
+                let alpha = 1.0/(self.segments_len as f32);
+                let segment_rate = board::ADC_SAMPLE_RATE/(self.segment_len as f32);
+                -(1.0 - alpha).ln()*segment_rate/(2.0*PI)
+            }
+            _ => 0.443*board::ADC_SAMPLE_RATE/(self.length() as f32)
+        }
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    /// Returns the number of outputs produced per second.
+    pub fn output_rate(&self) -> f32 {
+        match self.kind {
+            Kind::Block => board::ADC_SAMPLE_RATE/(self.length() as f32),
+            Kind::Moving | Kind::Exponential => board::ADC_SAMPLE_RATE/(self.segment_len as f32)
+        }
+    }
+
+    pub fn input(&mut self, sample: f32) {
+        // Welford's algorithm, which does not lose the noise in the mean.
+        self.segment_count += 1;
+        let delta = sample - self.segment_mean;
+        self.segment_mean += delta/(self.segment_count as f32);
+        self.segment_m2 += delta*(sample - self.segment_mean);
+        if self.segment_count < self.segment_len {
+            return
+        }
+        let segment = self.segment_mean;
+        self.segments[self.segment_index] = segment;
+        self.segments_m2[self.segment_index] = self.segment_m2;
+        self.segment_index = (self.segment_index + 1) % self.segments_len;
+        self.segment_mean = 0.0;
+        self.segment_m2 = 0.0;
+        self.segment_count = 0;
+
+        match self.kind {
+            Kind::Block | Kind::Moving => {
+                if self.segments_filled < self.segments_len {
+                    self.segments_filled += 1;
+                }
+                if self.segments_filled == self.segments_len {
+                    let mut sum = 0.0;
+                    for segment in self.segments[..self.segments_len].iter() {
+                        sum += *segment;
+                    }
+                    self.output = Some(sum/(self.segments_len as f32));
+                    self.output_count = self.output_count.wrapping_add(1);
+                    self.statistics = Some(self.window_statistics());
+                    if self.kind == Kind::Block {
+                        self.segments_filled = 0;
+                    }
+                }
+            }
+            Kind::Exponential => {
+                if self.segments_filled == 0 {
+                    self.exponential = segment;
+                } else {
+                    self.exponential += (segment - self.exponential)/(self.segments_len as f32);
+                }
+                // Only report once the initial value has been mostly forgotten.
+                if self.segments_filled < self.segments_len {
+                    self.segments_filled += 1;
+                } else {
+                    self.output = Some(self.exponential);
+                    self.output_count = self.output_count.wrapping_add(1);
+                    self.statistics = Some(self.window_statistics());
+                }
+            }
+        }
+    }
+
+    /// Computes the noise over the last window of segments, which must be full.
+    fn window_statistics(&self) -> Statistics {
+        let segments = &self.segments[..self.segments_len];
+        let count = segments.len() as f32;
+        let mut mean = 0.0;
+        for segment in segments.iter() {
+            mean += *segment;
+        }
+        mean /= count;
+        let mut spread = 0.0;
+        for segment in segments.iter() {
+            spread += (*segment - mean)*(*segment - mean);
+        }
+        let mut m2 = spread*(self.segment_len as f32);
+        for segment_m2 in self.segments_m2[..self.segments_len].iter() {
+            m2 += *segment_m2;
+        }
+
+        let samples = self.length() as f32;
+        let std_dev = if samples > 1.0 { (m2/(samples - 1.0)).sqrt() } else { 0.0 };
+        // The scatter of the segment means accounts for noise that is
+        // correlated over less than a segment. With a single segment, assume
+        // that the samples are uncorrelated.
+        let segment_std_dev = if segments.len() > 1 {
+            (spread/(count - 1.0)).sqrt()
+        } else {
+            std_dev/(self.segment_len as f32).sqrt()
+        };
+        let std_error = match self.kind {
+            Kind::Block | Kind::Moving => segment_std_dev/count.sqrt(),
+            Kind::Exponential => {
+                let alpha = 1.0/count;
+                segment_std_dev*(alpha/(2.0 - alpha)).sqrt()
+            }
+        };
+        Statistics {
+            std_dev: std_dev,
+            std_error: std_error
+        }
+    }
+
+    pub fn output(&self) -> Option<f32> {
+        self.output
+    }
+
+    /// Returns a counter that is incremented with each new output.
+    pub fn output_count(&self) -> u32 {
+        self.output_count
+    }
+
+    pub fn statistics(&self) -> Option<Statistics> {
+        self.statistics
+    }
+}
@@ -0,0 +1,301 @@
+use core::cell::RefCell;
+use core::str::FromStr;
+use cortex_m;
+use cortex_m::interrupt::Mutex;
+use libm::F32Ext;
+
+use config;
+use loop_anode;
+use loop_cathode;
+use electrometer;
+use calibration;
+use pid;
+
+pub const ANODE_MAX: f32 = 250.0;
+pub const CATHODE_BIAS_MAX: f32 = 90.0;
+pub const EMISSION_MAX: f32 = 10.0e-3;
+pub const NOISE_THRESHOLD_MAX: f32 = 10.0;
+pub const FILAMENT_RAMP_MAX: f32 = 100.0;
+pub const SLEW_RATE_MIN: f32 = 1.0;
+pub const SLEW_RATE_MAX: f32 = 1000.0;
+pub const DIODE_DROP_MIN: f32 = 0.3;
+pub const DIODE_DROP_MAX: f32 = 1.2;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PressureUnit {
+    Mbar,
+    Torr,
+    Pa
+}
+
+impl PressureUnit {
+    pub fn from_mbar(&self, mbar: f32) -> f32 {
+        match *self {
+            PressureUnit::Mbar => mbar,
+            PressureUnit::Torr => mbar*0.7500617,
+            PressureUnit::Pa   => mbar*100.0
+        }
+    }
+}
+
+/// Regulation loops with tunable PID parameters.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PidLoop {
+    /// Anode voltage, driving the high voltage PWM
+    Anode,
+    /// Emission current, driving the filament voltage target
+    Emission,
+    /// Filament voltage, driving the filament PWM
+    FilamentVoltage
+}
+
+pub const PID_LOOPS: [PidLoop; 3] = [PidLoop::Anode, PidLoop::Emission, PidLoop::FilamentVoltage];
+
+impl PidLoop {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            PidLoop::Anode => "anode",
+            PidLoop::Emission => "emission",
+            PidLoop::FilamentVoltage => "filament_voltage"
+        }
+    }
+
+    /// Index of the loop parameters in `config::Config::pid`.
+    pub fn index(&self) -> usize {
+        match *self {
+            PidLoop::Anode => 0,
+            PidLoop::Emission => 1,
+            PidLoop::FilamentVoltage => 2
+        }
+    }
+
+    pub fn default_parameters(&self) -> pid::Parameters {
+        match *self {
+            PidLoop::Anode => loop_anode::PID_PARAMETERS,
+            PidLoop::Emission => loop_cathode::FBI_PID_PARAMETERS,
+            PidLoop::FilamentVoltage => loop_cathode::FV_PID_PARAMETERS
+        }
+    }
+}
+
+impl FromStr for PidLoop {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<PidLoop, &'static str> {
+        match s.trim() {
+            "anode" => Ok(PidLoop::Anode),
+            "emission" => Ok(PidLoop::Emission),
+            "filament_voltage" => Ok(PidLoop::FilamentVoltage),
+            _ => Err("expected \"anode\", \"emission\" or \"filament_voltage\"")
+        }
+    }
+}
+
+// Ion currents below this are dominated by the electrometer offset and leakage.
+pub const IC_MIN: f32 = 1.0e-12;
+
+/// Result of a pressure measurement. The under and over range variants
+/// carry the measurable limit, in mbar.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Measurement {
+    Valid(f32),
+    UnderRange(f32),
+    OverRange(f32),
+    EmissionNotRegulated,
+    Settling,
+    OutputDisabled
+}
+
+impl Measurement {
+    pub fn pressure(&self) -> Option<f32> {
+        match *self {
+            Measurement::Valid(pressure) => Some(pressure),
+            _ => None
+        }
+    }
+
+    pub fn limit(&self) -> Option<f32> {
+        match *self {
+            Measurement::UnderRange(limit) | Measurement::OverRange(limit) => Some(limit),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Measurement::Valid(_) => "valid",
+            Measurement::UnderRange(_) => "under_range",
+            Measurement::OverRange(_) => "over_range",
+            Measurement::EmissionNotRegulated => "emission_not_regulated",
+            Measurement::Settling => "settling",
+            Measurement::OutputDisabled => "output_disabled"
+        }
+    }
+}
+
+/// Measures the pressure. The ratio of the ion and emission currents is
+/// divided by the sensitivity to the gas relative to nitrogen, and the
+/// calibration curve is the last step of the conversion.
+pub fn measure(cathode: &loop_cathode::ControllerStatus,
+               electrometer: &electrometer::ElectrometerStatus,
+               calibration: &calibration::Calibration, gas_sensitivity: f32) -> Measurement {
+    if !cathode.enabled {
+        return Measurement::OutputDisabled
+    }
+    let fbi = match cathode.fbi {
+        Some(fbi) if cathode.emission_ready && fbi > 0.0 => fbi,
+        _ => return Measurement::EmissionNotRegulated
+    };
+    let ic = match electrometer.ic {
+        Some(ic) if !electrometer.settling => ic,
+        _ => return Measurement::Settling
+    };
+    if electrometer.over_range {
+        Measurement::OverRange(calibration.pressure(electrometer.ic_max/fbi/gas_sensitivity))
+    } else if ic < IC_MIN {
+        Measurement::UnderRange(calibration.pressure(IC_MIN/fbi/gas_sensitivity))
+    } else {
+        Measurement::Valid(calibration.pressure(ic/fbi/gas_sensitivity))
+    }
+}
+
+/// Returns the relative standard uncertainty of the pressure, from the
+/// noise of the ion and emission currents and the slope of the calibration.
+pub fn pressure_uncertainty(cathode: &loop_cathode::ControllerStatus,
+                            electrometer: &electrometer::ElectrometerStatus,
+                            calibration: &calibration::Calibration,
+                            gas_sensitivity: f32) -> Option<f32> {
+    match (electrometer.ic, electrometer.ic_stats, cathode.fbi, cathode.fbi_stats) {
+        (Some(ic), Some(ic_stats), Some(fbi), Some(fbi_stats)) if ic > 0.0 && fbi > 0.0 => {
+            let ic_relative = ic_stats.std_error/ic;
+            let fbi_relative = fbi_stats.std_error/fbi;
+            let slope = calibration.log_slope(ic/fbi/gas_sensitivity);
+            Some(slope*(ic_relative*ic_relative + fbi_relative*fbi_relative).sqrt())
+        }
+        _ => None
+    }
+}
+
+/// Whether a reading is too noisy to be trusted. Readings without an
+/// uncertainty are not flagged; they have no pressure either.
+pub fn is_noisy(uncertainty: Option<f32>, noise_threshold: f32) -> bool {
+    match uncertainty {
+        Some(uncertainty) => uncertainty > noise_threshold,
+        None => false
+    }
+}
+
+pub fn check_anode(volts: f32) -> Result<f32, &'static str> {
+    if volts >= 0.0 && volts <= ANODE_MAX {
+        Ok(volts)
+    } else {
+        Err("anode voltage out of range")
+    }
+}
+
+pub fn check_cathode_bias(volts: f32) -> Result<f32, &'static str> {
+    if volts >= 0.0 && volts <= CATHODE_BIAS_MAX {
+        Ok(volts)
+    } else {
+        Err("cathode bias out of range")
+    }
+}
+
+pub fn check_emission(amperes: f32) -> Result<f32, &'static str> {
+    if amperes > 0.0 && amperes <= EMISSION_MAX {
+        Ok(amperes)
+    } else {
+        Err("emission current out of range")
+    }
+}
+
+pub fn check_filament_ramp(volts_per_second: f32) -> Result<f32, &'static str> {
+    if volts_per_second > 0.0 && volts_per_second <= FILAMENT_RAMP_MAX {
+        Ok(volts_per_second)
+    } else {
+        Err("filament ramp rate out of range")
+    }
+}
+
+/// Checks the slew rate of the anode voltage or cathode bias, in volts per
+/// second.
+pub fn check_slew_rate(volts_per_second: f32) -> Result<f32, &'static str> {
+    if volts_per_second >= SLEW_RATE_MIN && volts_per_second <= SLEW_RATE_MAX {
+        Ok(volts_per_second)
+    } else {
+        Err("slew rate out of range")
+    }
+}
+
+/// Checks the diode drop assumed in the high emission range, in volts.
+pub fn check_diode_drop(volts: f32) -> Result<f32, &'static str> {
+    if volts >= DIODE_DROP_MIN && volts <= DIODE_DROP_MAX {
+        Ok(volts)
+    } else {
+        Err("diode drop out of range")
+    }
+}
+
+pub fn check_noise_threshold(relative: f32) -> Result<f32, &'static str> {
+    if relative > 0.0 && relative <= NOISE_THRESHOLD_MAX {
+        Ok(relative)
+    } else {
+        Err("noise threshold out of range")
+    }
+}
+
+pub fn apply_config(config: &config::Config,
+                    loop_anode_m: &Mutex<RefCell<loop_anode::Controller>>,
+                    loop_cathode_m: &Mutex<RefCell<loop_cathode::Controller>>) {
+    cortex_m::interrupt::free(|cs| {
+        let mut loop_anode = loop_anode_m.borrow(cs).borrow_mut();
+        let mut loop_cathode = loop_cathode_m.borrow(cs).borrow_mut();
+
+        loop_anode.set_target(config.anode);
+        loop_anode.set_slew_rate(config.anode_slew_rate);
+        loop_cathode.set_fd_high(config.diode_drop);
+        loop_cathode.set_emission_target(config.emission);
+        loop_cathode.set_bias_target(config.cathode_bias);
+        loop_cathode.set_fv_ramp(config.filament_ramp);
+        loop_cathode.set_bias_slew_rate(config.bias_slew_rate);
+
+        loop_anode.set_enabled(config.output_enabled);
+        loop_cathode.set_enabled(config.output_enabled);
+    });
+}
+
+/// Applies the averaging settings, which restarts the averaging of the
+/// ion and emission currents.
+pub fn apply_filter(config: &config::Config,
+                    loop_cathode_m: &Mutex<RefCell<loop_cathode::Controller>>,
+                    electrometer_m: &Mutex<RefCell<electrometer::Electrometer>>) {
+    cortex_m::interrupt::free(|cs| {
+        loop_cathode_m.borrow(cs).borrow_mut().set_filter(config.filter, config.averaging_time);
+        electrometer_m.borrow(cs).borrow_mut().set_filter(config.filter, config.averaging_time);
+    });
+}
+
+/// Applies the PID parameters of the regulation loops. The loops keep
+/// running, without a step in their outputs.
+pub fn apply_pid(config: &config::Config,
+                 loop_anode_m: &Mutex<RefCell<loop_anode::Controller>>,
+                 loop_cathode_m: &Mutex<RefCell<loop_cathode::Controller>>) {
+    cortex_m::interrupt::free(|cs| {
+        let mut loop_cathode = loop_cathode_m.borrow(cs).borrow_mut();
+        loop_anode_m.borrow(cs).borrow_mut()
+            .set_pid_parameters(config.pid[PidLoop::Anode.index()]);
+        loop_cathode.set_fbi_pid_parameters(config.pid[PidLoop::Emission.index()]);
+        loop_cathode.set_fv_pid_parameters(config.pid[PidLoop::FilamentVoltage.index()]);
+    });
+}
+
+/// Applies the electrometer range settings. Changing the range discards
+/// the samples taken while the input settles.
+pub fn apply_range(config: &config::Config,
+                   electrometer_m: &Mutex<RefCell<electrometer::Electrometer>>) {
+    cortex_m::interrupt::free(|cs| {
+        let mut electrometer = electrometer_m.borrow(cs).borrow_mut();
+        electrometer.set_autorange(config.autorange);
+        electrometer.set_range_mode(config.range_mode);
+    });
+}
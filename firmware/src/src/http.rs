@@ -0,0 +1,883 @@
+use core::fmt;
+use core::str;
+use smoltcp::wire::{IpCidr, Ipv4Address};
+use smoltcp::socket::TcpSocket;
+
+use args;
+use auth;
+
+const MAX_METHOD: usize = 8;
+const MAX_QUERY: usize = 256;
+const MAX_VERSION: usize = 8;
+const MAX_HEADER_LINE: usize = 256;
+const MAX_HEADER_VALUE: usize = 128;
+const MAX_BODY: usize = 1024;
+
+const ERR_UNSUPPORTED_METHOD: &'static str = "unsupported method";
+const ERR_BODY_TOO_LONG: &'static str = "body is too long";
+
+/// Persistent connections are closed after this many milliseconds without a request...
+pub const KEEP_ALIVE_TIMEOUT: u64 = 10_000;
+/// ... or after serving this many requests.
+pub const KEEP_ALIVE_MAX_REQUESTS: u32 = 100;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Method {
+    Get,
+    Head,
+    Post
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum State {
+    GetMethod,
+    GetQuery,
+    GetVersion,
+    WaitLF,
+    GetHeader,
+    WaitHeaderLF,
+    GetBody,
+    Finished
+}
+
+/// A header value stored in a fixed-size buffer.
+struct HeaderValue {
+    len: Option<usize>,
+    data: [u8; MAX_HEADER_VALUE]
+}
+
+impl HeaderValue {
+    fn new() -> HeaderValue {
+        HeaderValue {
+            len: None,
+            data: [0; MAX_HEADER_VALUE]
+        }
+    }
+
+    fn clear(&mut self) {
+        self.len = None;
+    }
+
+    fn set(&mut self, value: &[u8]) -> Result<(), &'static str> {
+        if value.len() > self.data.len() {
+            return Err("header value is too long")
+        }
+        self.data[..value.len()].copy_from_slice(value);
+        self.len = Some(value.len());
+        Ok(())
+    }
+
+    fn get<'a>(&'a self) -> Option<&'a [u8]> {
+        self.len.map(|len| &self.data[..len])
+    }
+}
+
+pub struct Request {
+    state: State,
+    method: Option<Method>,
+    method_idx: usize,
+    method_buf: [u8; MAX_METHOD],
+    query_idx: usize,
+    query: [u8; MAX_QUERY],
+    version_idx: usize,
+    version: [u8; MAX_VERSION],
+    line_idx: usize,
+    line_overflow: bool,
+    line: [u8; MAX_HEADER_LINE],
+    content_length: Option<usize>,
+    connection_keep_alive: Option<bool>,
+    content_type: HeaderValue,
+    authorization: HeaderValue,
+    host: HeaderValue,
+    if_none_match: HeaderValue,
+    body_idx: usize,
+    body: [u8; MAX_BODY]
+}
+
+fn trim(mut data: &[u8]) -> &[u8] {
+    while let Some((&c, rest)) = data.split_first() {
+        if c == b' ' || c == b'\t' { data = rest } else { break }
+    }
+    while let Some((&c, rest)) = data.split_last() {
+        if c == b' ' || c == b'\t' { data = rest } else { break }
+    }
+    data
+}
+
+#[derive(Debug,Clone,Copy)]
+pub struct ArgError {
+    pub name: &'static str,
+    pub message: &'static str
+}
+
+impl fmt::Display for ArgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "argument \"{}\": {}", self.name, self.message)
+    }
+}
+
+fn parse_ip_cidr(value: &str) -> Result<IpCidr, &'static str> {
+    match value.trim().parse() {
+        Ok(ip @ IpCidr::Ipv4(_)) => Ok(ip),
+        _ => Err("invalid IPv4 address, expected <address>/<prefix>")
+    }
+}
+
+fn parse_ipv4(value: &str) -> Result<Ipv4Address, &'static str> {
+    value.trim().parse().map_err(|_| "invalid IPv4 address")
+}
+
+fn starts_with_ignore_case(data: &[u8], prefix: &[u8]) -> bool {
+    data.len() >= prefix.len() && data[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+impl Request {
+    pub fn new() -> Request {
+        Request {
+            state: State::GetMethod,
+            method: None,
+            method_idx: 0,
+            method_buf: [0; MAX_METHOD],
+            query_idx: 0,
+            query: [0; MAX_QUERY],
+            version_idx: 0,
+            version: [0; MAX_VERSION],
+            line_idx: 0,
+            line_overflow: false,
+            line: [0; MAX_HEADER_LINE],
+            content_length: None,
+            connection_keep_alive: None,
+            content_type: HeaderValue::new(),
+            authorization: HeaderValue::new(),
+            host: HeaderValue::new(),
+            if_none_match: HeaderValue::new(),
+            body_idx: 0,
+            body: [0; MAX_BODY]
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.state = State::GetMethod;
+        self.method = None;
+        self.method_idx = 0;
+        self.query_idx = 0;
+        self.version_idx = 0;
+        self.line_idx = 0;
+        self.line_overflow = false;
+        self.content_length = None;
+        self.connection_keep_alive = None;
+        self.content_type.clear();
+        self.authorization.clear();
+        self.host.clear();
+        self.if_none_match.clear();
+        self.body_idx = 0;
+    }
+
+    fn parse_header(&mut self) -> Result<(), &'static str> {
+        let line = &self.line[..self.line_idx];
+        let colon = match line.iter().position(|b| *b == b':') {
+            Some(colon) => colon,
+            None => return Err("malformed header")
+        };
+        let name = &line[..colon];
+        let value = trim(&line[colon + 1..]);
+
+        let known = name.eq_ignore_ascii_case(b"Content-Length") ||
+                    name.eq_ignore_ascii_case(b"Content-Type") ||
+                    name.eq_ignore_ascii_case(b"Authorization") ||
+                    name.eq_ignore_ascii_case(b"Host");
+        if known && self.line_overflow {
+            return Err("header is too long")
+        }
+
+        if name.eq_ignore_ascii_case(b"Content-Length") {
+            let length = str::from_utf8(value).ok().and_then(|s| s.parse().ok());
+            match length {
+                Some(length) => self.content_length = Some(length),
+                None => return Err("invalid Content-Length")
+            }
+        } else if name.eq_ignore_ascii_case(b"Connection") {
+            for option in value.split(|b| *b == b',') {
+                let option = trim(option);
+                if option.eq_ignore_ascii_case(b"close") {
+                    self.connection_keep_alive = Some(false);
+                } else if option.eq_ignore_ascii_case(b"keep-alive") {
+                    self.connection_keep_alive = Some(true);
+                }
+            }
+        } else if name.eq_ignore_ascii_case(b"Content-Type") {
+            self.content_type.set(value)?;
+        } else if name.eq_ignore_ascii_case(b"Authorization") {
+            self.authorization.set(value)?;
+        } else if name.eq_ignore_ascii_case(b"Host") {
+            self.host.set(value)?;
+        } else if name.eq_ignore_ascii_case(b"If-None-Match") {
+            // A validator that does not fit only costs a full reply.
+            if self.line_overflow || self.if_none_match.set(value).is_err() {
+                self.if_none_match.clear();
+            }
+        }
+        Ok(())
+    }
+
+    fn end_headers(&mut self) -> Result<bool, &'static str> {
+        if self.version_idx == 0 {
+            return Err("missing HTTP version")
+        }
+        let length = match (self.method, self.content_length) {
+            (Some(Method::Post), None) => return Err("POST request without Content-Length"),
+            (_, Some(length)) => length,
+            (_, None) => 0
+        };
+        if length > self.body.len() {
+            return Err(ERR_BODY_TOO_LONG)
+        }
+        if length == 0 {
+            self.finish()
+        } else {
+            self.state = State::GetBody;
+            Ok(false)
+        }
+    }
+
+    fn finish(&mut self) -> Result<bool, &'static str> {
+        self.state = State::Finished;
+        if self.is_json() {
+            args::check_json(self.get_body())?;
+        }
+        Ok(true)
+    }
+
+    pub fn input_char(&mut self, c: u8) -> Result<bool, &'static str> {
+        match self.state {
+            State::GetMethod => {
+                if c == b' ' {
+                    self.method = match &self.method_buf[..self.method_idx] {
+                        b"GET" => Some(Method::Get),
+                        b"HEAD" => Some(Method::Head),
+                        b"POST" => Some(Method::Post),
+                        _ => return Err(ERR_UNSUPPORTED_METHOD)
+                    };
+                    self.state = State::GetQuery;
+                } else if self.method_idx >= self.method_buf.len() {
+                    return Err("invalid character in method")
+                } else {
+                    self.method_buf[self.method_idx] = c;
+                    self.method_idx += 1;
+                }
+            }
+            State::GetQuery => {
+                if c == b'\r' || c == b'\n' {
+                    return Err("request line ended prematurely")
+                } else if c == b' ' {
+                    if self.query_idx == 0 {
+                        return Err("query is empty")
+                    } else {
+                        self.state = State::GetVersion;
+                    }
+                } else {
+                    if self.query_idx >= self.query.len() {
+                        return Err("query is too long")
+                    } else {
+                        self.query[self.query_idx] = c;
+                        self.query_idx += 1;
+                    }
+                }
+            }
+            State::GetVersion => {
+                if c == b'\r' {
+                    if !starts_with_ignore_case(&self.version[..self.version_idx], b"HTTP/") {
+                        return Err("invalid HTTP version")
+                    }
+                    self.state = State::WaitLF;
+                } else if self.version_idx >= self.version.len() {
+                    return Err("invalid HTTP version")
+                } else {
+                    self.version[self.version_idx] = c;
+                    self.version_idx += 1;
+                }
+            }
+            State::WaitLF => {
+                if c == b'\n' {
+                    self.state = State::GetHeader;
+                } else {
+                    return Err("expected LF after CR")
+                }
+            }
+            State::GetHeader => {
+                if c == b'\r' {
+                    self.state = State::WaitHeaderLF;
+                } else if self.line_idx >= self.line.len() {
+                    self.line_overflow = true;
+                } else {
+                    self.line[self.line_idx] = c;
+                    self.line_idx += 1;
+                }
+            }
+            State::WaitHeaderLF => {
+                if c != b'\n' {
+                    return Err("expected LF after CR")
+                }
+                if self.line_idx == 0 {
+                    return self.end_headers()
+                }
+                self.parse_header()?;
+                self.line_idx = 0;
+                self.line_overflow = false;
+                self.state = State::GetHeader;
+            }
+            State::GetBody => {
+                self.body[self.body_idx] = c;
+                self.body_idx += 1;
+                if Some(self.body_idx) == self.content_length {
+                    return self.finish()
+                }
+            }
+            State::Finished => return Err("trailing characters")
+        }
+        Ok(false)
+    }
+
+    /// Consumes input up to the end of the request, and returns the number
+    /// of bytes consumed along with the parsing result. Any remaining input
+    /// belongs to the next request on the same connection.
+    pub fn input(&mut self, buf: &[u8]) -> (usize, Result<bool, &'static str>) {
+        for (i, c) in buf.iter().enumerate() {
+            match self.input_char(*c) {
+                Ok(false) => (),
+                result => return (i + 1, result)
+            }
+        }
+        (buf.len(), Ok(false))
+    }
+
+    /// Returns whether the client asked for the connection to be kept open,
+    /// explicitly or by default for HTTP/1.1.
+    pub fn keep_alive(&self) -> bool {
+        match self.connection_keep_alive {
+            Some(keep_alive) => keep_alive,
+            None => &self.version[..self.version_idx] != &b"HTTP/1.0"[..]
+        }
+    }
+
+    pub fn get_method(&self) -> Option<Method> {
+        self.method
+    }
+
+    pub fn get_content_type<'a>(&'a self) -> Option<&'a [u8]> {
+        self.content_type.get()
+    }
+
+    pub fn get_authorization<'a>(&'a self) -> Option<&'a [u8]> {
+        self.authorization.get()
+    }
+
+    pub fn get_host<'a>(&'a self) -> Option<&'a [u8]> {
+        self.host.get()
+    }
+
+    /// Returns whether the If-None-Match header lists `etag`, i.e. whether
+    /// the client already has the current version of the resource.
+    pub fn etag_matches(&self, etag: &str) -> bool {
+        let if_none_match = match self.if_none_match.get() {
+            Some(if_none_match) => if_none_match,
+            None => return false
+        };
+        if_none_match.split(|b| *b == b',').any(|candidate| {
+            let candidate = trim(candidate);
+            // Weak comparison, as recommended for GET and HEAD.
+            let candidate = if candidate.starts_with(b"W/") { &candidate[2..] } else { candidate };
+            candidate == b"*" || candidate == etag.as_bytes()
+        })
+    }
+
+    pub fn get_body<'a>(&'a self) -> &'a [u8] {
+        &self.body[..self.body_idx]
+    }
+
+    fn is_form(&self) -> bool {
+        self.get_content_type().map_or(false, |content_type|
+            starts_with_ignore_case(content_type, b"application/x-www-form-urlencoded"))
+    }
+
+    fn is_json(&self) -> bool {
+        self.get_content_type().map_or(false, |content_type|
+            starts_with_ignore_case(content_type, b"application/json"))
+    }
+
+    pub fn get_query<'a>(&'a self) -> Result<&'a [u8], &'static str> {
+        if self.state != State::Finished {
+            return Err("request is not finished")
+        }
+        Ok(&self.query[..self.query_idx])
+    }
+
+    pub fn get_path<'a>(&'a self) -> Result<&'a [u8], &'static str> {
+        let query = self.get_query()?;
+        Ok(query.split(|b| *b == '?' as u8).next().unwrap())
+    }
+
+    fn query_args<'a>(&'a self) -> Result<&'a [u8], &'static str> {
+        let query = self.get_query()?;
+        match query.iter().position(|b| *b == '?' as u8) {
+            Some(question) => Ok(&query[question + 1..]),
+            None => Ok(&query[query.len()..])
+        }
+    }
+
+    /// Returns the arguments in the query string, and in the body if it is
+    /// form-urlencoded or JSON.
+    fn args<'a>(&'a self) -> Result<args::Args<'a>, &'static str> {
+        Ok(args::Args {
+            query: self.query_args()?,
+            form: if self.is_form() { self.get_body() } else { &[] },
+            json: if self.is_json() { self.get_body() } else { &[] }
+        })
+    }
+
+    /// Returns the decoded value of an argument, or `None` if it is absent.
+    pub fn get_arg<'a>(&self, name: &'static str, buffer: &'a mut [u8])
+                      -> Result<Option<&'a str>, ArgError> {
+        let error = |message| ArgError { name: name, message: message };
+        self.args().and_then(move |args| args.get(name, buffer)).map_err(error)
+    }
+
+    pub fn get_arg_parsed<T, F>(&self, name: &'static str, parse: F) -> Result<Option<T>, ArgError>
+            where F: FnOnce(&str) -> Result<T, &'static str> {
+        let error = |message| ArgError { name: name, message: message };
+        self.args().and_then(|args| args.get_parsed(name, parse)).map_err(error)
+    }
+
+    pub fn get_arg_f32(&self, name: &'static str) -> Result<Option<f32>, ArgError> {
+        self.get_arg_parsed(name, args::parse_f32)
+    }
+
+    pub fn get_arg_u32(&self, name: &'static str) -> Result<Option<u32>, ArgError> {
+        self.get_arg_parsed(name, args::parse_u32)
+    }
+
+    pub fn get_arg_bool(&self, name: &'static str) -> Result<Option<bool>, ArgError> {
+        self.get_arg_parsed(name, args::parse_bool)
+    }
+
+    pub fn get_arg_ip_cidr(&self, name: &'static str) -> Result<Option<IpCidr>, ArgError> {
+        self.get_arg_parsed(name, parse_ip_cidr)
+    }
+
+    pub fn get_arg_ipv4(&self, name: &'static str) -> Result<Option<Ipv4Address>, ArgError> {
+        self.get_arg_parsed(name, parse_ipv4)
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Status {
+    Ok,
+    NotModified,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    PayloadTooLarge,
+    InternalServerError,
+    ServiceUnavailable
+}
+
+impl Status {
+    pub fn code(&self) -> u16 {
+        match *self {
+            Status::Ok => 200,
+            Status::NotModified => 304,
+            Status::BadRequest => 400,
+            Status::Unauthorized => 401,
+            Status::Forbidden => 403,
+            Status::NotFound => 404,
+            Status::MethodNotAllowed => 405,
+            Status::PayloadTooLarge => 413,
+            Status::InternalServerError => 500,
+            Status::ServiceUnavailable => 503
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match *self {
+            Status::Ok => "OK",
+            Status::NotModified => "Not Modified",
+            Status::BadRequest => "Bad Request",
+            Status::Unauthorized => "Unauthorized",
+            Status::Forbidden => "Forbidden",
+            Status::NotFound => "Not Found",
+            Status::MethodNotAllowed => "Method Not Allowed",
+            Status::PayloadTooLarge => "Payload Too Large",
+            Status::InternalServerError => "Internal Server Error",
+            Status::ServiceUnavailable => "Service Unavailable"
+        }
+    }
+}
+
+/// Returns the status of the reply to a request that failed to parse.
+pub fn error_status(err: &'static str) -> Status {
+    if err == ERR_UNSUPPORTED_METHOD {
+        Status::MethodNotAllowed
+    } else if err == ERR_BODY_TOO_LONG {
+        Status::PayloadTooLarge
+    } else {
+        Status::BadRequest
+    }
+}
+
+// FNV-1a, to tell whether the part of a reply rendered again is what was sent.
+const HASH_INITIAL: u32 = 0x811c9dc5;
+
+fn hash(mut hash: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+/// Point from which the rendering of a reply is resumed: the end of a row of
+/// the body, or the start of the reply.
+#[derive(Clone, Copy)]
+struct Resume {
+    row: usize,
+    position: usize
+}
+
+/// Sink for the rendering of a reply. It either counts the bytes, or sends
+/// those that fall between the already transmitted part of the reply and
+/// the end of the free space in the transmit buffer.
+///
+/// Rendering resumes from the last row that was completely sent. The bytes
+/// that are rendered again, up to the end of the transmitted part, are
+/// checked against a hash of what was sent.
+pub struct Output<'a> {
+    socket: Option<&'a mut TcpSocket>,
+    position: usize,
+    sent: usize,
+    full: bool,
+    row: usize,
+    resume: Resume,
+    /// Hash of the bytes from `resume.position` to the end of the transmitted part.
+    hash: u32,
+    /// Hash of the bytes sent by the previous rendering past its resume point,
+    /// until they have been rendered again.
+    expected_hash: Option<u32>,
+    changed: bool
+}
+
+impl<'a> Output<'a> {
+    fn counter() -> Output<'a> {
+        Output {
+            socket: None,
+            position: 0,
+            sent: 0,
+            full: false,
+            row: 0,
+            resume: Resume { row: 0, position: 0 },
+            hash: HASH_INITIAL,
+            expected_hash: None,
+            changed: false
+        }
+    }
+
+    fn window(socket: &'a mut TcpSocket, sent: usize, resume: Resume,
+              expected_hash: u32) -> Output<'a> {
+        Output {
+            socket: Some(socket),
+            position: resume.position,
+            sent: sent,
+            full: false,
+            row: resume.row,
+            resume: resume,
+            hash: HASH_INITIAL,
+            expected_hash: if sent > resume.position { Some(expected_hash) } else { None },
+            changed: false
+        }
+    }
+
+    /// Checks the bytes rendered again against those that were sent.
+    fn check(&mut self) -> fmt::Result {
+        if let Some(expected_hash) = self.expected_hash.take() {
+            if self.hash != expected_hash {
+                self.changed = true;
+                return Err(fmt::Error)
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_bytes(&mut self, data: &[u8]) -> fmt::Result {
+        if self.full || self.changed {
+            return Err(fmt::Error)
+        }
+        let start = self.position;
+        self.position += data.len();
+        if self.socket.is_none() {
+            return Ok(())
+        }
+        if self.sent > start {
+            let end = if self.position < self.sent { self.position } else { self.sent };
+            self.hash = hash(self.hash, &data[..end - start]);
+        }
+        if self.position > self.sent {
+            self.check()?;
+            let chunk = &data[self.sent - start..];
+            let sent = match self.socket {
+                Some(ref mut socket) => socket.send_slice(chunk).map_err(|_| fmt::Error)?,
+                None => unreachable!()
+            };
+            self.hash = hash(self.hash, &chunk[..sent]);
+            self.sent += sent;
+            if sent < chunk.len() {
+                // Stop rendering; the rest is sent once the buffer drains.
+                self.full = true;
+                return Err(fmt::Error)
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks the end of a row of the body. Once a row has been sent,
+    /// rendering resumes after it instead of starting over.
+    pub fn row(&mut self) {
+        self.row += 1;
+        if self.socket.is_some() && self.expected_hash.is_none() && self.position <= self.sent {
+            self.resume = Resume { row: self.row, position: self.position };
+            self.hash = HASH_INITIAL;
+        }
+    }
+}
+
+impl<'a> fmt::Write for Output<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes())
+    }
+}
+
+/// Body of a reply. It is rendered once to measure it, and then each time
+/// the transmit buffer has room for more of it, starting after the `row`-th
+/// row marked with `Output::row` (or from the start if `row` is 0). Rendering
+/// must not have side effects. Bodies that read live data should mark their
+/// rows, so that each row is rendered for sending only once.
+pub trait Content {
+    fn render(&self, output: &mut Output, row: usize) -> fmt::Result;
+}
+
+pub struct Response {
+    status: Status,
+    content_type: &'static str,
+    gzip: bool,
+    etag: Option<&'static str>,
+    allow: Option<&'static str>,
+    keep_alive: bool
+}
+
+impl Response {
+    pub fn new(status: Status) -> Response {
+        Response {
+            status: status,
+            content_type: "text/plain; charset=utf-8",
+            gzip: false,
+            etag: None,
+            allow: None,
+            keep_alive: false
+        }
+    }
+
+    pub fn content_type(mut self, content_type: &'static str) -> Response {
+        self.content_type = content_type;
+        self
+    }
+
+    /// Marks the body as gzip-compressed.
+    pub fn gzip(mut self) -> Response {
+        self.gzip = true;
+        self
+    }
+
+    /// Sets the entity tag, and lets the client cache the reply.
+    pub fn etag(mut self, etag: &'static str) -> Response {
+        self.etag = Some(etag);
+        self
+    }
+
+    /// Sets the methods listed in the Allow header of a 405 reply.
+    pub fn allow(mut self, methods: &'static str) -> Response {
+        self.allow = Some(methods);
+        self
+    }
+
+    pub fn keep_alive(mut self, keep_alive: bool) -> Response {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    fn write_header(&self, output: &mut fmt::Write, content_length: usize) -> fmt::Result {
+        write!(output, "HTTP/1.1 {} {}\r\n", self.status.code(), self.status.reason())?;
+        // A 304 reply has no body, and describes the representation the client already has.
+        if self.status != Status::NotModified {
+            write!(output, "Content-Type: {}\r\nContent-Length: {}\r\n",
+                   self.content_type, content_length)?;
+            if self.gzip {
+                write!(output, "Content-Encoding: gzip\r\n")?;
+            }
+        }
+        if self.keep_alive {
+            write!(output, "Connection: keep-alive\r\n")?;
+            write!(output, "Keep-Alive: timeout={}, max={}\r\n",
+                   KEEP_ALIVE_TIMEOUT/1000, KEEP_ALIVE_MAX_REQUESTS)?;
+        } else {
+            write!(output, "Connection: close\r\n")?;
+        }
+        if self.status == Status::Unauthorized {
+            write!(output, "WWW-Authenticate: Basic realm=\"ionpak\", charset=\"UTF-8\"\r\n")?;
+        }
+        if let Some(allow) = self.allow {
+            write!(output, "Allow: {}\r\n", allow)?;
+        }
+        if let Some(etag) = self.etag {
+            write!(output, "ETag: {}\r\n", etag)?;
+            write!(output, "Cache-Control: public, max-age=600\r\n")?;
+        }
+        write!(output, "\r\n")
+    }
+
+    /// Prepares the reply to `request`. The body is omitted for HEAD requests
+    /// and 304 replies.
+    pub fn body<T: Content>(self, request: &Request, body: T) -> Transfer<T> {
+        let head_only = request.get_method() == Some(Method::Head) ||
+                        self.status == Status::NotModified;
+        Transfer {
+            response: self,
+            body: body,
+            head_only: head_only,
+            lengths: None,
+            sent: 0,
+            resume: Resume { row: 0, position: 0 },
+            hash: HASH_INITIAL
+        }
+    }
+}
+
+/// A reply in the process of being sent, framed with Content-Length so that
+/// the connection can be reused.
+pub struct Transfer<T: Content> {
+    response: Response,
+    body: T,
+    head_only: bool,
+    /// Lengths of the body and of the whole reply.
+    lengths: Option<(usize, usize)>,
+    sent: usize,
+    resume: Resume,
+    hash: u32
+}
+
+impl<T: Content> Transfer<T> {
+    pub fn keep_alive(&self) -> bool {
+        self.response.keep_alive
+    }
+
+    /// Sends as much of the reply as the transmit buffer has room for.
+    /// Returns true once the whole reply has been queued.
+    pub fn poll(&mut self, socket: &mut TcpSocket) -> Result<bool, &'static str> {
+        let (content_length, length) = match self.lengths {
+            Some(lengths) => lengths,
+            None => {
+                let mut counter = Output::counter();
+                self.body.render(&mut counter, 0).map_err(|_| "cannot render reply")?;
+                let content_length = counter.position;
+                let mut counter = Output::counter();
+                self.response.write_header(&mut counter, content_length)
+                             .map_err(|_| "cannot render reply")?;
+                let length = counter.position + if self.head_only { 0 } else { content_length };
+                self.lengths = Some((content_length, length));
+                (content_length, length)
+            }
+        };
+        if !socket.can_send() {
+            return Ok(false)
+        }
+
+        let mut output = Output::window(socket, self.sent, self.resume, self.hash);
+        let mut result = Ok(());
+        if self.resume.row == 0 {
+            result = self.response.write_header(&mut output, content_length);
+        }
+        if result.is_ok() && !self.head_only {
+            result = self.body.render(&mut output, self.resume.row);
+        }
+        if result.is_ok() {
+            result = output.check();
+        }
+        self.sent = output.sent;
+        self.resume = output.resume;
+        self.hash = output.hash;
+        match result {
+            Err(_) if output.changed => Err("reply changed while it was being sent"),
+            Err(_) if output.full => Ok(false),
+            Err(_) => Err("cannot render reply"),
+            Ok(()) if output.position != length => Err("reply changed while it was being sent"),
+            Ok(()) => Ok(true)
+        }
+    }
+}
+
+/// Request counters, reported in the device status.
+#[derive(Clone, Copy)]
+pub struct Stats {
+    pub requests: u32,
+    pub failed_requests: u32
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            requests: 0,
+            failed_requests: 0
+        }
+    }
+}
+
+/// State of a persistent connection, used to enforce the keep-alive limits and
+/// to remember the credentials verified on it.
+pub struct Connection {
+    requests: u32,
+    last_activity: u64,
+    pub verified: auth::Verified
+}
+
+impl Connection {
+    pub fn new() -> Connection {
+        Connection {
+            requests: 0,
+            last_activity: 0,
+            verified: auth::Verified::new()
+        }
+    }
+
+    pub fn reset(&mut self, time: u64) {
+        self.requests = 0;
+        self.last_activity = time;
+        self.verified.reset();
+    }
+
+    pub fn activity(&mut self, time: u64) {
+        self.last_activity = time;
+    }
+
+    /// Decides whether the connection is kept open after replying to `request`.
+    pub fn keep_alive(&self, request: &Request) -> bool {
+        request.keep_alive() && self.requests + 1 < KEEP_ALIVE_MAX_REQUESTS
+    }
+
+    pub fn request_served(&mut self, time: u64) {
+        self.requests += 1;
+        self.last_activity = time;
+    }
+
+    pub fn timed_out(&self, time: u64) -> bool {
+        time > self.last_activity + KEEP_ALIVE_TIMEOUT
+    }
+}
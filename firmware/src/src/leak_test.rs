@@ -0,0 +1,258 @@
+//! Rate-of-rise test: with the chamber isolated from its pumps, the pressure
+//! rise is fitted over a fixed duration, and converted into a leak rate
+//! using the chamber volume.
+//!
+//! Every new ion current reading is used for the fit. Only a subset of them
+//! is kept in the trace, with a stride that is chosen when the test starts
+//! so that the trace is only ever appended to.
+
+use core::str::FromStr;
+use libm::F32Ext;
+
+use gauge;
+
+pub const DURATION_MIN: f32 = 1.0;
+pub const DURATION_MAX: f32 = 3600.0;
+pub const VOLUME_MAX: f32 = 1.0e6;
+
+const TRACE_LEN: usize = 2048;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Fit {
+    /// Constant rise, as caused by a leak
+    Linear,
+    /// Exponential rise, fitted on the logarithm of the pressure. The rate
+    /// of rise is reported at the end of the test.
+    Exponential
+}
+
+impl Fit {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Fit::Linear => "linear",
+            Fit::Exponential => "exponential"
+        }
+    }
+}
+
+impl FromStr for Fit {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Fit, &'static str> {
+        match s.trim() {
+            "linear" => Ok(Fit::Linear),
+            "exponential" => Ok(Fit::Exponential),
+            _ => Err("expected \"linear\" or \"exponential\"")
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Idle,
+    Running,
+    Finished,
+    Aborted(&'static str)
+}
+
+impl State {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            State::Idle => "idle",
+            State::Running => "running",
+            State::Finished => "finished",
+            State::Aborted(_) => "aborted"
+        }
+    }
+}
+
+pub fn check_duration(seconds: f32) -> Result<f32, &'static str> {
+    if seconds >= DURATION_MIN && seconds <= DURATION_MAX {
+        Ok(seconds)
+    } else {
+        Err("duration out of range")
+    }
+}
+
+pub fn check_volume(liters: f32) -> Result<f32, &'static str> {
+    if liters > 0.0 && liters <= VOLUME_MAX {
+        Ok(liters)
+    } else {
+        Err("volume out of range")
+    }
+}
+
+/// Pressure reading, at a time in seconds from the start of the test.
+#[derive(Clone, Copy)]
+pub struct Point {
+    pub time: f32,
+    pub pressure: f32
+}
+
+#[derive(Clone, Copy)]
+pub struct LeakTestStatus {
+    pub state: State,
+    pub fit: Fit,
+    pub duration: f32,
+    pub volume: f32,
+    pub elapsed: f32,
+    pub points: u32,
+    pub trace_len: usize,
+    /// Rate of rise, in mbar/s
+    pub rate: Option<f32>,
+    /// Leak rate, in mbar.L/s
+    pub leak_rate: Option<f32>
+}
+
+pub struct LeakTest {
+    state: State,
+    fit: Fit,
+    duration: f32,
+    volume: f32,
+    start: u64,
+    elapsed: f32,
+    last_count: u32,
+    stride: u32,
+    // Least squares sums. Double precision avoids the cancellation in the
+    // slope over long tests.
+    n: u32,
+    sum_t: f64,
+    sum_tt: f64,
+    sum_y: f64,
+    sum_ty: f64,
+    trace: [Point; TRACE_LEN],
+    trace_len: usize
+}
+
+impl LeakTest {
+    pub const fn new() -> LeakTest {
+        LeakTest {
+            state: State::Idle,
+            fit: Fit::Linear,
+            duration: 0.0,
+            volume: 0.0,
+            start: 0,
+            elapsed: 0.0,
+            last_count: 0,
+            stride: 0,
+            n: 0,
+            sum_t: 0.0,
+            sum_tt: 0.0,
+            sum_y: 0.0,
+            sum_ty: 0.0,
+            trace: [Point { time: 0.0, pressure: 0.0 }; TRACE_LEN],
+            trace_len: 0
+        }
+    }
+
+    /// Starts a test at `time` (in ms), given the electrometer reading counter
+    /// and the number of readings per second.
+    pub fn start(&mut self, time: u64, fit: Fit, duration: f32, volume: f32,
+                 count: u32, rate: f32) {
+        self.state = State::Running;
+        self.fit = fit;
+        self.duration = duration;
+        self.volume = volume;
+        self.start = time;
+        self.elapsed = 0.0;
+        self.last_count = count;
+        self.stride = ((duration*rate/(TRACE_LEN as f32)).ceil() as u32).max(1);
+        self.n = 0;
+        self.sum_t = 0.0;
+        self.sum_tt = 0.0;
+        self.sum_y = 0.0;
+        self.sum_ty = 0.0;
+        self.trace_len = 0;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state == State::Running
+    }
+
+    pub fn abort(&mut self, reason: &'static str) {
+        if self.state == State::Running {
+            self.state = State::Aborted(reason);
+        }
+    }
+
+    /// Feeds the current measurement, which is only used if the electrometer
+    /// reading counter `count` has changed since the last call.
+    pub fn input(&mut self, time: u64, count: u32, measurement: gauge::Measurement) {
+        if self.state != State::Running || count == self.last_count {
+            return
+        }
+        self.last_count = count;
+        self.elapsed = ((time - self.start) as f32)/1000.0;
+
+        match measurement {
+            gauge::Measurement::Valid(pressure) => {
+                let t = self.elapsed as f64;
+                let y = match self.fit {
+                    Fit::Linear => pressure,
+                    Fit::Exponential => pressure.ln()
+                } as f64;
+                if self.n % self.stride == 0 && self.trace_len < TRACE_LEN {
+                    self.trace[self.trace_len] = Point { time: self.elapsed, pressure: pressure };
+                    self.trace_len += 1;
+                }
+                self.n += 1;
+                self.sum_t += t;
+                self.sum_tt += t*t;
+                self.sum_y += y;
+                self.sum_ty += t*y;
+            }
+            // Range changes are expected while the pressure rises.
+            gauge::Measurement::Settling => (),
+            gauge::Measurement::OutputDisabled => self.state = State::Aborted("outputs disabled"),
+            gauge::Measurement::EmissionNotRegulated =>
+                self.state = State::Aborted("emission not regulated"),
+            gauge::Measurement::UnderRange(_) => self.state = State::Aborted("under range"),
+            gauge::Measurement::OverRange(_) => self.state = State::Aborted("over range")
+        }
+
+        if self.state == State::Running && self.elapsed >= self.duration {
+            self.state = State::Finished;
+        }
+    }
+
+    /// Returns the fitted rate of rise, in mbar/s.
+    fn rate(&self) -> Option<f32> {
+        let n = self.n as f64;
+        let denominator = n*self.sum_tt - self.sum_t*self.sum_t;
+        if self.n < 2 || denominator <= 0.0 {
+            return None
+        }
+        let slope = (n*self.sum_ty - self.sum_t*self.sum_y)/denominator;
+        match self.fit {
+            Fit::Linear => Some(slope as f32),
+            Fit::Exponential => {
+                let intercept = (self.sum_y - slope*self.sum_t)/n;
+                let end = (intercept + slope*(self.elapsed as f64)) as f32;
+                Some((slope as f32)*end.exp())
+            }
+        }
+    }
+
+    pub fn get_status(&self) -> LeakTestStatus {
+        let rate = self.rate();
+        LeakTestStatus {
+            state: self.state,
+            fit: self.fit,
+            duration: self.duration,
+            volume: self.volume,
+            elapsed: self.elapsed,
+            points: self.n,
+            trace_len: self.trace_len,
+            rate: rate,
+            leak_rate: rate.map(|rate| rate*self.volume)
+        }
+    }
+
+    pub fn trace_point(&self, index: usize) -> Option<Point> {
+        if index < self.trace_len {
+            Some(self.trace[index])
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,1563 @@
+use core::fmt;
+use core::fmt::Write;
+use core::cell::RefCell;
+use cortex_m;
+use cortex_m::interrupt::Mutex;
+use smoltcp::socket::TcpSocket;
+use smoltcp::wire::IpCidr;
+
+use board;
+use auth;
+use assets;
+use http;
+use config;
+use gauge;
+use filter;
+use loop_anode;
+use loop_cathode;
+use electrometer;
+use history;
+use chart;
+use leak_test;
+use calibration;
+use self_test;
+use degas;
+use pid;
+
+macro_rules! opn_fmt {
+    ($struct_name:ident, $error:expr) => {
+        struct $struct_name(Option<f32>);
+
+        impl fmt::Display for $struct_name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match self.0 {
+                    None => f.write_str($error),
+                    Some(x) => x.fmt(f)
+                }
+            }
+        }
+
+        impl fmt::LowerExp for $struct_name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match self.0 {
+                    None => f.write_str($error),
+                    Some(x) => x.fmt(f)
+                }
+            }
+        }
+    }
+}
+
+opn_fmt!(OpnFmt, "ERROR");
+opn_fmt!(OpnFmtJSON, "null");
+opn_fmt!(OpnFmtCSV, "");
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HistoryFormat {
+    Csv,
+    Json
+}
+
+struct MeasurementFmt(gauge::Measurement);
+
+impl fmt::Display for MeasurementFmt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            gauge::Measurement::Valid(pressure) => write!(f, "{:.1e} mbar", pressure),
+            gauge::Measurement::UnderRange(limit) => write!(f, "&lt; {:.1e} mbar (under range)", limit),
+            gauge::Measurement::OverRange(limit) => write!(f, "&gt; {:.1e} mbar (over range)", limit),
+            gauge::Measurement::EmissionNotRegulated => f.write_str("Emission not regulated"),
+            gauge::Measurement::Settling => f.write_str("Settling"),
+            gauge::Measurement::OutputDisabled => f.write_str("Outputs disabled")
+        }
+    }
+}
+
+/// Forms to edit the parameters of each PID loop.
+struct PidFormsFmt([pid::Parameters; 3]);
+
+impl fmt::Display for PidFormsFmt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for pid_loop in gauge::PID_LOOPS.iter() {
+            let parameters = &self.0[pid_loop.index()];
+            let (title, description) = match *pid_loop {
+                gauge::PidLoop::Anode =>
+                    ("Anode voltage", "Regulates the anode voltage with the high voltage PWM."),
+                gauge::PidLoop::Emission =>
+                    ("Emission current", "Regulates the emission current by setting the \
+                                          filament voltage target, in volts."),
+                gauge::PidLoop::FilamentVoltage =>
+                    ("Filament voltage", "Regulates the filament voltage with the filament PWM.")
+            };
+            let anti_windup_selected = |option| {
+                if parameters.anti_windup == option { "selected" } else { "" }
+            };
+            write!(f, include_str!("pid_form.html"),
+                   title=title, description=description, name=pid_loop.as_str(),
+                   kp=parameters.kp, ki=parameters.ki, kd=parameters.kd,
+                   output_min=parameters.output_min, output_max=parameters.output_max,
+                   integral_min=parameters.integral_min, integral_max=parameters.integral_max,
+                   setpoint_weight=parameters.setpoint_weight,
+                   derivative_filter=parameters.derivative_filter,
+                   clamp_selected=anti_windup_selected(pid::AntiWindup::Clamp),
+                   conditional_selected=anti_windup_selected(pid::AntiWindup::Conditional),
+                   back_calculation_selected=anti_windup_selected(pid::AntiWindup::BackCalculation),
+                   tracking=parameters.tracking, rate_limit=parameters.rate_limit)?;
+        }
+        Ok(())
+    }
+}
+
+pub enum Notice {
+    Empty,
+    Message(&'static str),
+    Error(http::ArgError)
+}
+
+impl fmt::Display for Notice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Notice::Empty => Ok(()),
+            Notice::Message(message) => f.write_str(message),
+            Notice::Error(ref err) => write!(f, "Error: {}", err)
+        }
+    }
+}
+
+/// Everything needed to render a reply, captured when the request is served
+/// so that the reply can be rendered again while it is being sent.
+pub enum Page {
+    Empty,
+    Text(&'static str),
+    Error(http::Status, &'static str),
+    Index {
+        anode: loop_anode::ControllerStatus,
+        cathode: loop_cathode::ControllerStatus,
+        electrometer: electrometer::ElectrometerStatus,
+        noise_threshold: f32,
+        calibration: calibration::Calibration,
+        gas_sensitivity: f32,
+        chart: chart::Chart
+    },
+    Measure {
+        cathode: loop_cathode::ControllerStatus,
+        electrometer: electrometer::ElectrometerStatus,
+        noise_threshold: f32,
+        calibration: calibration::Calibration,
+        gas_sensitivity: f32
+    },
+    Status {
+        time: u64,
+        anode: loop_anode::ControllerStatus,
+        cathode: loop_cathode::ControllerStatus,
+        electrometer: electrometer::ElectrometerStatus,
+        noise_threshold: f32,
+        calibration: calibration::Calibration,
+        gas_sensitivity: f32,
+        errors: board::ErrorStatus,
+        http_stats: http::Stats
+    },
+    GaugeSettings {
+        notice: Notice,
+        electrometer: electrometer::ElectrometerStatus,
+        noise_threshold: f32,
+        calibration: calibration::Calibration,
+        gas_sensitivity: f32
+    },
+    Calibration(calibration::Calibration, f32),
+    NetworkSettings {
+        notice: Notice,
+        ip: IpCidr,
+        password_set: bool
+    },
+    History {
+        history_m: &'static Mutex<RefCell<history::History>>,
+        format: HistoryFormat,
+        tier: usize,
+        first: u64,
+        end: u64,
+        time: u64
+    },
+    LeakTest {
+        notice: Notice,
+        status: leak_test::LeakTestStatus,
+        fit: leak_test::Fit,
+        duration: f32,
+        volume: f32
+    },
+    LeakTestStatus(leak_test::LeakTestStatus),
+    LeakTestTrace {
+        leak_test_m: &'static Mutex<RefCell<leak_test::LeakTest>>,
+        len: usize
+    },
+    Degas {
+        notice: Notice,
+        status: degas::DegasStatus,
+        emission: f32,
+        anode: f32,
+        duration: f32,
+        ramp: f32,
+        pressure: f32
+    },
+    DegasStatus(degas::DegasStatus),
+    Diagnostics {
+        notice: Notice,
+        state: self_test::State,
+        result: Option<self_test::SelfTestResult>,
+        threshold: f32
+    },
+    SelfTestStatus {
+        state: self_test::State,
+        result: Option<self_test::SelfTestResult>
+    },
+    Pid {
+        notice: Notice,
+        parameters: [pid::Parameters; 3]
+    },
+    PidStatus([pid::Parameters; 3]),
+    Firmware,
+    Asset(&'static [u8])
+}
+
+fn render_history(output: &mut http::Output,
+                  history_m: &'static Mutex<RefCell<history::History>>,
+                  format: HistoryFormat, tier: usize, first: u64, end: u64,
+                  time: u64, row: usize) -> fmt::Result {
+    let resolution = history::TIERS[tier].resolution;
+    if row == 0 {
+        match format {
+            HistoryFormat::Csv => write!(output, "time,min,mean,max,emission,status\n")?,
+            HistoryFormat::Json => write!(output, "{{\"uptime\": {}, \"resolution\": {}, \"samples\": [",
+                                          time/1000, resolution)?
+        }
+    }
+    // Each period is a row, so that sending resumes where it stopped.
+    for period in first + row as u64..end {
+        let sample = cortex_m::interrupt::free(|cs| {
+            history_m.borrow(cs).borrow().get(tier, period)
+        }).unwrap_or(history::EMPTY_SAMPLE);
+        match format {
+            HistoryFormat::Csv =>
+                write!(output, "{},{:e},{:e},{:e},{:e},{}\n",
+                       period*resolution, OpnFmtCSV(sample.min()), OpnFmtCSV(sample.mean()),
+                       OpnFmtCSV(sample.max()), OpnFmtCSV(sample.emission()),
+                       sample.status("|", ""))?,
+            HistoryFormat::Json =>
+                write!(output, "{}{{\"time\": {}, \"min\": {:e}, \"mean\": {:e}, \"max\": {:e}, \
+                                \"emission\": {:e}, \"status\": [{}]}}",
+                       if period == first { "" } else { ", " },
+                       period*resolution, OpnFmtJSON(sample.min()), OpnFmtJSON(sample.mean()),
+                       OpnFmtJSON(sample.max()), OpnFmtJSON(sample.emission()),
+                       sample.status(", ", "\""))?
+        }
+        output.row();
+    }
+    match format {
+        HistoryFormat::Csv => Ok(()),
+        HistoryFormat::Json => write!(output, "]}}")
+    }
+}
+
+fn render_leak_test_trace(output: &mut http::Output,
+                          leak_test_m: &'static Mutex<RefCell<leak_test::LeakTest>>,
+                          len: usize, row: usize) -> fmt::Result {
+    if row == 0 {
+        write!(output, "time,pressure\n")?;
+    }
+    for index in row..len {
+        let point = cortex_m::interrupt::free(|cs| {
+            leak_test_m.borrow(cs).borrow().trace_point(index)
+        });
+        if let Some(point) = point {
+            write!(output, "{:.3},{:e}\n", point.time, point.pressure)?;
+        }
+        output.row();
+    }
+    Ok(())
+}
+
+impl http::Content for Page {
+    fn render(&self, output: &mut http::Output, row: usize) -> fmt::Result {
+        match *self {
+            Page::Empty => Ok(()),
+            Page::Text(message) => write!(output, "{}\n", message),
+            Page::Error(status, err) => write!(output, "{}: {}\n", status.reason(), err),
+            Page::Index { ref anode, ref cathode, ref electrometer, noise_threshold,
+                          ref calibration, gas_sensitivity, ref chart } => {
+                let measurement = gauge::measure(cathode, electrometer, calibration, gas_sensitivity);
+                let uncertainty = gauge::pressure_uncertainty(cathode, electrometer, calibration,
+                                                              gas_sensitivity);
+                let noisy = gauge::is_noisy(uncertainty, noise_threshold);
+                write!(output, include_str!("index.html"),
+                    pressure=MeasurementFmt(measurement),
+                    uncertainty=OpnFmt(uncertainty.map(|x| x*100.0)),
+                    noisy=if noisy { "<span class=\"w3-text-red\">Noisy reading</span>" } else { "" },
+                    anode_ready=anode.ready,
+                    anode_av=OpnFmt(anode.av),
+                    cathode_ready=cathode.ready,
+                    cathode_fbi=OpnFmt(cathode.fbi.and_then(|x| Some(x*1.0e6))),
+                    cathode_fbi_noise=OpnFmt(cathode.fbi_stats.map(|x| x.std_dev*1.0e6)),
+                    cathode_fv=OpnFmt(cathode.fv),
+                    cathode_fv_target=OpnFmt(cathode.fv_target),
+                    cathode_fbv=OpnFmt(cathode.fbv),
+                    ion_current=OpnFmt(electrometer.ic.and_then(|x| Some(x*1.0e9))),
+                    ion_current_noise=OpnFmt(electrometer.ic_stats.map(|x| x.std_dev*1.0e9)),
+                    range=electrometer.range.as_str(),
+                    range_mode=electrometer.mode.as_str(),
+                    range_settling=if electrometer.settling { ", settling" } else { "" },
+                    chart=chart)
+            }
+            Page::Measure { ref cathode, ref electrometer, noise_threshold, ref calibration,
+                            gas_sensitivity } => {
+                let measurement = gauge::measure(cathode, electrometer, calibration, gas_sensitivity);
+                let uncertainty = gauge::pressure_uncertainty(cathode, electrometer, calibration,
+                                                              gas_sensitivity);
+                write!(output, "{{\"pressure\": {:.1e}, \"status\": \"{}\", \"limit\": {:.1e}, \
+                                \"current\": {:.3e}, \"uncertainty\": {:.2e}, \"noisy\": {}}}",
+                       OpnFmtJSON(measurement.pressure()), measurement.as_str(),
+                       OpnFmtJSON(measurement.limit()), OpnFmtJSON(electrometer.ic),
+                       OpnFmtJSON(uncertainty), gauge::is_noisy(uncertainty, noise_threshold))
+            }
+            Page::Status { time, ref anode, ref cathode, ref electrometer, noise_threshold,
+                           ref calibration, gas_sensitivity, ref errors, ref http_stats } => {
+                let measurement = gauge::measure(cathode, electrometer, calibration, gas_sensitivity);
+                let uncertainty = gauge::pressure_uncertainty(cathode, electrometer, calibration,
+                                                              gas_sensitivity);
+                write!(output, "{{\"version\": \"{}\", \"uptime_ms\": {}, \"pressure\": {:e}, \
+                                \"pressure_status\": \"{}\", \"pressure_limit\": {:e}, \
+                                \"pressure_uncertainty\": {:e}, \"noise_threshold\": {:e}, \
+                                \"noisy\": {}, \"calibration\": \"{}\", \"gas_sensitivity\": {}, ",
+                       VERSION, time, OpnFmtJSON(measurement.pressure()), measurement.as_str(),
+                       OpnFmtJSON(measurement.limit()), OpnFmtJSON(uncertainty),
+                       noise_threshold, gauge::is_noisy(uncertainty, noise_threshold),
+                       calibration.as_str(), gas_sensitivity)?;
+                write!(output, "\"protection\": {{\"latched\": {}, \"filament_overvolt\": {}, \
+                                \"filament_bias_overvolt\": {}, \"filament_bias_overcurrent\": {}, \
+                                \"anode_overvolt\": {}, \"anode_overcurrent\": {}}}, ",
+                       errors.latched, errors.fv, errors.fbv, errors.fbi, errors.av, errors.ai)?;
+                write!(output, "\"anode\": {{\"enabled\": {}, \"ready\": {}, \
+                                \"voltage\": {:e}, \"voltage_target\": {:e}, \
+                                \"voltage_setpoint\": {:e}, \"ramping\": {}}}, ",
+                       anode.enabled, anode.ready, OpnFmtJSON(anode.av), anode.av_target,
+                       OpnFmtJSON(anode.av_setpoint), anode.ramping)?;
+                write!(output, "\"cathode\": {{\"enabled\": {}, \"ready\": {}, \
+                                \"emission_ready\": {}, \"bias_ready\": {}, \
+                                \"emission\": {:e}, \"emission_target\": {:e}, \"emission_range\": \"{}\", \
+                                \"emission_range_changes\": {}, \"emission_settling\": {}, \
+                                \"diode_drop\": {:e}, \"diode_drop_high\": {:e}, \
+                                \"emission_std_dev\": {:e}, \"emission_std_error\": {:e}, \
+                                \"filament_voltage\": {:e}, \"filament_voltage_target\": {:e}, \
+                                \"filament_soft_start\": {}, \
+                                \"bias\": {:e}, \"bias_target\": {:e}, \"bias_setpoint\": {:e}, \
+                                \"bias_ramping\": {}}}, ",
+                       cathode.enabled, cathode.ready, cathode.emission_ready, cathode.bias_ready,
+                       OpnFmtJSON(cathode.fbi), cathode.fbi_target, cathode.fbi_range.as_str(),
+                       cathode.fbi_range_changes, cathode.fbi_settling,
+                       OpnFmtJSON(cathode.fd), cathode.fd_high,
+                       OpnFmtJSON(cathode.fbi_stats.map(|x| x.std_dev)),
+                       OpnFmtJSON(cathode.fbi_stats.map(|x| x.std_error)),
+                       OpnFmtJSON(cathode.fv), OpnFmtJSON(cathode.fv_target), cathode.soft_start,
+                       OpnFmtJSON(cathode.fbv), cathode.fbv_target,
+                       OpnFmtJSON(cathode.fbv_setpoint), cathode.bias_ramping)?;
+                write!(output, "\"electrometer\": {{\"current\": {:e}, \
+                                \"std_dev\": {:e}, \"std_error\": {:e}, \"range\": \"{}\", \
+                                \"range_mode\": \"{}\", \"range_changes\": {}, \"settling\": {}, \
+                                \"over_range\": {}, \"current_max\": {:e}, \
+                                \"autorange\": {{\"up_threshold\": {}, \"down_threshold\": {}, \
+                                \"dwell\": {}, \"settle\": {}}}, \
+                                \"filter\": \"{}\", \"averaging_time\": {:e}, \
+                                \"bandwidth\": {:e}}}, ",
+                       OpnFmtJSON(electrometer.ic), OpnFmtJSON(electrometer.ic_stats.map(|x| x.std_dev)),
+                       OpnFmtJSON(electrometer.ic_stats.map(|x| x.std_error)), electrometer.range.as_str(),
+                       electrometer.mode.as_str(), electrometer.range_changes, electrometer.settling,
+                       electrometer.over_range, electrometer.ic_max,
+                       electrometer.autorange.up_threshold, electrometer.autorange.down_threshold,
+                       electrometer.autorange.dwell, electrometer.autorange.settle,
+                       electrometer.filter.as_str(), electrometer.averaging_time,
+                       electrometer.bandwidth)?;
+                write!(output, "\"http\": {{\"requests\": {}, \"failed_requests\": {}}}}}",
+                       http_stats.requests, http_stats.failed_requests)
+            }
+            Page::GaugeSettings { ref notice, ref electrometer, noise_threshold, ref calibration,
+                                  gas_sensitivity } => {
+                let selected = |option| if electrometer.filter == option { "selected" } else { "" };
+                let range_selected = |option| if electrometer.mode == option { "selected" } else { "" };
+                let calibration_selected = |option| {
+                    if calibration.as_str() == option { "selected" } else { "" }
+                };
+                // The values of a linear calibration are empty.
+                let linear = calibration::Calibration::Linear;
+                let (points, coefficients) = match *calibration {
+                    calibration::Calibration::Table { .. } => (calibration.values(), linear.values()),
+                    calibration::Calibration::Polynomial(_) => (linear.values(), calibration.values()),
+                    calibration::Calibration::Linear => (linear.values(), linear.values())
+                };
+                write!(output, include_str!("gauge_settings.html"),
+                       status=notice, bandwidth=electrometer.bandwidth,
+                       averaging_time=electrometer.averaging_time,
+                       noise_threshold=noise_threshold*100.0,
+                       block_selected=selected(filter::Kind::Block),
+                       moving_selected=selected(filter::Kind::Moving),
+                       exponential_selected=selected(filter::Kind::Exponential),
+                       range=electrometer.range.as_str(),
+                       range_changes=electrometer.range_changes,
+                       auto_selected=range_selected(electrometer::RangeMode::Auto),
+                       low_selected=range_selected(
+                           electrometer::RangeMode::Hold(board::ElectrometerRange::Low)),
+                       med_selected=range_selected(
+                           electrometer::RangeMode::Hold(board::ElectrometerRange::Med)),
+                       high_selected=range_selected(
+                           electrometer::RangeMode::Hold(board::ElectrometerRange::High)),
+                       up_threshold=electrometer.autorange.up_threshold,
+                       down_threshold=electrometer.autorange.down_threshold,
+                       dwell=electrometer.autorange.dwell,
+                       settle=electrometer.autorange.settle,
+                       linear_selected=calibration_selected("linear"),
+                       table_selected=calibration_selected("table"),
+                       polynomial_selected=calibration_selected("polynomial"),
+                       points=points,
+                       coefficients=coefficients,
+                       gas_sensitivity=gas_sensitivity)
+            }
+            Page::Calibration(ref calibration, gas_sensitivity) => {
+                write!(output, "{{\"kind\": \"{}\", \"sensitivity\": {:e}, \"gas_sensitivity\": {}, \
+                                \"points\": [",
+                       calibration.as_str(), calibration::SENSITIVITY, gas_sensitivity)?;
+                if let calibration::Calibration::Table { ref points, len } = *calibration {
+                    for (i, point) in points[..len].iter().enumerate() {
+                        write!(output, "{}[{:e}, {:e}]", if i == 0 { "" } else { ", " },
+                               point.ratio, point.pressure)?;
+                    }
+                }
+                write!(output, "], \"coefficients\": [")?;
+                if let calibration::Calibration::Polynomial(ref coefficients) = *calibration {
+                    for (i, coefficient) in coefficients.iter().enumerate() {
+                        write!(output, "{}{:e}", if i == 0 { "" } else { ", " }, coefficient)?;
+                    }
+                }
+                write!(output, "]}}")
+            }
+            Page::NetworkSettings { ref notice, ip, password_set } => {
+                write!(output, include_str!("network_settings.html"),
+                       status=notice, ip=ip, username=auth::USERNAME,
+                       password_state=if password_set { "set" } else { "not set" })
+            }
+            Page::History { history_m, format, tier, first, end, time } =>
+                render_history(output, history_m, format, tier, first, end, time, row),
+            Page::LeakTest { ref notice, ref status, fit, duration, volume } => {
+                let fit_selected = |option| if fit == option { "selected" } else { "" };
+                let reason = match status.state {
+                    leak_test::State::Aborted(reason) => reason,
+                    _ => ""
+                };
+                write!(output, include_str!("leak_test.html"),
+                       refresh=if status.state == leak_test::State::Running {
+                           "<meta http-equiv=\"refresh\" content=\"2\">"
+                       } else { "" },
+                       status=notice,
+                       state=status.state.as_str(),
+                       separator=if reason.is_empty() { "" } else { ": " },
+                       reason=reason,
+                       elapsed=status.elapsed,
+                       duration=status.duration,
+                       points=status.points,
+                       rate=OpnFmt(status.rate),
+                       volume=status.volume,
+                       leak_rate=OpnFmt(status.leak_rate),
+                       linear_selected=fit_selected(leak_test::Fit::Linear),
+                       exponential_selected=fit_selected(leak_test::Fit::Exponential),
+                       form_duration=duration,
+                       form_volume=volume)
+            }
+            Page::LeakTestStatus(ref status) => {
+                let reason = match status.state {
+                    leak_test::State::Aborted(reason) => Some(reason),
+                    _ => None
+                };
+                write!(output, "{{\"state\": \"{}\", \"reason\": ", status.state.as_str())?;
+                match reason {
+                    Some(reason) => write!(output, "\"{}\", ", reason)?,
+                    None => write!(output, "null, ")?
+                }
+                write!(output, "\"fit\": \"{}\", \"duration\": {:e}, \"volume\": {:e}, \
+                                \"elapsed\": {:e}, \"points\": {}, \"rate\": {:e}, \
+                                \"leak_rate\": {:e}}}",
+                       status.fit.as_str(), status.duration, status.volume, status.elapsed,
+                       status.points, OpnFmtJSON(status.rate), OpnFmtJSON(status.leak_rate))
+            }
+            Page::LeakTestTrace { leak_test_m, len } =>
+                render_leak_test_trace(output, leak_test_m, len, row),
+            Page::Degas { ref notice, ref status, emission, anode, duration, ramp, pressure } => {
+                let reason = match status.state {
+                    degas::State::Aborted(reason) => reason,
+                    _ => ""
+                };
+                let running = status.state == degas::State::Ramp ||
+                              status.state == degas::State::Hold;
+                write!(output, include_str!("degas.html"),
+                       refresh=if running {
+                           "<meta http-equiv=\"refresh\" content=\"2\">"
+                       } else { "" },
+                       status=notice,
+                       state=status.state.as_str(),
+                       separator=if reason.is_empty() { "" } else { ": " },
+                       reason=reason,
+                       elapsed=status.elapsed,
+                       remaining=status.remaining,
+                       emission=status.emission*1.0e3,
+                       anode=status.anode,
+                       form_emission=emission*1.0e3,
+                       form_anode=anode,
+                       form_duration=duration,
+                       form_ramp=ramp,
+                       form_pressure=pressure)
+            }
+            Page::DegasStatus(ref status) => {
+                write!(output, "{{\"state\": \"{}\", \"reason\": ", status.state.as_str())?;
+                match status.state {
+                    degas::State::Aborted(reason) => write!(output, "\"{}\", ", reason)?,
+                    _ => write!(output, "null, ")?
+                }
+                write!(output, "\"emission\": {:e}, \"anode\": {:e}, \"elapsed\": {:e}, \
+                                \"remaining\": {:e}}}",
+                       status.emission, status.anode, status.elapsed, status.remaining)
+            }
+            Page::Diagnostics { ref notice, state, ref result, threshold } => {
+                let reason = match state {
+                    self_test::State::Aborted(reason) => reason,
+                    _ => ""
+                };
+                let verdict = match *result {
+                    None => "No test has been completed yet.",
+                    Some(ref result) if result.passed() =>
+                        "<span class=\"w3-text-green\">Passed</span>",
+                    Some(_) => "<span class=\"w3-text-red\">Failed</span>"
+                };
+                write!(output, include_str!("diagnostics.html"),
+                       refresh=if state == self_test::State::AnodeOn ||
+                                  state == self_test::State::AnodeOff {
+                           "<meta http-equiv=\"refresh\" content=\"2\">"
+                       } else { "" },
+                       status=notice,
+                       state=state.as_str(),
+                       separator=if reason.is_empty() { "" } else { ": " },
+                       reason=reason,
+                       verdict=verdict,
+                       anode=OpnFmt(result.map(|x| x.anode)),
+                       current_on=OpnFmt(result.map(|x| x.current_on*1.0e12)),
+                       current_off=OpnFmt(result.map(|x| x.current_off*1.0e12)),
+                       leakage=OpnFmt(result.map(|x| x.leakage*1.0e12)),
+                       over_range=if result.map(|x| x.over_range) == Some(true) {
+                           " (over range)"
+                       } else { "" },
+                       result_threshold=OpnFmt(result.map(|x| x.threshold*1.0e12)),
+                       threshold=threshold)
+            }
+            Page::SelfTestStatus { state, ref result } => {
+                write!(output, "{{\"state\": \"{}\", \"reason\": ", state.as_str())?;
+                match state {
+                    self_test::State::Aborted(reason) => write!(output, "\"{}\", ", reason)?,
+                    _ => write!(output, "null, ")?
+                }
+                write!(output, "\"result\": ")?;
+                match *result {
+                    None => write!(output, "null}}"),
+                    Some(ref result) =>
+                        write!(output, "{{\"passed\": {}, \"anode\": {:e}, \"current_on\": {:e}, \
+                                        \"current_off\": {:e}, \"leakage\": {:e}, \
+                                        \"threshold\": {:e}, \"over_range\": {}}}}}",
+                               result.passed(), result.anode, result.current_on,
+                               result.current_off, result.leakage, result.threshold,
+                               result.over_range)
+                }
+            }
+            Page::Pid { ref notice, parameters } => {
+                write!(output, include_str!("pid.html"),
+                       status=notice, forms=PidFormsFmt(parameters))
+            }
+            Page::PidStatus(parameters) => {
+                write!(output, "{{")?;
+                for (i, pid_loop) in gauge::PID_LOOPS.iter().enumerate() {
+                    let parameters = &parameters[pid_loop.index()];
+                    write!(output, "{}\"{}\": {{\"kp\": {:e}, \"ki\": {:e}, \"kd\": {:e}, \
+                                    \"output_min\": {:e}, \"output_max\": {:e}, \
+                                    \"integral_min\": {:e}, \"integral_max\": {:e}, \
+                                    \"setpoint_weight\": {:e}, \"derivative_filter\": {:e}, \
+                                    \"anti_windup\": \"{}\", \"tracking\": {:e}, \
+                                    \"rate_limit\": {:e}}}",
+                           if i == 0 { "" } else { ", " }, pid_loop.as_str(),
+                           parameters.kp, parameters.ki, parameters.kd,
+                           parameters.output_min, parameters.output_max,
+                           parameters.integral_min, parameters.integral_max,
+                           parameters.setpoint_weight, parameters.derivative_filter,
+                           parameters.anti_windup.as_str(), parameters.tracking,
+                           parameters.rate_limit)?;
+                }
+                write!(output, "}}")
+            }
+            Page::Firmware => {
+                write!(output, include_str!("firmware.html"),
+                       version=VERSION)
+            }
+            Page::Asset(data) => output.write_bytes(data)
+        }
+    }
+}
+
+pub type Reply = http::Transfer<Page>;
+
+fn text(request: &http::Request, keep_alive: bool, status: http::Status,
+        message: &'static str) -> Reply {
+    http::Response::new(status)
+        .keep_alive(keep_alive)
+        .body(request, Page::Text(message))
+}
+
+/// Replies to a request that could not be parsed.
+pub fn serve_error(request: &http::Request, err: &'static str) -> Reply {
+    let status = http::error_status(err);
+    http::Response::new(status)
+        .allow("GET, HEAD, POST")
+        .body(request, Page::Error(status, err))
+}
+
+/// Replies to a request for a settings or control page that failed `auth::check`.
+fn deny(request: &http::Request, keep_alive: bool, denial: auth::Denial) -> Reply {
+    match denial {
+        auth::Denial::Unauthorized =>
+            text(request, keep_alive, http::Status::Unauthorized, "Authentication required"),
+        auth::Denial::Forbidden =>
+            text(request, keep_alive, http::Status::Forbidden,
+                 "Access from this address is not allowed"),
+        auth::Denial::Throttled =>
+            text(request, keep_alive, http::Status::ServiceUnavailable,
+                 "Too many failed attempts, try again later")
+    }
+}
+
+/// Selects the history tier and the range of periods to download from the
+/// `resolution`, `start` and `end` arguments. Times are in seconds of uptime,
+/// or relative to now if negative. Without a resolution, the finest tier that
+/// goes back to the start is used.
+fn history_range(request: &http::Request,
+                 history_m: &Mutex<RefCell<history::History>>,
+                 time: u64) -> Result<(usize, u64, u64), http::ArgError> {
+    let now = (time/1000) as i64;
+    let parse_time = |value: &str| {
+        match value.trim().parse::<i64>() {
+            Ok(seconds) if seconds < 0 => Ok(if now + seconds > 0 { now + seconds } else { 0 }),
+            Ok(seconds) => Ok(seconds),
+            Err(_) => Err("invalid integer")
+        }
+    };
+    let start = request.get_arg_parsed("start", &parse_time)?;
+    let end = request.get_arg_parsed("end", &parse_time)?.unwrap_or(now) as u64;
+    let resolution = request.get_arg_parsed("resolution", |value| {
+        match value.trim().parse::<u64>() {
+            Ok(seconds) => Ok(seconds),
+            Err(_) => Err("invalid integer")
+        }
+    })?;
+
+    let periods = cortex_m::interrupt::free(|cs| {
+        let history = history_m.borrow(cs).borrow();
+        let periods = [history.periods(0), history.periods(1), history.periods(2)];
+        periods
+    });
+    let tier = match resolution {
+        Some(resolution) => match history::TIERS.iter().position(|tier| tier.resolution == resolution) {
+            Some(tier) => tier,
+            None => return Err(http::ArgError {
+                name: "resolution",
+                message: "expected 1, 60 or 600"
+            })
+        },
+        None => {
+            let start = start.unwrap_or(now) as u64;
+            (0..history::TIERS.len())
+                .find(|&tier| periods[tier].0*history::TIERS[tier].resolution <= start)
+                .unwrap_or(history::TIERS.len() - 1)
+        }
+    };
+
+    let (stored_first, stored_end) = periods[tier];
+    let resolution = history::TIERS[tier].resolution;
+    let mut first = match start {
+        Some(start) => start as u64/resolution,
+        None => stored_first
+    };
+    if first < stored_first {
+        first = stored_first;
+    }
+    let mut end = end/resolution + 1;
+    if end > stored_end {
+        end = stored_end;
+    }
+    if first > end {
+        first = end;
+    }
+    Ok((tier, first, end))
+}
+
+#[derive(Clone, Copy)]
+enum CalibrationKind {
+    Linear,
+    Table,
+    Polynomial
+}
+
+/// Parses a new calibration from the `calibration` argument, and the `points`
+/// or `coefficients` argument that the table or polynomial requires.
+fn calibration_arg(request: &http::Request)
+                   -> Result<Option<calibration::Calibration>, http::ArgError> {
+    let kind = request.get_arg_parsed("calibration", |value| {
+        match value.trim() {
+            "linear" => Ok(CalibrationKind::Linear),
+            "table" => Ok(CalibrationKind::Table),
+            "polynomial" => Ok(CalibrationKind::Polynomial),
+            _ => Err("expected \"linear\", \"table\" or \"polynomial\"")
+        }
+    })?;
+    let (name, values) = match kind {
+        None => return Ok(None),
+        Some(CalibrationKind::Linear) => return Ok(Some(calibration::Calibration::Linear)),
+        Some(CalibrationKind::Table) =>
+            ("points", request.get_arg_parsed("points", calibration::parse_points)?),
+        Some(CalibrationKind::Polynomial) =>
+            ("coefficients", request.get_arg_parsed("coefficients", calibration::parse_coefficients)?)
+    };
+    match values {
+        Some(calibration) => Ok(Some(calibration)),
+        None => Err(http::ArgError { name: name, message: "missing argument" })
+    }
+}
+
+/// Parses the `gas_sensitivity` argument, relative to nitrogen.
+fn gas_sensitivity_arg(request: &http::Request) -> Result<Option<f32>, http::ArgError> {
+    request.get_arg_parsed("gas_sensitivity", |value| {
+        match value.trim().parse::<f32>() {
+            Ok(relative) => calibration::check_gas_sensitivity(relative),
+            Err(_) => Err("invalid number")
+        }
+    })
+}
+
+#[derive(Clone, Copy)]
+enum TestAction {
+    Start,
+    Stop
+}
+
+fn parse_test_action(value: &str) -> Result<TestAction, &'static str> {
+    match value.trim() {
+        "start" => Ok(TestAction::Start),
+        "stop" => Ok(TestAction::Stop),
+        _ => Err("expected \"start\" or \"stop\"")
+    }
+}
+
+/// Starts or stops the leak test as requested by the `action` argument.
+/// When starting, the `fit`, `duration` and `volume` arguments default to,
+/// and are remembered in, the configuration.
+fn leak_test_action(request: &http::Request, config: &mut config::Config,
+                    electrometer_m: &Mutex<RefCell<electrometer::Electrometer>>,
+                    leak_test_m: &Mutex<RefCell<leak_test::LeakTest>>,
+                    degas: &degas::Degas,
+                    time: u64) -> Result<Option<&'static str>, http::ArgError> {
+    match request.get_arg_parsed("action", parse_test_action)? {
+        None => Ok(None),
+        Some(TestAction::Stop) => {
+            cortex_m::interrupt::free(|cs| {
+                leak_test_m.borrow(cs).borrow_mut().abort("stopped");
+            });
+            Ok(Some("Leak test has been stopped."))
+        }
+        Some(TestAction::Start) => {
+            if degas.is_running() {
+                return Err(http::ArgError { name: "action", message: "degas running" })
+            }
+            let fit = request.get_arg_parsed("fit", |value| value.parse::<leak_test::Fit>())?;
+            let duration = request.get_arg_parsed("duration", |value| {
+                match value.trim().parse() {
+                    Ok(seconds) => leak_test::check_duration(seconds),
+                    Err(_) => Err("invalid number")
+                }
+            })?;
+            let volume = request.get_arg_parsed("volume", |value| {
+                match value.trim().parse() {
+                    Ok(liters) => leak_test::check_volume(liters),
+                    Err(_) => Err("invalid number")
+                }
+            })?;
+            let fit = fit.unwrap_or(config.leak_test_fit);
+            let duration = duration.unwrap_or(config.leak_test_duration);
+            let volume = volume.unwrap_or(config.leak_test_volume);
+            if fit != config.leak_test_fit || duration != config.leak_test_duration ||
+                    volume != config.leak_test_volume {
+                config.leak_test_fit = fit;
+                config.leak_test_duration = duration;
+                config.leak_test_volume = volume;
+                config.save();
+            }
+            cortex_m::interrupt::free(|cs| {
+                let electrometer = electrometer_m.borrow(cs).borrow().get_status();
+                leak_test_m.borrow(cs).borrow_mut().start(time, fit, duration, volume,
+                                                          electrometer.ic_count,
+                                                          electrometer.ic_rate);
+            });
+            Ok(Some("Leak test has been started."))
+        }
+    }
+}
+
+/// Starts or stops the insulation self-test as requested by the `action`
+/// argument. When starting, the `threshold` argument defaults to, and is
+/// remembered in, the configuration.
+fn self_test_action(request: &http::Request, config: &mut config::Config,
+                    loop_anode_m: &Mutex<RefCell<loop_anode::Controller>>,
+                    loop_cathode_m: &Mutex<RefCell<loop_cathode::Controller>>,
+                    electrometer_m: &Mutex<RefCell<electrometer::Electrometer>>,
+                    self_test: &mut self_test::SelfTest,
+                    degas: &degas::Degas,
+                    time: u64) -> Result<Option<&'static str>, http::ArgError> {
+    match request.get_arg_parsed("action", parse_test_action)? {
+        None => Ok(None),
+        Some(TestAction::Stop) => {
+            self_test.abort("stopped", config, loop_anode_m, loop_cathode_m, electrometer_m);
+            Ok(Some("Self-test has been stopped."))
+        }
+        Some(TestAction::Start) => {
+            if degas.is_running() {
+                return Err(http::ArgError { name: "action", message: "degas running" })
+            }
+            let threshold = request.get_arg_parsed("threshold", |value| {
+                match value.trim().parse() {
+                    Ok(amperes) => self_test::check_threshold(amperes),
+                    Err(_) => Err("invalid number")
+                }
+            })?;
+            if let Some(threshold) = threshold {
+                if threshold != config.leakage_threshold {
+                    config.leakage_threshold = threshold;
+                    config.save();
+                }
+            }
+            match self_test.start(time, config, loop_anode_m, loop_cathode_m, electrometer_m) {
+                Ok(()) => Ok(Some("Self-test has been started.")),
+                Err(message) => Err(http::ArgError { name: "action", message: message })
+            }
+        }
+    }
+}
+
+/// Starts or stops degassing as requested by the `action` argument. When
+/// starting, the `emission` (in mA), `anode`, `duration`, `ramp` and
+/// `pressure` arguments default to, and are remembered in, the
+/// configuration.
+fn degas_action(request: &http::Request, config: &mut config::Config,
+                loop_anode_m: &Mutex<RefCell<loop_anode::Controller>>,
+                loop_cathode_m: &Mutex<RefCell<loop_cathode::Controller>>,
+                electrometer_m: &Mutex<RefCell<electrometer::Electrometer>>,
+                leak_test_m: &Mutex<RefCell<leak_test::LeakTest>>,
+                self_test: &self_test::SelfTest,
+                degas: &mut degas::Degas,
+                time: u64) -> Result<Option<&'static str>, http::ArgError> {
+    match request.get_arg_parsed("action", parse_test_action)? {
+        None => Ok(None),
+        Some(TestAction::Stop) => {
+            degas.abort("stopped", config, loop_anode_m, loop_cathode_m);
+            Ok(Some("Degas has been stopped."))
+        }
+        Some(TestAction::Start) => {
+            let leak_test_running = cortex_m::interrupt::free(|cs| {
+                leak_test_m.borrow(cs).borrow().is_running()
+            });
+            if leak_test_running {
+                return Err(http::ArgError { name: "action", message: "leak test running" })
+            }
+            if self_test.is_running() {
+                return Err(http::ArgError { name: "action", message: "self-test running" })
+            }
+            let emission = request.get_arg_parsed("emission", |value| {
+                match value.trim().parse::<f32>() {
+                    Ok(milliamperes) => gauge::check_emission(milliamperes*1.0e-3),
+                    Err(_) => Err("invalid number")
+                }
+            })?;
+            let anode = request.get_arg_parsed("anode", |value| {
+                match value.trim().parse() {
+                    Ok(volts) => gauge::check_anode(volts),
+                    Err(_) => Err("invalid number")
+                }
+            })?;
+            let duration = request.get_arg_parsed("duration", |value| {
+                match value.trim().parse() {
+                    Ok(seconds) => degas::check_duration(seconds),
+                    Err(_) => Err("invalid number")
+                }
+            })?;
+            let ramp = request.get_arg_parsed("ramp", |value| {
+                match value.trim().parse() {
+                    Ok(seconds) => degas::check_ramp(seconds),
+                    Err(_) => Err("invalid number")
+                }
+            })?;
+            let pressure = request.get_arg_parsed("pressure", |value| {
+                match value.trim().parse() {
+                    Ok(mbar) => degas::check_pressure(mbar),
+                    Err(_) => Err("invalid number")
+                }
+            })?;
+            let emission = emission.unwrap_or(config.degas_emission);
+            let anode = anode.unwrap_or(config.degas_anode);
+            let duration = duration.unwrap_or(config.degas_duration);
+            let ramp = ramp.unwrap_or(config.degas_ramp);
+            let pressure = pressure.unwrap_or(config.degas_pressure);
+            if emission != config.degas_emission || anode != config.degas_anode ||
+                    duration != config.degas_duration || ramp != config.degas_ramp ||
+                    pressure != config.degas_pressure {
+                config.degas_emission = emission;
+                config.degas_anode = anode;
+                config.degas_duration = duration;
+                config.degas_ramp = ramp;
+                config.degas_pressure = pressure;
+                config.save();
+            }
+            match degas.start(time, config, loop_cathode_m, electrometer_m) {
+                Ok(()) => Ok(Some("Degas has been started.")),
+                Err(message) => Err(http::ArgError { name: "action", message: message })
+            }
+        }
+    }
+}
+
+/// Updates the PID parameters of the loop given by the `loop` argument from
+/// the `kp`, `ki`, `kd`, `output_min`, `output_max`, `integral_min`,
+/// `integral_max`, `setpoint_weight`, `derivative_filter`, `anti_windup`,
+/// `tracking` and `rate_limit` arguments, or restores its defaults if
+/// `action` is "default".
+fn pid_action(request: &http::Request, config: &mut config::Config,
+              loop_anode_m: &Mutex<RefCell<loop_anode::Controller>>,
+              loop_cathode_m: &Mutex<RefCell<loop_cathode::Controller>>)
+              -> Result<Option<&'static str>, http::ArgError> {
+    let pid_loop = match request.get_arg_parsed("loop", |value| value.parse::<gauge::PidLoop>())? {
+        None => return Ok(None),
+        Some(pid_loop) => pid_loop
+    };
+    let restore = request.get_arg_parsed("action", |value| {
+        match value.trim() {
+            "update" => Ok(false),
+            "default" => Ok(true),
+            _ => Err("expected \"update\" or \"default\"")
+        }
+    })?.unwrap_or(false);
+
+    let parameters = if restore {
+        pid_loop.default_parameters()
+    } else {
+        let current = config.pid[pid_loop.index()];
+        pid::Parameters {
+            kp: request.get_arg_f32("kp")?.unwrap_or(current.kp),
+            ki: request.get_arg_f32("ki")?.unwrap_or(current.ki),
+            kd: request.get_arg_f32("kd")?.unwrap_or(current.kd),
+            output_min: request.get_arg_f32("output_min")?.unwrap_or(current.output_min),
+            output_max: request.get_arg_f32("output_max")?.unwrap_or(current.output_max),
+            integral_min: request.get_arg_f32("integral_min")?.unwrap_or(current.integral_min),
+            integral_max: request.get_arg_f32("integral_max")?.unwrap_or(current.integral_max),
+            setpoint_weight: request.get_arg_f32("setpoint_weight")?
+                                    .unwrap_or(current.setpoint_weight),
+            derivative_filter: request.get_arg_f32("derivative_filter")?
+                                      .unwrap_or(current.derivative_filter),
+            anti_windup: request.get_arg_parsed("anti_windup",
+                                                |value| value.parse::<pid::AntiWindup>())?
+                                .unwrap_or(current.anti_windup),
+            tracking: request.get_arg_f32("tracking")?.unwrap_or(current.tracking),
+            rate_limit: request.get_arg_f32("rate_limit")?.unwrap_or(current.rate_limit)
+        }
+    };
+    match parameters.check() {
+        Ok(parameters) => {
+            config.pid[pid_loop.index()] = parameters;
+            config.save();
+            gauge::apply_pid(config, loop_anode_m, loop_cathode_m);
+            Ok(Some(if restore {
+                "PID parameters have been restored to their defaults."
+            } else {
+                "PID parameters have been updated."
+            }))
+        }
+        Err(message) => Err(http::ArgError { name: "loop", message: message })
+    }
+}
+
+const VERSION: &'static str = include_str!(concat!(env!("OUT_DIR"), "/git-describe"));
+
+/// Handles a request, and returns the reply to be sent.
+pub fn serve(socket: &TcpSocket, request: &http::Request, keep_alive: bool,
+             verified: &mut auth::Verified, throttle: &mut auth::Throttle,
+             config: &mut config::Config,
+             loop_anode_m: &Mutex<RefCell<loop_anode::Controller>>,
+             loop_cathode_m: &Mutex<RefCell<loop_cathode::Controller>>,
+             electrometer_m: &Mutex<RefCell<electrometer::Electrometer>>,
+             history_m: &'static Mutex<RefCell<history::History>>,
+             leak_test_m: &'static Mutex<RefCell<leak_test::LeakTest>>,
+             self_test: &mut self_test::SelfTest,
+             degas: &mut degas::Degas,
+             http_stats: &http::Stats,
+             time: u64) -> Result<Reply, &'static str> {
+    let path = request.get_path()?;
+
+    // Only the settings and control pages accept form submissions.
+    let accepts_post = match path {
+        b"/gauge_settings.html" | b"/network_settings.html" | b"/calibration.json" |
+        b"/leak_test.html" | b"/leak_test.json" |
+        b"/degas.html" | b"/degas.json" |
+        b"/diagnostics.html" | b"/self_test.json" |
+        b"/pid.html" | b"/pid.json" => true,
+        _ => false
+    };
+    if request.get_method() == Some(http::Method::Post) && !accepts_post {
+        return Ok(http::Response::new(http::Status::MethodNotAllowed)
+            .allow("GET, HEAD")
+            .keep_alive(keep_alive)
+            .body(request, Page::Empty))
+    }
+
+    let reply = match path {
+        b"/" => {
+            let (anode, cathode, electrometer) = cortex_m::interrupt::free(|cs| {
+                (loop_anode_m.borrow(cs).borrow().get_status(),
+                 loop_cathode_m.borrow(cs).borrow().get_status(),
+                 electrometer_m.borrow(cs).borrow().get_status())
+            });
+            let span = request.get_arg_parsed("span", |value| {
+                match value.trim().parse::<u64>() {
+                    Ok(span) if chart::SPANS.contains(&span) => Ok(span),
+                    _ => Err("expected 600, 3600 or 86400")
+                }
+            });
+
+            match span {
+                Ok(span) =>
+                    http::Response::new(http::Status::Ok)
+                        .content_type("text/html; charset=utf-8")
+                        .keep_alive(keep_alive)
+                        .body(request, Page::Index {
+                            anode: anode,
+                            cathode: cathode,
+                            electrometer: electrometer,
+                            noise_threshold: config.noise_threshold,
+                            calibration: config.calibration,
+                            gas_sensitivity: config.gas_sensitivity,
+                            chart: chart::Chart::new(history_m, span.unwrap_or(chart::SPANS[0]))
+                        }),
+                Err(err) =>
+                    http::Response::new(http::Status::BadRequest)
+                        .keep_alive(keep_alive)
+                        .body(request, Page::Error(http::Status::BadRequest, err.message))
+            }
+        },
+        b"/measure.json" => {
+            let (cathode, electrometer) = cortex_m::interrupt::free(|cs| {
+                (loop_cathode_m.borrow(cs).borrow().get_status(),
+                 electrometer_m.borrow(cs).borrow().get_status())
+            });
+
+            http::Response::new(http::Status::Ok)
+                .content_type("application/json")
+                .keep_alive(keep_alive)
+                .body(request, Page::Measure {
+                    cathode: cathode,
+                    electrometer: electrometer,
+                    noise_threshold: config.noise_threshold,
+                    calibration: config.calibration,
+                    gas_sensitivity: config.gas_sensitivity
+                })
+        }
+        b"/status.json" => {
+            let (anode, cathode, electrometer) = cortex_m::interrupt::free(|cs| {
+                (loop_anode_m.borrow(cs).borrow().get_status(),
+                 loop_cathode_m.borrow(cs).borrow().get_status(),
+                 electrometer_m.borrow(cs).borrow().get_status())
+            });
+
+            http::Response::new(http::Status::Ok)
+                .content_type("application/json")
+                .keep_alive(keep_alive)
+                .body(request, Page::Status {
+                    time: time,
+                    anode: anode,
+                    cathode: cathode,
+                    electrometer: electrometer,
+                    noise_threshold: config.noise_threshold,
+                    calibration: config.calibration,
+                    gas_sensitivity: config.gas_sensitivity,
+                    errors: board::get_error_status(),
+                    http_stats: *http_stats
+                })
+        }
+        b"/gauge_settings.html" => {
+            let remote = Some(socket.remote_endpoint().addr);
+            if let Err(denial) = auth::check(config, request, remote, verified, throttle, time) {
+                return Ok(deny(request, keep_alive, denial))
+            }
+
+            let mut notice = Notice::Empty;
+
+            if request.get_method() == Some(http::Method::Post) {
+                let kind = request.get_arg_parsed("filter", |value| value.parse::<filter::Kind>());
+                let averaging_time = request.get_arg_parsed("averaging_time", |value| {
+                    match value.trim().parse() {
+                        Ok(seconds) => filter::check_averaging_time(seconds),
+                        Err(_) => Err("invalid number")
+                    }
+                });
+                let noise_threshold = request.get_arg_parsed("noise_threshold", |value| {
+                    match value.trim().parse::<f32>() {
+                        Ok(percent) => gauge::check_noise_threshold(percent/100.0),
+                        Err(_) => Err("invalid number")
+                    }
+                });
+                match (kind, averaging_time, noise_threshold) {
+                    (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) =>
+                        notice = Notice::Error(err),
+                    (Ok(None), Ok(None), Ok(None)) => (),
+                    (Ok(kind), Ok(averaging_time), Ok(noise_threshold)) => {
+                        config.filter = kind.unwrap_or(config.filter);
+                        config.averaging_time = averaging_time.unwrap_or(config.averaging_time);
+                        config.noise_threshold = noise_threshold.unwrap_or(config.noise_threshold);
+                        config.save();
+                        gauge::apply_filter(config, loop_cathode_m, electrometer_m);
+                        notice = Notice::Message("Averaging settings have been updated.");
+                    }
+                }
+
+                let parse_u16 = |value: &str| {
+                    value.trim().parse::<u16>().map_err(|_| "invalid integer")
+                };
+                let range_mode = request.get_arg_parsed("range", |value| {
+                    value.parse::<electrometer::RangeMode>()
+                });
+                let up_threshold = request.get_arg_parsed("up_threshold", &parse_u16);
+                let down_threshold = request.get_arg_parsed("down_threshold", &parse_u16);
+                let dwell = request.get_arg_parsed("dwell", &parse_u16);
+                let settle = request.get_arg_parsed("settle", &parse_u16);
+                match (range_mode, up_threshold, down_threshold, dwell, settle) {
+                    (Err(err), _, _, _, _) | (_, Err(err), _, _, _) | (_, _, Err(err), _, _) |
+                    (_, _, _, Err(err), _) | (_, _, _, _, Err(err)) => notice = Notice::Error(err),
+                    (Ok(None), Ok(None), Ok(None), Ok(None), Ok(None)) => (),
+                    (Ok(range_mode), Ok(up_threshold), Ok(down_threshold), Ok(dwell), Ok(settle)) => {
+                        let autorange = electrometer::Autorange {
+                            up_threshold: up_threshold.unwrap_or(config.autorange.up_threshold),
+                            down_threshold: down_threshold.unwrap_or(config.autorange.down_threshold),
+                            dwell: dwell.unwrap_or(config.autorange.dwell),
+                            settle: settle.unwrap_or(config.autorange.settle)
+                        };
+                        match autorange.check() {
+                            Ok(autorange) => {
+                                config.range_mode = range_mode.unwrap_or(config.range_mode);
+                                config.autorange = autorange;
+                                config.save();
+                                gauge::apply_range(config, electrometer_m);
+                                notice = Notice::Message(
+                                    "Electrometer range settings have been updated.");
+                            }
+                            Err(message) => notice = Notice::Error(http::ArgError {
+                                name: "up_threshold",
+                                message: message
+                            })
+                        }
+                    }
+                }
+
+                match (calibration_arg(request), gas_sensitivity_arg(request)) {
+                    (Err(err), _) | (_, Err(err)) => notice = Notice::Error(err),
+                    (Ok(None), Ok(None)) => (),
+                    (Ok(calibration), Ok(gas_sensitivity)) => {
+                        config.calibration = calibration.unwrap_or(config.calibration);
+                        config.gas_sensitivity = gas_sensitivity.unwrap_or(config.gas_sensitivity);
+                        config.save();
+                        notice = Notice::Message("Calibration has been updated.");
+                    }
+                }
+            }
+
+            let status = match notice {
+                Notice::Error(_) => http::Status::BadRequest,
+                _ => http::Status::Ok
+            };
+            let electrometer = cortex_m::interrupt::free(|cs| {
+                electrometer_m.borrow(cs).borrow().get_status()
+            });
+            http::Response::new(status)
+                .content_type("text/html; charset=utf-8")
+                .keep_alive(keep_alive)
+                .body(request, Page::GaugeSettings {
+                    notice: notice,
+                    electrometer: electrometer,
+                    noise_threshold: config.noise_threshold,
+                    calibration: config.calibration,
+                    gas_sensitivity: config.gas_sensitivity
+                })
+        },
+        b"/network_settings.html" => {
+            let remote = Some(socket.remote_endpoint().addr);
+            if let Err(denial) = auth::check(config, request, remote, verified, throttle, time) {
+                return Ok(deny(request, keep_alive, denial))
+            }
+
+            let mut notice = Notice::Empty;
+
+            if request.get_method() == Some(http::Method::Post) {
+                match request.get_arg_ip_cidr("ip") {
+                    Ok(Some(ip)) => {
+                        notice = Notice::Message(
+                            "IP address has been updated and will be active after a reboot.");
+                        config.ip = ip;
+                        config.save();
+                    }
+                    Ok(None) => (),
+                    Err(err) => notice = Notice::Error(err)
+                }
+                let mut buffer = [0; 64];
+                match request.get_arg("password", &mut buffer) {
+                    Ok(Some("")) | Ok(None) => (),
+                    Ok(Some(password)) => {
+                        notice = Notice::Message("Admin password has been updated.");
+                        config.password = Some(auth::PasswordHash::new(password.as_bytes(), time,
+                                                                       &::get_entropy()));
+                        config.save();
+                    }
+                    Err(err) => notice = Notice::Error(err)
+                }
+            }
+
+            let status = match notice {
+                Notice::Error(_) => http::Status::BadRequest,
+                _ => http::Status::Ok
+            };
+            http::Response::new(status)
+                .content_type("text/html; charset=utf-8")
+                .keep_alive(keep_alive)
+                .body(request, Page::NetworkSettings {
+                    notice: notice,
+                    ip: config.ip,
+                    password_set: config.password.is_some()
+                })
+        },
+        b"/history.csv" | b"/history.json" => {
+            let (format, content_type) = if path == b"/history.csv" {
+                (HistoryFormat::Csv, "text/csv")
+            } else {
+                (HistoryFormat::Json, "application/json")
+            };
+            match history_range(request, history_m, time) {
+                Ok((tier, first, end)) =>
+                    http::Response::new(http::Status::Ok)
+                        .content_type(content_type)
+                        .keep_alive(keep_alive)
+                        .body(request, Page::History {
+                            history_m: history_m,
+                            format: format,
+                            tier: tier,
+                            first: first,
+                            end: end,
+                            time: time
+                        }),
+                Err(err) =>
+                    http::Response::new(http::Status::BadRequest)
+                        .keep_alive(keep_alive)
+                        .body(request, Page::Error(http::Status::BadRequest, err.message))
+            }
+        }
+        b"/calibration.json" => {
+            let mut result = (Ok(None), Ok(None));
+            if request.get_method() == Some(http::Method::Post) {
+                let remote = Some(socket.remote_endpoint().addr);
+                if let Err(denial) = auth::check(config, request, remote, verified, throttle, time) {
+                    return Ok(deny(request, keep_alive, denial))
+                }
+                result = (calibration_arg(request), gas_sensitivity_arg(request));
+            }
+
+            match result {
+                (Ok(calibration), Ok(gas_sensitivity)) => {
+                    if calibration.is_some() || gas_sensitivity.is_some() {
+                        config.calibration = calibration.unwrap_or(config.calibration);
+                        config.gas_sensitivity = gas_sensitivity.unwrap_or(config.gas_sensitivity);
+                        config.save();
+                    }
+                    http::Response::new(http::Status::Ok)
+                        .content_type("application/json")
+                        .keep_alive(keep_alive)
+                        .body(request, Page::Calibration(config.calibration,
+                                                         config.gas_sensitivity))
+                }
+                (Err(err), _) | (_, Err(err)) =>
+                    http::Response::new(http::Status::BadRequest)
+                        .keep_alive(keep_alive)
+                        .body(request, Page::Error(http::Status::BadRequest, err.message))
+            }
+        }
+        b"/leak_test.html" => {
+            let remote = Some(socket.remote_endpoint().addr);
+            if let Err(denial) = auth::check(config, request, remote, verified, throttle, time) {
+                return Ok(deny(request, keep_alive, denial))
+            }
+
+            let mut notice = Notice::Empty;
+
+            if request.get_method() == Some(http::Method::Post) {
+                match leak_test_action(request, config, electrometer_m, leak_test_m, degas, time) {
+                    Ok(Some(message)) => notice = Notice::Message(message),
+                    Ok(None) => (),
+                    Err(err) => notice = Notice::Error(err)
+                }
+            }
+
+            let status = match notice {
+                Notice::Error(_) => http::Status::BadRequest,
+                _ => http::Status::Ok
+            };
+            let leak_test = cortex_m::interrupt::free(|cs| {
+                leak_test_m.borrow(cs).borrow().get_status()
+            });
+            http::Response::new(status)
+                .content_type("text/html; charset=utf-8")
+                .keep_alive(keep_alive)
+                .body(request, Page::LeakTest {
+                    notice: notice,
+                    status: leak_test,
+                    fit: config.leak_test_fit,
+                    duration: config.leak_test_duration,
+                    volume: config.leak_test_volume
+                })
+        }
+        b"/leak_test.json" => {
+            let mut result = Ok(None);
+            if request.get_method() == Some(http::Method::Post) {
+                let remote = Some(socket.remote_endpoint().addr);
+                if let Err(denial) = auth::check(config, request, remote, verified, throttle, time) {
+                    return Ok(deny(request, keep_alive, denial))
+                }
+                result = leak_test_action(request, config, electrometer_m, leak_test_m, degas,
+                                          time);
+            }
+
+            match result {
+                Ok(_) => {
+                    let leak_test = cortex_m::interrupt::free(|cs| {
+                        leak_test_m.borrow(cs).borrow().get_status()
+                    });
+                    http::Response::new(http::Status::Ok)
+                        .content_type("application/json")
+                        .keep_alive(keep_alive)
+                        .body(request, Page::LeakTestStatus(leak_test))
+                }
+                Err(err) =>
+                    http::Response::new(http::Status::BadRequest)
+                        .keep_alive(keep_alive)
+                        .body(request, Page::Error(http::Status::BadRequest, err.message))
+            }
+        }
+        b"/leak_test.csv" => {
+            let len = cortex_m::interrupt::free(|cs| {
+                leak_test_m.borrow(cs).borrow().get_status().trace_len
+            });
+            http::Response::new(http::Status::Ok)
+                .content_type("text/csv")
+                .keep_alive(keep_alive)
+                .body(request, Page::LeakTestTrace {
+                    leak_test_m: leak_test_m,
+                    len: len
+                })
+        }
+        b"/degas.html" => {
+            let remote = Some(socket.remote_endpoint().addr);
+            if let Err(denial) = auth::check(config, request, remote, verified, throttle, time) {
+                return Ok(deny(request, keep_alive, denial))
+            }
+
+            let mut notice = Notice::Empty;
+
+            if request.get_method() == Some(http::Method::Post) {
+                match degas_action(request, config, loop_anode_m, loop_cathode_m,
+                                   electrometer_m, leak_test_m, self_test, degas, time) {
+                    Ok(Some(message)) => notice = Notice::Message(message),
+                    Ok(None) => (),
+                    Err(err) => notice = Notice::Error(err)
+                }
+            }
+
+            let status = match notice {
+                Notice::Error(_) => http::Status::BadRequest,
+                _ => http::Status::Ok
+            };
+            http::Response::new(status)
+                .content_type("text/html; charset=utf-8")
+                .keep_alive(keep_alive)
+                .body(request, Page::Degas {
+                    notice: notice,
+                    status: degas.get_status(),
+                    emission: config.degas_emission,
+                    anode: config.degas_anode,
+                    duration: config.degas_duration,
+                    ramp: config.degas_ramp,
+                    pressure: config.degas_pressure
+                })
+        }
+        b"/degas.json" => {
+            let mut result = Ok(None);
+            if request.get_method() == Some(http::Method::Post) {
+                let remote = Some(socket.remote_endpoint().addr);
+                if let Err(denial) = auth::check(config, request, remote, verified, throttle, time) {
+                    return Ok(deny(request, keep_alive, denial))
+                }
+                result = degas_action(request, config, loop_anode_m, loop_cathode_m,
+                                      electrometer_m, leak_test_m, self_test, degas, time);
+            }
+
+            match result {
+                Ok(_) =>
+                    http::Response::new(http::Status::Ok)
+                        .content_type("application/json")
+                        .keep_alive(keep_alive)
+                        .body(request, Page::DegasStatus(degas.get_status())),
+                Err(err) =>
+                    http::Response::new(http::Status::BadRequest)
+                        .keep_alive(keep_alive)
+                        .body(request, Page::Error(http::Status::BadRequest, err.message))
+            }
+        }
+        b"/diagnostics.html" => {
+            let remote = Some(socket.remote_endpoint().addr);
+            if let Err(denial) = auth::check(config, request, remote, verified, throttle, time) {
+                return Ok(deny(request, keep_alive, denial))
+            }
+
+            let mut notice = Notice::Empty;
+
+            if request.get_method() == Some(http::Method::Post) {
+                match self_test_action(request, config, loop_anode_m, loop_cathode_m,
+                                       electrometer_m, self_test, degas, time) {
+                    Ok(Some(message)) => notice = Notice::Message(message),
+                    Ok(None) => (),
+                    Err(err) => notice = Notice::Error(err)
+                }
+            }
+
+            let status = match notice {
+                Notice::Error(_) => http::Status::BadRequest,
+                _ => http::Status::Ok
+            };
+            http::Response::new(status)
+                .content_type("text/html; charset=utf-8")
+                .keep_alive(keep_alive)
+                .body(request, Page::Diagnostics {
+                    notice: notice,
+                    state: self_test.get_state(),
+                    result: config.self_test,
+                    threshold: config.leakage_threshold
+                })
+        }
+        b"/self_test.json" => {
+            let mut result = Ok(None);
+            if request.get_method() == Some(http::Method::Post) {
+                let remote = Some(socket.remote_endpoint().addr);
+                if let Err(denial) = auth::check(config, request, remote, verified, throttle, time) {
+                    return Ok(deny(request, keep_alive, denial))
+                }
+                result = self_test_action(request, config, loop_anode_m, loop_cathode_m,
+                                          electrometer_m, self_test, degas, time);
+            }
+
+            match result {
+                Ok(_) =>
+                    http::Response::new(http::Status::Ok)
+                        .content_type("application/json")
+                        .keep_alive(keep_alive)
+                        .body(request, Page::SelfTestStatus {
+                            state: self_test.get_state(),
+                            result: config.self_test
+                        }),
+                Err(err) =>
+                    http::Response::new(http::Status::BadRequest)
+                        .keep_alive(keep_alive)
+                        .body(request, Page::Error(http::Status::BadRequest, err.message))
+            }
+        }
+        b"/pid.html" => {
+            let remote = Some(socket.remote_endpoint().addr);
+            if let Err(denial) = auth::check(config, request, remote, verified, throttle, time) {
+                return Ok(deny(request, keep_alive, denial))
+            }
+
+            let mut notice = Notice::Empty;
+
+            if request.get_method() == Some(http::Method::Post) {
+                match pid_action(request, config, loop_anode_m, loop_cathode_m) {
+                    Ok(Some(message)) => notice = Notice::Message(message),
+                    Ok(None) => (),
+                    Err(err) => notice = Notice::Error(err)
+                }
+            }
+
+            let status = match notice {
+                Notice::Error(_) => http::Status::BadRequest,
+                _ => http::Status::Ok
+            };
+            http::Response::new(status)
+                .content_type("text/html; charset=utf-8")
+                .keep_alive(keep_alive)
+                .body(request, Page::Pid {
+                    notice: notice,
+                    parameters: config.pid
+                })
+        }
+        b"/pid.json" => {
+            let mut result = Ok(None);
+            if request.get_method() == Some(http::Method::Post) {
+                let remote = Some(socket.remote_endpoint().addr);
+                if let Err(denial) = auth::check(config, request, remote, verified, throttle, time) {
+                    return Ok(deny(request, keep_alive, denial))
+                }
+                result = pid_action(request, config, loop_anode_m, loop_cathode_m);
+            }
+
+            match result {
+                Ok(_) =>
+                    http::Response::new(http::Status::Ok)
+                        .content_type("application/json")
+                        .keep_alive(keep_alive)
+                        .body(request, Page::PidStatus(config.pid)),
+                Err(err) =>
+                    http::Response::new(http::Status::BadRequest)
+                        .keep_alive(keep_alive)
+                        .body(request, Page::Error(http::Status::BadRequest, err.message))
+            }
+        }
+        b"/firmware.html" => {
+            http::Response::new(http::Status::Ok)
+                .content_type("text/html; charset=utf-8")
+                .keep_alive(keep_alive)
+                .body(request, Page::Firmware)
+        }
+        path => match assets::find(path) {
+            Some(asset) => {
+                let status = if request.etag_matches(asset.etag) {
+                    http::Status::NotModified
+                } else {
+                    http::Status::Ok
+                };
+                http::Response::new(status)
+                    .content_type(asset.content_type)
+                    .gzip()
+                    .etag(asset.etag)
+                    .keep_alive(keep_alive)
+                    .body(request, Page::Asset(asset.data))
+            }
+            None => text(request, keep_alive, http::Status::NotFound, "Not found")
+        }
+    };
+    Ok(reply)
+}
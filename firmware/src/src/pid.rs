@@ -0,0 +1,513 @@
+//! PID controller with an explicit sample period.
+//!
+//! The derivative acts on the measurement only, through an optional
+//! first-order low-pass filter. The integral is always kept within its
+//! limits, and can additionally be kept from winding up while the output
+//! saturates. With unit setpoint weight, no derivative filter, clamping
+//! anti-windup and no rate limit, the controller is a textbook PID.
+
+use core::str::FromStr;
+use libm::F32Ext;
+
+/// Anti-windup strategy, applied on top of the integral limits.
+#[derive(Clone, Copy, PartialEq)]
+pub enum AntiWindup {
+    /// Only the integral limits apply.
+    Clamp,
+    /// The error is not integrated while it would drive the output further
+    /// into saturation.
+    Conditional,
+    /// While the output saturates, the integral is driven back towards the
+    /// output limit, with the tracking time constant.
+    BackCalculation
+}
+
+impl AntiWindup {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            AntiWindup::Clamp => "clamp",
+            AntiWindup::Conditional => "conditional",
+            AntiWindup::BackCalculation => "back_calculation"
+        }
+    }
+}
+
+impl FromStr for AntiWindup {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<AntiWindup, &'static str> {
+        match s.trim() {
+            "clamp" => Ok(AntiWindup::Clamp),
+            "conditional" => Ok(AntiWindup::Conditional),
+            "back_calculation" => Ok(AntiWindup::BackCalculation),
+            _ => Err("expected \"clamp\", \"conditional\" or \"back_calculation\"")
+        }
+    }
+}
+
+/// Gains are per second: the integral is that of the error over time, in
+/// error units times seconds, and the derivative is per second.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Parameters {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+    pub output_min: f32,
+    pub output_max: f32,
+    pub integral_min: f32,
+    pub integral_max: f32,
+    /// Fraction of the setpoint seen by the proportional term, from 0 to 1.
+    /// Lower values reduce the overshoot on setpoint changes.
+    pub setpoint_weight: f32,
+    /// Time constant of the derivative filter, in seconds, or 0.
+    pub derivative_filter: f32,
+    pub anti_windup: AntiWindup,
+    /// Tracking time constant of the back-calculation anti-windup, in seconds.
+    pub tracking: f32,
+    /// Maximum output change per second, or 0 for no limit.
+    pub rate_limit: f32
+}
+
+impl Parameters {
+    pub fn check(&self) -> Result<Parameters, &'static str> {
+        let values = [self.kp, self.ki, self.kd, self.output_min, self.output_max,
+                      self.integral_min, self.integral_max, self.setpoint_weight,
+                      self.derivative_filter, self.tracking, self.rate_limit];
+        if !values.iter().all(|&value| value.is_finite()) {
+            return Err("invalid PID parameter")
+        }
+        if self.kp < 0.0 || self.ki < 0.0 || self.kd < 0.0 {
+            return Err("PID gains must not be negative")
+        }
+        if self.output_min >= self.output_max {
+            return Err("output minimum not below maximum")
+        }
+        if self.integral_min > 0.0 || self.integral_max < 0.0 {
+            return Err("integral limits must include zero")
+        }
+        if self.setpoint_weight < 0.0 || self.setpoint_weight > 1.0 {
+            return Err("setpoint weight out of range")
+        }
+        if self.derivative_filter < 0.0 || self.rate_limit < 0.0 {
+            return Err("PID filter and rate limit must not be negative")
+        }
+        if self.tracking <= 0.0 {
+            return Err("tracking time constant must be positive")
+        }
+        Ok(*self)
+    }
+}
+
+pub struct Controller {
+    parameters: Parameters,
+    /// Time between two updates, in seconds.
+    period: f32,
+    target: f32,
+    integral: f32,
+    derivative: f32,
+    last_input: Option<f32>,
+    last_output: Option<f32>
+}
+
+impl Controller {
+    pub const fn new(parameters: Parameters, period: f32) -> Controller {
+        Controller {
+            parameters: parameters,
+            period: period,
+            target: 0.0,
+            integral: 0.0,
+            derivative: 0.0,
+            last_input: None,
+            last_output: None
+        }
+    }
+
+    fn clamp_integral(&mut self) {
+        if self.integral < self.parameters.integral_min {
+            self.integral = self.parameters.integral_min;
+        }
+        if self.integral > self.parameters.integral_max {
+            self.integral = self.parameters.integral_max;
+        }
+    }
+
+    pub fn update(&mut self, input: f32) -> f32 {
+        let parameters = self.parameters;
+        let error = self.target - input;
+
+        let p = parameters.kp*(parameters.setpoint_weight*self.target - input);
+
+        if let Some(last_input) = self.last_input {
+            let derivative = (last_input - input)/self.period;
+            let alpha = self.period/(parameters.derivative_filter + self.period);
+            self.derivative += alpha*(derivative - self.derivative);
+        }
+        self.last_input = Some(input);
+        let d = parameters.kd*self.derivative;
+
+        let last_integral = self.integral;
+        self.integral += error*self.period;
+        self.clamp_integral();
+        let mut unlimited = p + parameters.ki*self.integral + d;
+
+        if parameters.anti_windup == AntiWindup::Conditional {
+            if (unlimited > parameters.output_max && error > 0.0) ||
+                    (unlimited < parameters.output_min && error < 0.0) {
+                self.integral = last_integral;
+                unlimited = p + parameters.ki*self.integral + d;
+            }
+        }
+
+        let mut output = unlimited;
+        if output < parameters.output_min {
+            output = parameters.output_min;
+        }
+        if output > parameters.output_max {
+            output = parameters.output_max;
+        }
+        if let (Some(last_output), true) = (self.last_output, parameters.rate_limit > 0.0) {
+            let step = parameters.rate_limit*self.period;
+            if output > last_output + step {
+                output = last_output + step;
+            }
+            if output < last_output - step {
+                output = last_output - step;
+            }
+        }
+        self.last_output = Some(output);
+
+        if parameters.anti_windup == AntiWindup::BackCalculation && parameters.ki != 0.0 {
+            self.integral += (output - unlimited)/(parameters.ki*parameters.tracking)*self.period;
+            self.clamp_integral();
+        }
+
+        output
+    }
+
+    pub fn set_target(&mut self, target: f32) {
+        self.target = target;
+    }
+
+    /// Changes the parameters without a step in the output: once the
+    /// controller is running, the integral is set so that the new gains
+    /// give the last output, as far as the new integral limits allow.
+    /// Before that, the integral is rescaled so that the integral term keeps
+    /// its value.
+    pub fn set_parameters(&mut self, parameters: Parameters) {
+        if parameters.ki != 0.0 {
+            match (self.last_input, self.last_output) {
+                (Some(input), Some(output)) => {
+                    let p = parameters.kp*(parameters.setpoint_weight*self.target - input);
+                    let d = parameters.kd*self.derivative;
+                    self.integral = (output - p - d)/parameters.ki;
+                }
+                _ => self.integral *= self.parameters.ki/parameters.ki
+            }
+        }
+        self.parameters = parameters;
+        self.clamp_integral();
+    }
+
+    /// Re-initializes the controller after a step in the measurement that
+    /// is not a change of the process, e.g. a change of sensor range: the
+    /// output continues from `output`, without a proportional or derivative
+    /// kick.
+    pub fn initialize(&mut self, input: f32, output: f32) {
+        let parameters = self.parameters;
+        if parameters.ki != 0.0 {
+            let p = parameters.kp*(parameters.setpoint_weight*self.target - input);
+            self.integral = (output - p)/parameters.ki;
+            self.clamp_integral();
+        }
+        self.derivative = 0.0;
+        self.last_input = Some(input);
+        self.last_output = Some(output);
+    }
+
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.derivative = 0.0;
+        self.last_input = None;
+        self.last_output = None;
+    }
+}
+
+/// Setpoint that follows its target at a limited rate, to avoid steps in
+/// the loop outputs.
+pub struct Ramp {
+    /// Maximum change of the setpoint per second.
+    rate: f32,
+    target: f32,
+    setpoint: Option<f32>
+}
+
+impl Ramp {
+    pub const fn new(rate: f32) -> Ramp {
+        Ramp {
+            rate: rate,
+            target: 0.0,
+            setpoint: None
+        }
+    }
+
+    pub fn set_rate(&mut self, rate: f32) {
+        self.rate = rate;
+    }
+
+    pub fn set_target(&mut self, target: f32) {
+        self.target = target;
+    }
+
+    /// Moves the setpoint towards the target by at most one step, and
+    /// returns it. After a reset, the setpoint starts from `start`.
+    pub fn update(&mut self, start: f32, period: f32) -> f32 {
+        let setpoint = self.setpoint.unwrap_or(start);
+        let step = self.rate*period;
+        let setpoint = if self.target > setpoint + step {
+            setpoint + step
+        } else if self.target < setpoint - step {
+            setpoint - step
+        } else {
+            self.target
+        };
+        self.setpoint = Some(setpoint);
+        setpoint
+    }
+
+    pub fn get_setpoint(&self) -> Option<f32> {
+        self.setpoint
+    }
+
+    pub fn is_ramping(&self) -> bool {
+        self.setpoint != Some(self.target)
+    }
+
+    pub fn reset(&mut self) {
+        self.setpoint = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::prelude::v1::*;
+    use super::*;
+    use board;
+    use loop_anode;
+    use loop_cathode;
+
+    const PERIOD: f32 = 1.0e-3;
+
+    fn parameters(kp: f32, ki: f32, kd: f32, output_min: f32, output_max: f32) -> Parameters {
+        Parameters {
+            kp: kp,
+            ki: ki,
+            kd: kd,
+            output_min: output_min,
+            output_max: output_max,
+            integral_min: -1.0e3,
+            integral_max: 1.0e3,
+            setpoint_weight: 1.0,
+            derivative_filter: 0.0,
+            anti_windup: AntiWindup::Clamp,
+            tracking: 0.01,
+            rate_limit: 0.0
+        }
+    }
+
+    /// Discrete first-order plant, with a gain and a time constant.
+    struct Plant {
+        gain: f32,
+        time_constant: f32,
+        period: f32,
+        output: f32
+    }
+
+    impl Plant {
+        fn new(gain: f32, time_constant: f32, period: f32) -> Plant {
+            Plant { gain: gain, time_constant: time_constant, period: period, output: 0.0 }
+        }
+
+        fn update(&mut self, input: f32) -> f32 {
+            self.output += (self.gain*input - self.output)*self.period/self.time_constant;
+            self.output
+        }
+    }
+
+    /// Controller as it was before the gains were per second: the error is
+    /// summed and differenced per sample.
+    struct LegacyController {
+        kp: f32,
+        ki: f32,
+        kd: f32,
+        output_min: f32,
+        output_max: f32,
+        integral_min: f32,
+        integral_max: f32,
+        target: f32,
+        integral: f32,
+        last_input: Option<f32>
+    }
+
+    impl LegacyController {
+        fn update(&mut self, input: f32) -> f32 {
+            let error = self.target - input;
+            let p = self.kp*error;
+            self.integral += error;
+            if self.integral < self.integral_min {
+                self.integral = self.integral_min;
+            }
+            if self.integral > self.integral_max {
+                self.integral = self.integral_max;
+            }
+            let i = self.ki*self.integral;
+            let d = match self.last_input {
+                None => 0.0,
+                Some(last_input) => self.kd*(last_input - input)
+            };
+            self.last_input = Some(input);
+            let output = p + i + d;
+            output.max(self.output_min).min(self.output_max)
+        }
+    }
+
+    fn legacy(kp: f32, ki: f32, kd: f32, output_min: f32, output_max: f32,
+              integral_limit: f32, target: f32) -> LegacyController {
+        LegacyController {
+            kp: kp,
+            ki: ki,
+            kd: kd,
+            output_min: output_min,
+            output_max: output_max,
+            integral_min: -integral_limit,
+            integral_max: integral_limit,
+            target: target,
+            integral: 0.0,
+            last_input: None
+        }
+    }
+
+    /// Runs a controller against a plant, and returns the outputs of the
+    /// controller and of the plant.
+    fn run<F>(mut update: F, plant: &mut Plant, samples: usize) -> (Vec<f32>, Vec<f32>)
+            where F: FnMut(f32) -> f32 {
+        let mut outputs = Vec::new();
+        let mut measurements = Vec::new();
+        let mut measurement = plant.output;
+        for _ in 0..samples {
+            let output = update(measurement);
+            measurement = plant.update(output);
+            outputs.push(output);
+            measurements.push(measurement);
+        }
+        (outputs, measurements)
+    }
+
+    fn check_legacy(parameters: Parameters, mut old: LegacyController,
+                    gain: f32, time_constant: f32) {
+        let period = board::ADC_SAMPLE_PERIOD;
+        let mut controller = Controller::new(parameters, period);
+        controller.set_target(old.target);
+        let (new_outputs, _) = run(|input| controller.update(input),
+                                   &mut Plant::new(gain, time_constant, period), 5000);
+        let (old_outputs, _) = run(|input| old.update(input),
+                                   &mut Plant::new(gain, time_constant, period), 5000);
+        let tolerance = 1.0e-3*(parameters.output_max - parameters.output_min);
+        for (new, old) in new_outputs.iter().zip(old_outputs.iter()) {
+            assert!((new - old).abs() <= tolerance);
+        }
+    }
+
+    #[test]
+    fn legacy_defaults() {
+        check_legacy(loop_anode::PID_PARAMETERS,
+                     legacy(0.2, 0.05, 0.0, 0.0, 225.0, 700.0, 165.0),
+                     1.0, 5.0e-3);
+        check_legacy(loop_cathode::FBI_PID_PARAMETERS,
+                     legacy(200.0, 20.0, 10.0, 0.5, 10.0, 0.1, 0.5e-3),
+                     2.0e-4, 20.0e-3);
+        check_legacy(loop_cathode::FV_PID_PARAMETERS,
+                     legacy(20.0, 1.5, 0.0, 0.0, 150.0, 50.0, 3.0),
+                     0.05, 5.0e-3);
+    }
+
+    fn overshoot(anti_windup: AntiWindup) -> f32 {
+        let mut parameters = parameters(2.0, 20.0, 0.0, 0.0, 1.0);
+        parameters.anti_windup = anti_windup;
+        let mut controller = Controller::new(parameters, PERIOD);
+        controller.set_target(1.5);
+        let (outputs, measurements) = run(|input| controller.update(input),
+                                          &mut Plant::new(2.0, 0.1, PERIOD), 3000);
+        assert!(outputs[0] == 1.0);
+        measurements.iter().fold(0.0, |max, &measurement| max.max(measurement)) - 1.5
+    }
+
+    #[test]
+    fn anti_windup() {
+        let clamp = overshoot(AntiWindup::Clamp);
+        assert!(clamp > 0.1);
+        assert!(overshoot(AntiWindup::Conditional) < clamp/2.0);
+        assert!(overshoot(AntiWindup::BackCalculation) < clamp/2.0);
+    }
+
+    fn derivative_kick(derivative_filter: f32) -> f32 {
+        let mut parameters = parameters(0.0, 0.0, 1.0, -1.0e6, 1.0e6);
+        parameters.derivative_filter = derivative_filter;
+        let mut controller = Controller::new(parameters, PERIOD);
+        controller.update(0.0);
+        controller.update(1.0).abs()
+    }
+
+    #[test]
+    fn derivative_filter() {
+        assert!(derivative_kick(0.0) == 1.0/PERIOD);
+        assert!(derivative_kick(10.0*PERIOD) < derivative_kick(0.0)/5.0);
+    }
+
+    fn proportional_kick(setpoint_weight: f32) -> f32 {
+        let mut parameters = parameters(2.0, 0.0, 0.0, -10.0, 10.0);
+        parameters.setpoint_weight = setpoint_weight;
+        let mut controller = Controller::new(parameters, PERIOD);
+        let before = controller.update(0.0);
+        controller.set_target(1.0);
+        controller.update(0.0) - before
+    }
+
+    #[test]
+    fn setpoint_weight() {
+        assert!(proportional_kick(1.0) == 2.0);
+        assert!(proportional_kick(0.5) == 1.0);
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut parameters = parameters(5.0, 50.0, 0.0, -10.0, 10.0);
+        parameters.rate_limit = 100.0;
+        let mut controller = Controller::new(parameters, PERIOD);
+        controller.set_target(1.0);
+        let (outputs, measurements) = run(|input| controller.update(input),
+                                          &mut Plant::new(1.0, 0.05, PERIOD), 2000);
+        let step = parameters.rate_limit*PERIOD;
+        assert!(outputs.windows(2).all(|pair| (pair[1] - pair[0]).abs() <= step*1.001));
+        assert!(outputs.windows(2).any(|pair| (pair[1] - pair[0]).abs() > step*0.999));
+        assert!((measurements[1999] - 1.0).abs() < 1.0e-3);
+    }
+
+    #[test]
+    fn bumpless_parameters() {
+        let mut parameters = parameters(1.0, 10.0, 0.01, -10.0, 10.0);
+        let mut controller = Controller::new(parameters, PERIOD);
+        controller.set_target(1.0);
+        let mut plant = Plant::new(1.0, 0.05, PERIOD);
+        let (outputs, measurements) = run(|input| controller.update(input), &mut plant, 20);
+        parameters.kp = 3.0;
+        parameters.ki = 5.0;
+        parameters.kd = 0.02;
+        parameters.setpoint_weight = 0.5;
+        controller.set_parameters(parameters);
+        // Without the integral being adjusted, the output would step by
+        // more than the error.
+        assert!(1.0 - measurements[19] > 0.5);
+        let output = controller.update(measurements[19]);
+        assert!((output - outputs[19]).abs() < 0.05);
+    }
+}
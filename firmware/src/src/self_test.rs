@@ -0,0 +1,263 @@
+//! Insulation self-test, which measures the leakage current from the anode
+//! to the collector, e.g. through a damaged cable or a contaminated
+//! feedthrough, with the filament off.
+//!
+//! The collector current is averaged on the most sensitive electrometer
+//! range, first with the anode at its configured voltage, then with the
+//! anode off. The difference is the leakage current.
+
+use core::cell::RefCell;
+use cortex_m;
+use cortex_m::interrupt::Mutex;
+
+use board;
+use config;
+use gauge;
+use loop_anode;
+use loop_cathode;
+use electrometer;
+
+pub const THRESHOLD_MAX: f32 = 1.0e-9;
+
+// Time allowed for the anode voltage to regulate.
+const ANODE_TIMEOUT_MS: u64 = 30_000;
+// Time for the collector current to settle after the anode voltage has
+// reached its target, or has been switched off.
+const ANODE_ON_SETTLE_MS: u64 = 5_000;
+const ANODE_OFF_SETTLE_MS: u64 = 10_000;
+// Time over which the collector current is averaged.
+const MEASURE_MS: u64 = 5_000;
+
+pub fn check_threshold(amperes: f32) -> Result<f32, &'static str> {
+    if amperes > 0.0 && amperes <= THRESHOLD_MAX {
+        Ok(amperes)
+    } else {
+        Err("leakage threshold out of range")
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Idle,
+    AnodeOn,
+    AnodeOff,
+    Finished,
+    Aborted(&'static str)
+}
+
+impl State {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            State::Idle => "idle",
+            State::AnodeOn => "anode_on",
+            State::AnodeOff => "anode_off",
+            State::Finished => "finished",
+            State::Aborted(_) => "aborted"
+        }
+    }
+}
+
+/// Outcome of a completed test. Currents are in amperes.
+#[derive(Clone, Copy, PartialEq)]
+pub struct SelfTestResult {
+    /// Anode voltage during the test
+    pub anode: f32,
+    pub current_on: f32,
+    pub current_off: f32,
+    pub leakage: f32,
+    pub threshold: f32,
+    /// The collector current exceeded the most sensitive range with the
+    /// anode on, so the leakage is at least `leakage`.
+    pub over_range: bool
+}
+
+impl SelfTestResult {
+    pub fn passed(&self) -> bool {
+        !self.over_range && self.leakage <= self.threshold
+    }
+}
+
+pub struct SelfTest {
+    state: State,
+    /// Start of the current step.
+    step_start: u64,
+    /// Start of the averaging in the current step, once known.
+    measure_start: Option<u64>,
+    last_count: u32,
+    sum: f32,
+    count: u32,
+    over_range: bool,
+    anode: f32,
+    threshold: f32,
+    current_on: f32
+}
+
+impl SelfTest {
+    pub fn new() -> SelfTest {
+        SelfTest {
+            state: State::Idle,
+            step_start: 0,
+            measure_start: None,
+            last_count: 0,
+            sum: 0.0,
+            count: 0,
+            over_range: false,
+            anode: 0.0,
+            threshold: 0.0,
+            current_on: 0.0
+        }
+    }
+
+    pub fn get_state(&self) -> State {
+        self.state
+    }
+
+    pub fn is_running(&self) -> bool {
+        match self.state {
+            State::AnodeOn | State::AnodeOff => true,
+            _ => false
+        }
+    }
+
+    fn begin_step(&mut self, state: State, time: u64, measure_start: Option<u64>) {
+        self.state = state;
+        self.step_start = time;
+        self.measure_start = measure_start;
+        self.sum = 0.0;
+        self.count = 0;
+    }
+
+    /// Switches the filament off and the anode on, and holds the electrometer
+    /// on its most sensitive range.
+    pub fn start(&mut self, time: u64, config: &config::Config,
+                 loop_anode_m: &Mutex<RefCell<loop_anode::Controller>>,
+                 loop_cathode_m: &Mutex<RefCell<loop_cathode::Controller>>,
+                 electrometer_m: &Mutex<RefCell<electrometer::Electrometer>>) -> Result<(), &'static str> {
+        if config.anode <= 0.0 {
+            return Err("anode voltage not set")
+        }
+        let last_count = cortex_m::interrupt::free(|cs| {
+            let mut loop_anode = loop_anode_m.borrow(cs).borrow_mut();
+            loop_cathode_m.borrow(cs).borrow_mut().set_enabled(false);
+            loop_anode.set_target(config.anode);
+            loop_anode.set_enabled(true);
+            let mut electrometer = electrometer_m.borrow(cs).borrow_mut();
+            electrometer.set_range_mode(
+                electrometer::RangeMode::Hold(board::ElectrometerRange::Low));
+            electrometer.get_status().ic_count
+        });
+        self.last_count = last_count;
+        self.over_range = false;
+        self.anode = config.anode;
+        self.threshold = config.leakage_threshold;
+        self.begin_step(State::AnodeOn, time, None);
+        Ok(())
+    }
+
+    /// Aborts a running test, and restores the configured outputs and range.
+    pub fn abort(&mut self, reason: &'static str, config: &config::Config,
+                 loop_anode_m: &Mutex<RefCell<loop_anode::Controller>>,
+                 loop_cathode_m: &Mutex<RefCell<loop_cathode::Controller>>,
+                 electrometer_m: &Mutex<RefCell<electrometer::Electrometer>>) {
+        if self.is_running() {
+            self.state = State::Aborted(reason);
+            gauge::apply_config(config, loop_anode_m, loop_cathode_m);
+            gauge::apply_range(config, electrometer_m);
+        }
+    }
+
+    /// Advances a running test. Returns a message to log when the test ends.
+    /// The result of a completed test is saved in the configuration.
+    pub fn poll(&mut self, time: u64, config: &mut config::Config,
+                loop_anode_m: &Mutex<RefCell<loop_anode::Controller>>,
+                loop_cathode_m: &Mutex<RefCell<loop_cathode::Controller>>,
+                electrometer_m: &Mutex<RefCell<electrometer::Electrometer>>) -> Option<&'static str> {
+        if !self.is_running() {
+            return None
+        }
+        let (anode, cathode, electrometer) = cortex_m::interrupt::free(|cs| {
+            (loop_anode_m.borrow(cs).borrow().get_status(),
+             loop_cathode_m.borrow(cs).borrow().get_status(),
+             electrometer_m.borrow(cs).borrow().get_status())
+        });
+
+        // The regulation timeout runs from the end of the anode ramp.
+        if self.state == State::AnodeOn && anode.ramping {
+            self.step_start = time;
+        }
+
+        // The outputs or range may have been changed by another user.
+        let reason = if cathode.enabled {
+            Some("filament switched on")
+        } else if electrometer.mode != electrometer::RangeMode::Hold(board::ElectrometerRange::Low) {
+            Some("electrometer range changed")
+        } else if self.state == State::AnodeOn && !anode.enabled {
+            Some("anode switched off")
+        } else if self.state == State::AnodeOn && self.measure_start.is_none() &&
+                time - self.step_start > ANODE_TIMEOUT_MS {
+            Some("anode not regulated")
+        } else if self.state == State::AnodeOff && electrometer.over_range {
+            Some("collector over range with anode off")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            self.abort(reason, config, loop_anode_m, loop_cathode_m, electrometer_m);
+            return Some("self-test aborted")
+        }
+
+        if self.state == State::AnodeOn && self.measure_start.is_none() && anode.ready {
+            self.measure_start = Some(time + ANODE_ON_SETTLE_MS);
+        }
+        let measure_start = match self.measure_start {
+            Some(measure_start) if time >= measure_start => measure_start,
+            _ => return None
+        };
+
+        if electrometer.ic_count != self.last_count {
+            self.last_count = electrometer.ic_count;
+            if electrometer.over_range {
+                self.over_range = true;
+            }
+            if let (Some(ic), false) = (electrometer.ic, electrometer.settling) {
+                self.sum += ic;
+                self.count += 1;
+            }
+        }
+        if time - measure_start < MEASURE_MS {
+            return None
+        }
+        if self.count == 0 {
+            self.abort("no collector readings", config, loop_anode_m, loop_cathode_m, electrometer_m);
+            return Some("self-test aborted")
+        }
+        let current = self.sum/(self.count as f32);
+
+        match self.state {
+            State::AnodeOn => {
+                self.current_on = current;
+                cortex_m::interrupt::free(|cs| {
+                    loop_anode_m.borrow(cs).borrow_mut().set_enabled(false);
+                });
+                self.begin_step(State::AnodeOff, time, Some(time + ANODE_OFF_SETTLE_MS));
+                None
+            }
+            _ => {
+                let result = SelfTestResult {
+                    anode: self.anode,
+                    current_on: self.current_on,
+                    current_off: current,
+                    leakage: self.current_on - current,
+                    threshold: self.threshold,
+                    over_range: self.over_range
+                };
+                self.state = State::Finished;
+                gauge::apply_config(config, loop_anode_m, loop_cathode_m);
+                gauge::apply_range(config, electrometer_m);
+                config.self_test = Some(result);
+                config.save();
+                Some(if result.passed() { "self-test passed" } else { "self-test failed" })
+            }
+        }
+    }
+}
@@ -0,0 +1,313 @@
+//! Emulation of the subset of the Pfeiffer TPG 26x/36x ASCII protocol
+//! that is relevant to a single-channel ionization gauge controller.
+//!
+//! The host sends a mnemonic terminated by CR (optionally followed by LF),
+//! the device acknowledges it with ACK or NAK, and the host then requests
+//! the data with ENQ. Sensor 2 is always reported as absent.
+
+use core::fmt;
+use core::fmt::Write;
+use core::str;
+use core::cell::RefCell;
+use cortex_m;
+use cortex_m::interrupt::Mutex;
+use smoltcp::socket::TcpSocket;
+
+use auth;
+use config;
+use gauge;
+use loop_cathode;
+use electrometer;
+
+pub const PORT: u16 = 8000;
+
+const ETX: u8 = 0x03;
+const ENQ: u8 = 0x05;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+
+const MAX_LINE: usize = 32;
+const MAX_REPLY: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Pressure(u8),
+    PressureAll,
+    Unit,
+    TransmitterId,
+    Error,
+    Reset,
+    Sensor
+}
+
+// Error status bits, reported by ERR and RES
+const ERR_SYNTAX: u8 = 0b0001;
+const ERR_PARAMETER: u8 = 0b0010;
+const ERR_CONTROLLER: u8 = 0b1000;
+
+// Measurement status codes
+const STATUS_OK: u8 = 0;
+const STATUS_UNDERRANGE: u8 = 1;
+const STATUS_OVERRANGE: u8 = 2;
+const STATUS_SENSOR_ERROR: u8 = 3;
+const STATUS_SENSOR_OFF: u8 = 4;
+const STATUS_NO_SENSOR: u8 = 5;
+
+/// Queues a whole reply, or fails if the transmit buffer cannot take it.
+fn send(output: &mut TcpSocket, data: &[u8]) -> fmt::Result {
+    match output.send_slice(data) {
+        Ok(len) if len == data.len() => Ok(()),
+        _ => Err(fmt::Error)
+    }
+}
+
+pub struct Session {
+    line: [u8; MAX_LINE],
+    line_len: usize,
+    overflow: bool,
+    command: Option<Command>,
+    errors: u8
+}
+
+struct Buffer {
+    data: [u8; MAX_REPLY],
+    len: usize
+}
+
+impl Buffer {
+    fn new() -> Buffer {
+        Buffer { data: [0; MAX_REPLY], len: 0 }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl fmt::Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.data.len() {
+            return Err(fmt::Error)
+        }
+        self.data[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Writes a value in the `sx.xxxxEsxx` format.
+fn write_value(output: &mut fmt::Write, value: f32) -> fmt::Result {
+    let mut buffer = Buffer::new();
+    write!(buffer, "{:.4e}", value)?;
+    let formatted = str::from_utf8(buffer.as_bytes()).map_err(|_| fmt::Error)?;
+    let mut parts = formatted.split('e');
+    // Non-finite values have no exponent, and are reported as an error.
+    let mantissa = parts.next().ok_or(fmt::Error)?;
+    let exponent: i32 = parts.next().and_then(|e| e.parse().ok()).ok_or(fmt::Error)?;
+    if mantissa.starts_with('-') {
+        write!(output, "{}E{:+03}", mantissa, exponent)
+    } else {
+        write!(output, "+{}E{:+03}", mantissa, exponent)
+    }
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session {
+            line: [0; MAX_LINE],
+            line_len: 0,
+            overflow: false,
+            command: None,
+            errors: 0
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.line_len = 0;
+        self.overflow = false;
+        self.command = None;
+        self.errors = 0;
+    }
+
+    /// Parses the received line. Settings are only changed if `writable`,
+    /// i.e. if the client is in the web control allowlist; otherwise they
+    /// are read-only.
+    fn parse(&self, config: &mut config::Config, writable: bool) -> Result<Command, u8> {
+        if self.overflow {
+            return Err(ERR_SYNTAX)
+        }
+        let line = str::from_utf8(&self.line[..self.line_len]).map_err(|_| ERR_SYNTAX)?;
+        let mut args = line.split(',');
+        let mnemonic = args.next().unwrap();
+        let arg = args.next();
+        let command = match (mnemonic, arg) {
+            ("PR1", None) => Command::Pressure(1),
+            ("PR2", None) => Command::Pressure(2),
+            ("PRX", None) => Command::PressureAll,
+            ("UNI", None) => Command::Unit,
+            ("UNI", Some(unit)) => {
+                let unit = match unit {
+                    "0" => gauge::PressureUnit::Mbar,
+                    "1" => gauge::PressureUnit::Torr,
+                    "2" => gauge::PressureUnit::Pa,
+                    _ => return Err(ERR_SYNTAX)
+                };
+                if unit != config.pressure_unit {
+                    if !writable {
+                        return Err(ERR_PARAMETER)
+                    }
+                    config.pressure_unit = unit;
+                    config.save();
+                }
+                Command::Unit
+            }
+            ("TID", None) => Command::TransmitterId,
+            ("ERR", None) => Command::Error,
+            ("RES", None) => Command::Reset,
+            ("SEN", None) => Command::Sensor,
+            // Only "no change" is accepted; the outputs cannot be switched over this protocol.
+            ("SEN", Some("0")) => match args.next() {
+                Some("0") => Command::Sensor,
+                _ => return Err(ERR_SYNTAX)
+            },
+            _ => return Err(ERR_SYNTAX)
+        };
+        if args.next().is_some() {
+            return Err(ERR_SYNTAX)
+        }
+        Ok(command)
+    }
+
+    fn write_data(&mut self, output: &mut fmt::Write, command: Command,
+                  config: &config::Config,
+                  loop_cathode_m: &Mutex<RefCell<loop_cathode::Controller>>,
+                  electrometer_m: &Mutex<RefCell<electrometer::Electrometer>>) -> fmt::Result {
+        let (status, pressure) = {
+            let (cathode, electrometer) = cortex_m::interrupt::free(|cs| {
+                (loop_cathode_m.borrow(cs).borrow().get_status(),
+                 electrometer_m.borrow(cs).borrow().get_status())
+            });
+            let unit = config.pressure_unit;
+            let (status, pressure) = match gauge::measure(&cathode, &electrometer,
+                                                          &config.calibration,
+                                                          config.gas_sensitivity) {
+                gauge::Measurement::Valid(pressure) => (STATUS_OK, unit.from_mbar(pressure)),
+                gauge::Measurement::UnderRange(limit) => (STATUS_UNDERRANGE, unit.from_mbar(limit)),
+                gauge::Measurement::OverRange(limit) => (STATUS_OVERRANGE, unit.from_mbar(limit)),
+                gauge::Measurement::OutputDisabled => (STATUS_SENSOR_OFF, 0.0),
+                gauge::Measurement::EmissionNotRegulated |
+                gauge::Measurement::Settling => (STATUS_SENSOR_ERROR, 0.0)
+            };
+            // A non-finite reading cannot be represented in the reply; it is
+            // reported according to the state of the gauge instead.
+            if pressure.is_finite() {
+                (status, pressure)
+            } else if electrometer.over_range {
+                (STATUS_OVERRANGE, 0.0)
+            } else if !cathode.enabled {
+                (STATUS_SENSOR_OFF, 0.0)
+            } else {
+                (STATUS_NO_SENSOR, 0.0)
+            }
+        };
+
+        match command {
+            Command::Pressure(1) => {
+                write!(output, "{},", status)?;
+                write_value(output, pressure)?;
+            }
+            Command::Pressure(_) => {
+                write!(output, "{},", STATUS_NO_SENSOR)?;
+                write_value(output, 0.0)?;
+            }
+            Command::PressureAll => {
+                write!(output, "{},", status)?;
+                write_value(output, pressure)?;
+                write!(output, ",{},", STATUS_NO_SENSOR)?;
+                write_value(output, 0.0)?;
+            }
+            Command::Unit => {
+                let unit = match config.pressure_unit {
+                    gauge::PressureUnit::Mbar => 0,
+                    gauge::PressureUnit::Torr => 1,
+                    gauge::PressureUnit::Pa => 2
+                };
+                write!(output, "{}", unit)?;
+            }
+            Command::TransmitterId => write!(output, "PBR,noSen")?,
+            Command::Error => write!(output, "{:04b}", self.errors)?,
+            Command::Reset => {
+                write!(output, "{:04b}", self.errors)?;
+                self.errors = 0;
+            }
+            Command::Sensor => {
+                let state = if config.output_enabled { 2 } else { 1 };
+                write!(output, "{},0", state)?;
+            }
+        }
+        write!(output, "\r\n")
+    }
+
+    pub fn input(&mut self, data: &[u8], output: &mut TcpSocket,
+                 config: &mut config::Config,
+                 loop_cathode_m: &Mutex<RefCell<loop_cathode::Controller>>,
+                 electrometer_m: &Mutex<RefCell<electrometer::Electrometer>>) -> fmt::Result {
+        for &c in data.iter() {
+            match c {
+                ETX => {
+                    self.line_len = 0;
+                    self.overflow = false;
+                    self.command = None;
+                }
+                ENQ => match self.command {
+                    Some(command) => {
+                        // The reply is formatted completely before it is sent, so
+                        // that a formatting error does not leave a partial line.
+                        let mut reply = Buffer::new();
+                        match self.write_data(&mut reply, command, config,
+                                              loop_cathode_m, electrometer_m) {
+                            Ok(()) => {
+                                send(output, reply.as_bytes())?;
+                            }
+                            Err(fmt::Error) => {
+                                self.errors |= ERR_CONTROLLER;
+                                send(output, &[NAK, b'\r', b'\n'])?;
+                            }
+                        }
+                    }
+                    None => {
+                        self.errors |= ERR_SYNTAX;
+                        send(output, &[NAK, b'\r', b'\n'])?;
+                    }
+                },
+                b'\r' => {
+                    let writable = auth::allowed(config, Some(output.remote_endpoint().addr));
+                    match self.parse(config, writable) {
+                        Ok(command) => {
+                            self.command = Some(command);
+                            send(output, &[ACK, b'\r', b'\n'])?;
+                        }
+                        Err(error) => {
+                            self.command = None;
+                            self.errors |= error;
+                            send(output, &[NAK, b'\r', b'\n'])?;
+                        }
+                    }
+                    self.line_len = 0;
+                    self.overflow = false;
+                }
+                b'\n' => (),
+                _ => {
+                    if self.line_len < self.line.len() {
+                        self.line[self.line_len] = c;
+                        self.line_len += 1;
+                    } else {
+                        self.overflow = true;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}